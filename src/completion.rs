@@ -0,0 +1,257 @@
+use crate::constants::CONSTANTS;
+use crate::{Expr, PREDICATES};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// Forms handled directly by `compile_expr`, the macro expander or the
+/// module loader rather than by a function call.
+pub const SPECIAL_FORMS: &[&str] = &[
+    "begin",
+    "define",
+    "define-syntax",
+    "defmacro",
+    "do",
+    "if",
+    "import",
+    "let",
+    "module",
+    "quote",
+    "set!",
+    "syntax-rules",
+    "while",
+];
+
+/// Functions built into the compiler, other than arithmetic and comparisons.
+pub const BUILTINS: &[&str] = &[
+    "quotient",
+    "remainder",
+    "modulo",
+    "exact->inexact",
+    "inexact->exact",
+    "exact",
+    "inexact",
+    "not",
+    "cons",
+    "car",
+    "cdr",
+    "list",
+    "length",
+    "append",
+    "reverse",
+    "vector",
+    "make-vector",
+    "vector-ref",
+    "vector-set!",
+    "vector-length",
+    "display",
+    "write",
+    "newline",
+    "string-append",
+    "string-length",
+    "number->string",
+    "string->number",
+    "gc",
+];
+
+/// LLVM intrinsics that take and return doubles, callable as `(llvm.sqrt x)`.
+pub const INTRINSICS: &[&str] = &[
+    "llvm.fabs",
+    "llvm.sqrt",
+    "llvm.sin",
+    "llvm.cos",
+    "llvm.exp",
+    "llvm.exp2",
+    "llvm.log",
+    "llvm.log2",
+    "llvm.log10",
+    "llvm.pow",
+    "llvm.powi",
+    "llvm.fma",
+    "llvm.minnum",
+    "llvm.maxnum",
+    "llvm.copysign",
+    "llvm.ceil",
+    "llvm.floor",
+    "llvm.round",
+    "llvm.trunc",
+    "llvm.rint",
+];
+
+/// The names a session has defined so far, kept for completion and hints.
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    /// function name -> parameter names
+    functions: BTreeMap<String, Vec<String>>,
+    globals: BTreeSet<String>,
+    macros: BTreeSet<String>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the names with those a session has in effect: the functions
+    /// of its `definitions`, the names its `imports` stand for (imported
+    /// name -> qualified name), and its `globals`.
+    pub fn refresh<'g>(
+        &mut self,
+        definitions: &[Expr],
+        imports: &HashMap<String, String>,
+        globals: impl IntoIterator<Item = &'g str>,
+        macros: impl IntoIterator<Item = &'g str>,
+    ) {
+        *self = Self::new();
+        for def in definitions {
+            self.record(def);
+        }
+        self.globals.extend(globals.into_iter().map(str::to_string));
+        self.macros.extend(macros.into_iter().map(str::to_string));
+        for (name, qualified) in imports {
+            match self.functions.get(qualified) {
+                Some(params) => {
+                    let params = params.clone();
+                    self.functions.insert(name.clone(), params);
+                }
+                None => {
+                    self.globals.insert(name.clone());
+                }
+            }
+        }
+    }
+
+    /// Records whatever a successfully compiled `define` form introduced.
+    pub fn record(&mut self, expr: &Expr) {
+        let exprs = match expr {
+            Expr::List(exprs) => exprs,
+            _ => return,
+        };
+
+        match exprs.as_slice() {
+            [Expr::Symbol(op), Expr::List(sig), ..] if op == "define" => {
+                if let Some((Expr::Symbol(name), params)) = sig.split_first() {
+                    let params = params
+                        .iter()
+                        .filter_map(|p| match p {
+                            Expr::Symbol(s) => Some(s.clone()),
+                            // annotated, as in (x : f64)
+                            Expr::List(parts) => match parts.first() {
+                                Some(Expr::Symbol(s)) => Some(s.clone()),
+                                _ => None,
+                            },
+                            _ => None,
+                        })
+                        .collect();
+                    self.globals.remove(name);
+                    self.functions.insert(name.clone(), params);
+                }
+            }
+            [Expr::Symbol(op), Expr::Symbol(name), ..] if op == "define" => {
+                self.functions.remove(name);
+                self.globals.insert(name.clone());
+            }
+            _ => {}
+        }
+    }
+
+    pub fn params(&self, function: &str) -> Option<&[String]> {
+        self.functions.get(function).map(Vec::as_slice)
+    }
+
+    /// All completable names starting with `prefix`, sorted and deduplicated.
+    pub fn candidates(&self, prefix: &str) -> Vec<String> {
+        let mut names: Vec<String> = SPECIAL_FORMS
+            .iter()
+            .chain(BUILTINS.iter())
+            .chain(PREDICATES.iter())
+            .chain(INTRINSICS.iter())
+            .chain(CONSTANTS.iter().map(|(name, _)| name))
+            .map(|s| s.to_string())
+            .chain(self.functions.keys().cloned())
+            .chain(self.globals.iter().cloned())
+            .chain(self.macros.iter().cloned())
+            .filter(|name| name.starts_with(prefix))
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// Completes the symbol ending at `pos`, returning where it starts and the
+    /// candidates that could replace it.
+    pub fn complete(&self, line: &str, pos: usize) -> (usize, Vec<String>) {
+        let start = symbol_start(line, pos);
+        let prefix = &line[start..pos];
+        if prefix.is_empty() {
+            return (pos, vec![]);
+        }
+        (start, self.candidates(prefix))
+    }
+
+    /// After `(name ` shows the parameters of `name` that are still missing,
+    /// e.g. `(dot a ` hints `y n)` for `(define (dot x y n) ...)`.
+    pub fn hint(&self, line: &str, pos: usize) -> Option<String> {
+        if pos < line.len() {
+            return None;
+        }
+
+        let open = innermost_open_paren(&line[..pos])?;
+        let inner = &line[open + 1..pos];
+        if !inner.ends_with(char::is_whitespace) {
+            return None;
+        }
+
+        let name_end = inner.find(is_delimiter)?;
+        let params = self.params(&inner[..name_end])?;
+        let given = count_arguments(&inner[name_end..]);
+        if given >= params.len() {
+            return Some(")".to_string());
+        }
+        Some(format!("{})", params[given..].join(" ")))
+    }
+}
+
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || c == '(' || c == ')'
+}
+
+fn symbol_start(line: &str, pos: usize) -> usize {
+    line[..pos].rfind(is_delimiter).map(|i| i + 1).unwrap_or(0)
+}
+
+/// Byte offset of the innermost `(` that is still open at the end of `line`.
+fn innermost_open_paren(line: &str) -> Option<usize> {
+    let mut open = vec![];
+    for (i, c) in line.char_indices() {
+        match c {
+            '(' => open.push(i),
+            ')' => {
+                open.pop();
+            }
+            _ => {}
+        }
+    }
+    open.pop()
+}
+
+/// Counts the top-level forms in `args`, so a nested `(f x)` is one argument.
+fn count_arguments(args: &str) -> usize {
+    let mut depth = 0;
+    let mut count = 0;
+    let mut in_form = false;
+    for c in args.chars() {
+        if depth == 0 && c.is_whitespace() {
+            in_form = false;
+            continue;
+        }
+        if depth == 0 && !in_form {
+            count += 1;
+            in_form = true;
+        }
+        match c {
+            '(' => depth += 1,
+            ')' if depth > 0 => depth -= 1,
+            _ => {}
+        }
+    }
+    count
+}
@@ -0,0 +1,31 @@
+//! The mathematical constants every session starts with, as in C++'s
+//! `std::numbers`. References fold to `const_float`; a user definition of
+//! the same name shadows the constant.
+
+use std::f64::consts;
+
+pub const CONSTANTS: &[(&str, f64)] = &[
+    ("e", consts::E),
+    ("egamma", 0.5772156649015329),
+    ("ln2", consts::LN_2),
+    ("ln10", consts::LN_10),
+    ("log2e", consts::LOG2_E),
+    ("log10e", consts::LOG10_E),
+    ("pi", consts::PI),
+    ("inv_pi", consts::FRAC_1_PI),
+    ("sqrtpi", 1.772453850905516),
+    ("inv_sqrtpi", 0.5641895835477563),
+    ("sqrt2", consts::SQRT_2),
+    ("inv_sqrt2", consts::FRAC_1_SQRT_2),
+    ("sqrt3", 1.7320508075688772),
+    ("inv_sqrt3", 0.5773502691896257),
+    ("phi", 1.618033988749895),
+];
+
+/// The value of the constant `name`, if there is one.
+pub fn lookup(name: &str) -> Option<f64> {
+    CONSTANTS
+        .iter()
+        .find(|(constant, _)| *constant == name)
+        .map(|(_, value)| *value)
+}
@@ -0,0 +1,628 @@
+//! Macro expansion, run on every form before anything else sees it. Macros
+//! are defined with `define-syntax` and `syntax-rules`, as in
+//!
+//! ```scheme
+//! (define-syntax swap!
+//!   (syntax-rules ()
+//!     ((_ a b) (let ((tmp a)) (set! a b) (set! b tmp)))))
+//! ```
+//!
+//! and a use of one, `(swap! x y)`, is replaced by the template of the first
+//! rule whose pattern matches it, with the pattern variables filled in.
+//!
+//! Expansion is hygienic through renaming: every symbol a template brings in
+//! is renamed apart, to `tmp~1` say, and then renamed back unless the
+//! expansion binds it. So the `tmp` above cannot capture a `tmp` the user
+//! passes in, while `let` and `set!` still mean what they always do. The
+//! other half of hygiene is not provided: a free symbol of a template, such
+//! as a global function it calls, means whatever that name means where the
+//! macro is used.
+//!
+//! Macros can also be defined Common Lisp style, with `defmacro`:
+//!
+//! ```scheme
+//! (defmacro unroll-sum (v n)
+//!   (let loop ((i (- n 1)) (terms '()))
+//!     (if (< i 0)
+//!         `(+ ,@terms)
+//!         (loop (- i 1) (cons `(vector-ref ,v ,i) terms)))))
+//! ```
+//!
+//! A use of one runs its body, in the interpreter of `interpret`, with the
+//! parameters bound to the use's arguments as they were written, and is
+//! replaced by what the body returns. That is not hygienic; `(gensym)` gives
+//! a symbol that cannot clash with any other.
+
+use crate::interpret;
+use crate::lift::variable_name;
+use crate::{function_parts, Expr};
+use std::collections::HashMap;
+
+/// How deeply macro uses may expand into further macro uses, so that a macro
+/// that expands into itself fails rather than overflowing the stack.
+const MAX_DEPTH: usize = 1000;
+
+/// A `syntax-rules` transformer.
+#[derive(Clone, Debug)]
+struct SyntaxRules {
+    literals: Vec<String>,
+    /// Each pattern, without the macro keyword, and its template.
+    rules: Vec<(Vec<Expr>, Expr)>,
+}
+
+/// A `defmacro` macro.
+#[derive(Clone, Debug)]
+struct Procedure {
+    params: Vec<String>,
+    /// The parameter after `&rest`, which takes the remaining arguments as a
+    /// list.
+    rest: Option<String>,
+    body: Vec<Expr>,
+}
+
+#[derive(Clone, Debug)]
+enum Macro {
+    Rules(SyntaxRules),
+    Procedure(Procedure),
+}
+
+/// What a pattern variable matched: a form, or under an ellipsis, what it
+/// matched in each repetition.
+#[derive(Clone, Debug)]
+enum Binding {
+    One(Expr),
+    Many(Vec<Binding>),
+}
+
+type Bindings = HashMap<String, Binding>;
+
+/// The macros a session has defined so far.
+#[derive(Clone, Debug, Default)]
+pub struct Expander {
+    macros: HashMap<String, Macro>,
+    /// The symbol each renamed template symbol stands for.
+    renamed: HashMap<String, String>,
+    /// Numbers the expansions, to keep their renamed symbols apart, and the
+    /// symbols `gensym` makes.
+    counter: usize,
+}
+
+impl Expander {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Expands a top-level form. A `define-syntax` or `defmacro` defines its
+    /// macro and gives `None`; a definition of a function or variable of the same name
+    /// as a macro replaces the macro.
+    pub fn expand_top(&mut self, expr: &Expr) -> Result<Option<Expr>, &'static str> {
+        if let Expr::List(exprs) = expr {
+            if let [Expr::Symbol(op), args @ ..] = exprs.as_slice() {
+                if op == "define-syntax" {
+                    let (name, rules) = syntax_definition(args)?;
+                    self.macros.insert(name.to_string(), Macro::Rules(rules));
+                    return Ok(None);
+                }
+                if op == "defmacro" {
+                    let (name, procedure) = macro_definition(args)?;
+                    self.macros
+                        .insert(name.to_string(), Macro::Procedure(procedure));
+                    return Ok(None);
+                }
+            }
+        }
+        let expanded = self.expand_form(expr, true, 0)?;
+        let defined = match function_parts(&expanded)? {
+            Some((name, _, _)) => Some(name),
+            None => variable_name(&expanded),
+        };
+        if let Some(name) = defined {
+            self.macros.remove(name);
+        }
+        Ok(Some(expanded))
+    }
+
+    /// The names of the macros defined so far.
+    pub fn macro_names(&self) -> impl Iterator<Item = &str> {
+        self.macros.keys().map(String::as_str)
+    }
+
+    /// Expands every macro use in `expr`.
+    pub fn expand(&mut self, expr: &Expr) -> Result<Expr, &'static str> {
+        self.expand_form(expr, false, 0)
+    }
+
+    /// The symbol `name` stands for, if it is a renamed template symbol.
+    fn original<'s>(&'s self, name: &'s str) -> &'s str {
+        self.renamed.get(name).map_or(name, String::as_str)
+    }
+
+    /// Expands `expr`, which is a top-level form if `top`, at `depth`
+    /// macro uses deep.
+    fn expand_form(&mut self, expr: &Expr, top: bool, depth: usize) -> Result<Expr, &'static str> {
+        let exprs = match expr {
+            Expr::List(exprs) => exprs,
+            _ => return Ok(expr.clone()),
+        };
+        let (op, args) = match exprs.split_first() {
+            Some((Expr::Symbol(op), args)) => (self.original(op).to_string(), args),
+            _ => return self.expand_all(exprs, depth).map(Expr::List),
+        };
+        let rules = match self.macros.get(&op).cloned() {
+            Some(Macro::Rules(rules)) => Some(rules),
+            Some(Macro::Procedure(procedure)) => {
+                if depth == MAX_DEPTH {
+                    return Err("macro expansion did not terminate.");
+                }
+                let expansion = self.run(&procedure, args)?;
+                return self.expand_form(&expansion, top, depth + 1);
+            }
+            None => None,
+        };
+        if let Some(rules) = rules {
+            if depth == MAX_DEPTH {
+                return Err("macro expansion did not terminate.");
+            }
+            self.counter += 1;
+            let mut aliases = HashMap::new();
+            let transcribed = self.transcribe_use(&rules, args, &mut aliases)?;
+            for (name, alias) in &aliases {
+                self.renamed.insert(alias.clone(), name.clone());
+            }
+            let expanded = self.expand_form(&transcribed, top, depth + 1)?;
+            let mut bound = vec![];
+            self.binders(&expanded, !top, &mut bound);
+            return Ok(restore(&expanded, &aliases, &bound, false));
+        }
+
+        let mut expanded = vec![exprs[0].clone()];
+        match (op.as_str(), args) {
+            ("quote", _) => return Ok(expr.clone()),
+            ("define-syntax" | "defmacro", _) => {
+                return Err("macros can only be defined at the top level.")
+            }
+            // type declarations hold no expressions
+            (":", _) => return Ok(expr.clone()),
+            // the signature is not a call
+            ("define", [sig @ Expr::List(_), body @ ..]) => {
+                expanded.push(sig.clone());
+                expanded.extend(self.expand_all(body, depth)?);
+            }
+            ("lambda", [params, body @ ..]) => {
+                expanded.push(params.clone());
+                expanded.extend(self.expand_all(body, depth)?);
+            }
+            ("let", [name @ Expr::Symbol(_), Expr::List(bindings), body @ ..]) => {
+                expanded.push(name.clone());
+                expanded.push(Expr::List(self.expand_bindings(bindings, depth)?));
+                expanded.extend(self.expand_all(body, depth)?);
+            }
+            ("let", [Expr::List(bindings), body @ ..]) => {
+                expanded.push(Expr::List(self.expand_bindings(bindings, depth)?));
+                expanded.extend(self.expand_all(body, depth)?);
+            }
+            ("do", [Expr::List(specs), rest @ ..]) => {
+                expanded.push(Expr::List(self.expand_bindings(specs, depth)?));
+                expanded.extend(self.expand_all(rest, depth)?);
+            }
+            _ => expanded.extend(self.expand_all(args, depth)?),
+        }
+        Ok(Expr::List(expanded))
+    }
+
+    fn expand_all(&mut self, exprs: &[Expr], depth: usize) -> Result<Vec<Expr>, &'static str> {
+        exprs
+            .iter()
+            .map(|expr| self.expand_form(expr, false, depth))
+            .collect()
+    }
+
+    /// Expands the values in `let` bindings or `do` variables, leaving the
+    /// names alone.
+    fn expand_bindings(
+        &mut self,
+        bindings: &[Expr],
+        depth: usize,
+    ) -> Result<Vec<Expr>, &'static str> {
+        bindings
+            .iter()
+            .map(|binding| match binding {
+                Expr::List(parts) if !parts.is_empty() => {
+                    let mut expanded = vec![parts[0].clone()];
+                    expanded.extend(self.expand_all(&parts[1..], depth)?);
+                    Ok(Expr::List(expanded))
+                }
+                _ => Ok(binding.clone()),
+            })
+            .collect()
+    }
+
+    /// What a use of a `defmacro` macro with arguments `args` expands to.
+    fn run(&mut self, procedure: &Procedure, args: &[Expr]) -> Result<Expr, &'static str> {
+        let count = procedure.params.len();
+        if args.len() < count || (procedure.rest.is_none() && args.len() > count) {
+            return Err("wrong number of arguments");
+        }
+        let mut bindings: Vec<(String, Expr)> = procedure
+            .params
+            .iter()
+            .cloned()
+            .zip(args.iter().cloned())
+            .collect();
+        if let Some(rest) = &procedure.rest {
+            bindings.push((rest.clone(), Expr::List(args[count..].to_vec())));
+        }
+        interpret::run(bindings, &procedure.body, &mut self.counter)
+    }
+
+    /// The template of the first rule of `rules` that matches a use of the
+    /// macro with arguments `args`, filled in. `aliases` collects what each
+    /// symbol the template brings in was renamed to.
+    fn transcribe_use(
+        &self,
+        rules: &SyntaxRules,
+        args: &[Expr],
+        aliases: &mut HashMap<String, String>,
+    ) -> Result<Expr, &'static str> {
+        for (pattern, template) in &rules.rules {
+            let mut bindings = Bindings::new();
+            if self.match_sequence(pattern, args, &rules.literals, &mut bindings) {
+                return self.transcribe(template, &bindings, aliases);
+            }
+        }
+        Err("no syntax-rules pattern matches this use of the macro.")
+    }
+
+    fn match_pattern(
+        &self,
+        pattern: &Expr,
+        form: &Expr,
+        literals: &[String],
+        bindings: &mut Bindings,
+    ) -> bool {
+        match (pattern, form) {
+            (Expr::Symbol(name), _) if name == "_" => true,
+            (Expr::Symbol(name), _) if literals.contains(name) => {
+                matches!(form, Expr::Symbol(symbol) if self.original(symbol) == name)
+            }
+            (Expr::Symbol(name), _) => {
+                bindings.insert(name.clone(), Binding::One(form.clone()));
+                true
+            }
+            (Expr::List(patterns), Expr::List(forms))
+            | (Expr::Vector(patterns), Expr::Vector(forms)) => {
+                self.match_sequence(patterns, forms, literals, bindings)
+            }
+            (Expr::List(_) | Expr::Vector(_), _) => false,
+            _ => pattern == form,
+        }
+    }
+
+    /// Matches `forms` against `patterns`, at most one of which may be
+    /// followed by an ellipsis.
+    fn match_sequence(
+        &self,
+        patterns: &[Expr],
+        forms: &[Expr],
+        literals: &[String],
+        bindings: &mut Bindings,
+    ) -> bool {
+        let ellipsis = match patterns.iter().position(is_ellipsis) {
+            Some(ellipsis) => ellipsis,
+            None => {
+                return patterns.len() == forms.len()
+                    && patterns.iter().zip(forms).all(|(pattern, form)| {
+                        self.match_pattern(pattern, form, literals, bindings)
+                    })
+            }
+        };
+        let (before, repeated, after) = (
+            &patterns[..ellipsis - 1],
+            &patterns[ellipsis - 1],
+            &patterns[ellipsis + 1..],
+        );
+        if forms.len() < before.len() + after.len() {
+            return false;
+        }
+        let repeats = forms.len() - after.len();
+        let matched = before
+            .iter()
+            .zip(forms)
+            .chain(after.iter().zip(&forms[repeats..]))
+            .all(|(pattern, form)| self.match_pattern(pattern, form, literals, bindings));
+        if !matched {
+            return false;
+        }
+
+        let mut vars = vec![];
+        pattern_variables(repeated, literals, &mut vars);
+        let mut each: HashMap<&str, Vec<Binding>> =
+            vars.iter().map(|var| (var.as_str(), vec![])).collect();
+        for form in &forms[before.len()..repeats] {
+            let mut inner = Bindings::new();
+            if !self.match_pattern(repeated, form, literals, &mut inner) {
+                return false;
+            }
+            for (var, bound) in inner {
+                if let Some(each) = each.get_mut(var.as_str()) {
+                    each.push(bound);
+                }
+            }
+        }
+        for (var, bound) in each {
+            bindings.insert(var.to_string(), Binding::Many(bound));
+        }
+        true
+    }
+
+    fn transcribe(
+        &self,
+        template: &Expr,
+        bindings: &Bindings,
+        aliases: &mut HashMap<String, String>,
+    ) -> Result<Expr, &'static str> {
+        match template {
+            Expr::Symbol(name) => match bindings.get(name) {
+                Some(Binding::One(form)) => Ok(form.clone()),
+                Some(Binding::Many(_)) => {
+                    Err("a pattern variable matched under an ellipsis needs one in the template.")
+                }
+                None if is_ellipsis(template) => {
+                    Err("an ellipsis in a template must follow a form.")
+                }
+                None => {
+                    let alias = aliases
+                        .entry(name.clone())
+                        .or_insert_with(|| format!("{}~{}", name, self.counter));
+                    Ok(Expr::Symbol(alias.clone()))
+                }
+            },
+            Expr::List(templates) => self
+                .transcribe_sequence(templates, bindings, aliases)
+                .map(Expr::List),
+            Expr::Vector(templates) => self
+                .transcribe_sequence(templates, bindings, aliases)
+                .map(Expr::Vector),
+            _ => Ok(template.clone()),
+        }
+    }
+
+    /// Fills in a list of templates, repeating each one followed by an
+    /// ellipsis once for every form its pattern variables matched.
+    fn transcribe_sequence(
+        &self,
+        templates: &[Expr],
+        bindings: &Bindings,
+        aliases: &mut HashMap<String, String>,
+    ) -> Result<Vec<Expr>, &'static str> {
+        let mut forms = vec![];
+        let mut i = 0;
+        while i < templates.len() {
+            let template = &templates[i];
+            if !templates.get(i + 1).is_some_and(is_ellipsis) {
+                forms.push(self.transcribe(template, bindings, aliases)?);
+                i += 1;
+                continue;
+            }
+            let mut vars = vec![];
+            pattern_variables(template, &[], &mut vars);
+            let repeated: Vec<(&String, &Vec<Binding>)> = vars
+                .iter()
+                .filter_map(|var| match bindings.get_key_value(var) {
+                    Some((var, Binding::Many(each))) => Some((var, each)),
+                    _ => None,
+                })
+                .collect();
+            let count = match repeated.first() {
+                Some((_, each)) => each.len(),
+                None => return Err("an ellipsis in a template must follow a pattern variable."),
+            };
+            if repeated.iter().any(|(_, each)| each.len() != count) {
+                return Err(
+                    "pattern variables under one ellipsis matched different numbers of forms.",
+                );
+            }
+            for n in 0..count {
+                let mut inner = bindings.clone();
+                for (var, each) in &repeated {
+                    inner.insert(var.to_string(), each[n].clone());
+                }
+                forms.push(self.transcribe(template, &inner, aliases)?);
+            }
+            i += 2;
+        }
+        Ok(forms)
+    }
+
+    /// Collects the variables `expr` binds: the names bound by `let`, `do`,
+    /// `lambda` and function definitions, and by `define` when `nested` in a
+    /// body rather than at the top level.
+    fn binders(&self, expr: &Expr, nested: bool, bound: &mut Vec<String>) {
+        let exprs = match expr {
+            Expr::List(exprs) => exprs,
+            _ => return,
+        };
+        let (op, args) = match exprs.split_first() {
+            Some((Expr::Symbol(op), args)) => (self.original(op), args),
+            _ => {
+                exprs
+                    .iter()
+                    .for_each(|expr| self.binders(expr, true, bound));
+                return;
+            }
+        };
+        let first = |expr: &Expr| match expr {
+            Expr::Symbol(name) => Some(name.clone()),
+            Expr::List(parts) => match parts.first() {
+                Some(Expr::Symbol(name)) => Some(name.clone()),
+                _ => None,
+            },
+            _ => None,
+        };
+        match (op, args) {
+            ("quote", _) => return,
+            ("define", [Expr::List(sig), ..]) => {
+                let (name, params) = sig.split_first().unwrap_or((&exprs[0], &[]));
+                if nested {
+                    bound.extend(first(name));
+                }
+                bound.extend(params.iter().filter_map(first));
+            }
+            ("define", [Expr::Symbol(name), ..]) if nested => bound.push(name.clone()),
+            ("lambda", [Expr::List(params), ..]) => bound.extend(params.iter().filter_map(first)),
+            ("lambda", [Expr::Symbol(rest), ..]) => bound.push(rest.clone()),
+            ("let", [Expr::Symbol(name), Expr::List(bindings), ..]) => {
+                bound.push(name.clone());
+                bound.extend(bindings.iter().filter_map(first));
+            }
+            ("let" | "do", [Expr::List(bindings), ..]) => {
+                bound.extend(bindings.iter().filter_map(first))
+            }
+            _ => {}
+        }
+        args.iter().for_each(|expr| self.binders(expr, true, bound));
+    }
+}
+
+/// Renames the symbols of `aliases` that are not `bound` back to the symbols
+/// they stand for; all of them if `quoted`, since data binds nothing.
+fn restore(expr: &Expr, aliases: &HashMap<String, String>, bound: &[String], quoted: bool) -> Expr {
+    match expr {
+        Expr::Symbol(name) => {
+            let original = aliases.iter().find(|(_, alias)| *alias == name);
+            match original {
+                Some((original, _)) if quoted || !bound.contains(name) => {
+                    Expr::Symbol(original.clone())
+                }
+                _ => expr.clone(),
+            }
+        }
+        Expr::List(exprs) => {
+            let quoted = quoted
+                || matches!(exprs.first(), Some(Expr::Symbol(op))
+                    if op == "quote" || aliases.get("quote") == Some(op));
+            Expr::List(
+                exprs
+                    .iter()
+                    .map(|expr| restore(expr, aliases, bound, quoted))
+                    .collect(),
+            )
+        }
+        Expr::Vector(exprs) => Expr::Vector(
+            exprs
+                .iter()
+                .map(|expr| restore(expr, aliases, bound, quoted))
+                .collect(),
+        ),
+        _ => expr.clone(),
+    }
+}
+
+fn is_ellipsis(expr: &Expr) -> bool {
+    matches!(expr, Expr::Symbol(name) if name == "...")
+}
+
+/// Collects the pattern variables of `pattern`.
+fn pattern_variables(pattern: &Expr, literals: &[String], vars: &mut Vec<String>) {
+    match pattern {
+        Expr::Symbol(name) if name == "_" || name == "..." || literals.contains(name) => {}
+        Expr::Symbol(name) => vars.push(name.clone()),
+        Expr::List(patterns) | Expr::Vector(patterns) => patterns
+            .iter()
+            .for_each(|pattern| pattern_variables(pattern, literals, vars)),
+        _ => {}
+    }
+}
+
+/// Splits the arguments of `(define-syntax name (syntax-rules (literals...)
+/// (pattern template)...))` into the name and the transformer.
+fn syntax_definition(args: &[Expr]) -> Result<(&str, SyntaxRules), &'static str> {
+    let (name, transformer) = match args {
+        [Expr::Symbol(name), Expr::List(transformer)] => (name, transformer),
+        _ => return Err("define-syntax requires a name and a syntax-rules transformer."),
+    };
+    let (literals, clauses) = match transformer.as_slice() {
+        [Expr::Symbol(op), Expr::List(literals), clauses @ ..] if op == "syntax-rules" => {
+            (literals, clauses)
+        }
+        _ => return Err("define-syntax requires a name and a syntax-rules transformer."),
+    };
+    let literals = literals
+        .iter()
+        .map(|literal| match literal {
+            Expr::Symbol(literal) => Ok(literal.clone()),
+            _ => Err("syntax-rules literals must be symbols."),
+        })
+        .collect::<Result<_, _>>()?;
+
+    let mut rules = vec![];
+    for clause in clauses {
+        let (pattern, template) = match clause {
+            Expr::List(clause) => match clause.as_slice() {
+                [Expr::List(pattern), template] if !pattern.is_empty() => (pattern, template),
+                _ => return Err("a syntax-rules clause is written ((_ pattern...) template)."),
+            },
+            _ => return Err("a syntax-rules clause is written ((_ pattern...) template)."),
+        };
+        // the keyword position matches the macro's name, whatever it says
+        let pattern = pattern[1..].to_vec();
+        check_ellipses(&pattern)?;
+        rules.push((pattern, template.clone()));
+    }
+    Ok((name, SyntaxRules { literals, rules }))
+}
+
+/// Splits the arguments of `(defmacro name (params... &rest rest) body...)`
+/// into the name and the macro.
+fn macro_definition(args: &[Expr]) -> Result<(&str, Procedure), &'static str> {
+    let (name, params, body) = match args {
+        [Expr::Symbol(name), Expr::List(params), body @ ..] if !body.is_empty() => {
+            (name, params, body)
+        }
+        _ => return Err("defmacro requires a name, a parameter list and a body."),
+    };
+    let mut names = vec![];
+    for param in params {
+        match param {
+            Expr::Symbol(param) => names.push(param.clone()),
+            _ => return Err("defmacro parameters must be symbols."),
+        }
+    }
+    let rest = match names.iter().position(|param| param == "&rest") {
+        Some(i) if i + 2 == names.len() => {
+            let rest = names.pop();
+            names.pop();
+            rest
+        }
+        Some(_) => return Err("&rest must be followed by exactly one parameter."),
+        None => None,
+    };
+    let procedure = Procedure {
+        params: names,
+        rest,
+        body: body.to_vec(),
+    };
+    Ok((name, procedure))
+}
+
+/// Checks that every ellipsis in a pattern follows a pattern, one per list.
+fn check_ellipses(patterns: &[Expr]) -> Result<(), &'static str> {
+    let ellipses: Vec<usize> = patterns
+        .iter()
+        .enumerate()
+        .filter(|(_, pattern)| is_ellipsis(pattern))
+        .map(|(i, _)| i)
+        .collect();
+    match ellipses.as_slice() {
+        [] => {}
+        [0] => return Err("an ellipsis in a pattern must follow a pattern."),
+        [_] => {}
+        _ => return Err("a pattern can only have one ellipsis per list."),
+    }
+    for pattern in patterns {
+        if let Expr::List(patterns) | Expr::Vector(patterns) = pattern {
+            check_ellipses(patterns)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,310 @@
+//! The garbage collector for the objects `runtime` allocates on behalf of
+//! compiled code: a mark-sweep collector that is precise within the heap and
+//! conservative about its roots.
+//!
+//! Compiled code keeps no maps of where its pointers are, so the roots are
+//! every word on the machine stack between the collector and the frame that
+//! entered compiled code, the callee-saved registers, and the host slots of
+//! global variables. Any of those words that points into an object keeps it
+//! alive, not only its address: optimised code may hold on to nothing but a
+//! pointer derived from it, such as the address of a vector element or of a
+//! pair's `cdr`. That can retain garbage that looks like a pointer, but never
+//! frees anything live. Objects never move.
+
+use crate::runtime::{Pair, Procedure, Ratio};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
+use std::mem::size_of;
+
+/// Collect once this many objects are live, to begin with; after each
+/// collection the threshold is twice what survived.
+const MIN_THRESHOLD: usize = 1 << 12;
+
+/// What a heap object is, so the collector knows how to trace and free it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Ratio,
+    Procedure,
+    Pair,
+    /// A vector of this many floats, after its header, see `vector_words`.
+    Vector(usize),
+    /// A `String` with this many bytes of capacity.
+    String(usize),
+}
+
+/// A type compiled code can hold a pointer to.
+pub(crate) trait Object {
+    const KIND: Kind;
+}
+
+impl Object for Ratio {
+    const KIND: Kind = Kind::Ratio;
+}
+
+impl Object for Procedure {
+    const KIND: Kind = Kind::Procedure;
+}
+
+impl Object for Pair {
+    const KIND: Kind = Kind::Pair;
+}
+
+impl Kind {
+    fn size(self) -> usize {
+        match self {
+            Kind::Ratio => size_of::<Ratio>(),
+            Kind::Procedure => size_of::<Procedure>(),
+            Kind::Pair => size_of::<Pair>(),
+            Kind::Vector(len) => vector_words(len) * size_of::<u64>(),
+            Kind::String(capacity) => size_of::<String>() + capacity,
+        }
+    }
+
+    /// How far into its allocation an object's address is: past the header,
+    /// for a vector.
+    fn offset(self) -> u64 {
+        match self {
+            Kind::Vector(_) => size_of::<u64>() as u64,
+            _ => 0,
+        }
+    }
+
+    /// The bytes of the allocation an object's address points into; the
+    /// text of a `String` is allocated apart.
+    fn extent(self) -> u64 {
+        match self {
+            Kind::String(_) => size_of::<String>() as u64,
+            kind => kind.size() as u64,
+        }
+    }
+}
+
+/// Heap statistics, as `:heap` reports them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub live_objects: usize,
+    pub live_bytes: usize,
+    pub collections: usize,
+    /// Objects freed by every collection so far.
+    pub freed_objects: usize,
+}
+
+struct Heap {
+    /// Every object, by the address its allocation starts at.
+    objects: BTreeMap<u64, Kind>,
+    /// Host slots of global variables, each two words.
+    roots: HashSet<usize>,
+    /// The highest stack address compiled code can be using, while it runs.
+    stack_base: Option<usize>,
+    threshold: usize,
+    stats: Stats,
+}
+
+impl Heap {
+    /// The object whose allocation `word` points into, by where that starts.
+    fn containing(&self, word: u64) -> Option<(u64, Kind)> {
+        let (&start, &kind) = self.objects.range(..=word).next_back()?;
+        (word - start < kind.extent()).then_some((start, kind))
+    }
+}
+
+thread_local! {
+    static HEAP: RefCell<Heap> = RefCell::new(Heap {
+        objects: BTreeMap::new(),
+        roots: HashSet::new(),
+        stack_base: None,
+        threshold: MIN_THRESHOLD,
+        stats: Stats::default(),
+    });
+}
+
+/// Collects if the heap has grown past the threshold while compiled code is
+/// running.
+fn collect_if_due() {
+    let due = HEAP.with(|heap| {
+        let heap = heap.borrow();
+        heap.stack_base.is_some() && heap.objects.len() >= heap.threshold
+    });
+    if due {
+        collect();
+    }
+}
+
+fn register(address: u64, kind: Kind) -> u64 {
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.objects.insert(address - kind.offset(), kind);
+        heap.stats.live_objects += 1;
+        heap.stats.live_bytes += kind.size();
+    });
+    address
+}
+
+/// Moves `object` to the heap and returns its address, collecting first if
+/// the heap has grown past the threshold.
+pub(crate) fn allocate<T: Object>(object: T) -> u64 {
+    collect_if_due();
+    register(Box::into_raw(Box::new(object)) as u64, T::KIND)
+}
+
+/// Moves `text` to the heap and returns its address.
+pub(crate) fn allocate_string(text: String) -> u64 {
+    collect_if_due();
+    let kind = Kind::String(text.capacity());
+    register(Box::into_raw(Box::new(text)) as u64, kind)
+}
+
+/// The words allocated for a vector of `len` floats: its header, then its
+/// elements, with room for one even when it has none. Vectors are known by
+/// the address after the header, which then still lies inside their own
+/// allocation rather than at the start of whatever follows it.
+fn vector_words(len: usize) -> usize {
+    len.max(1) + 1
+}
+
+/// Allocates a vector of `len` copies of `fill`, see `runtime::Vector`, and
+/// returns the address of its first element.
+pub(crate) fn allocate_vector(len: usize, fill: f64) -> u64 {
+    collect_if_due();
+    let mut words = vec![fill.to_bits(); vector_words(len)].into_boxed_slice();
+    words[0] = len as u64;
+    let header = Box::into_raw(words) as *mut u64;
+    register(unsafe { header.add(1) } as u64, Kind::Vector(len))
+}
+
+/// Runs `f`, which calls into compiled code, with the stack below this frame
+/// scanned for roots. Outside of it nothing is collected except by an
+/// explicit `collect`, which then only sees the global variables.
+pub fn with_stack_roots<R>(f: impl FnOnce() -> R) -> R {
+    let base = 0u8;
+    let base = std::hint::black_box(&base) as *const u8 as usize;
+    let outermost = HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        let outermost = heap.stack_base.is_none();
+        if outermost {
+            heap.stack_base = Some(base);
+        }
+        outermost
+    });
+    let result = f();
+    if outermost {
+        HEAP.with(|heap| heap.borrow_mut().stack_base = None);
+    }
+    result
+}
+
+/// Treats the two words at `slot` as roots until `remove_root`.
+pub fn add_root(slot: *const u64) {
+    HEAP.with(|heap| heap.borrow_mut().roots.insert(slot as usize));
+}
+
+pub fn remove_root(slot: *const u64) {
+    HEAP.with(|heap| heap.borrow_mut().roots.remove(&(slot as usize)));
+}
+
+pub fn stats() -> Stats {
+    HEAP.with(|heap| heap.borrow().stats)
+}
+
+/// The callee-saved registers, which may hold the only copy of a pointer
+/// that compiled code up the stack is still using.
+#[inline(always)]
+fn saved_registers() -> [usize; 12] {
+    let mut registers = [0usize; 12];
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        std::arch::asm!(
+            "mov [{0}], rbx",
+            "mov [{0} + 8], rbp",
+            "mov [{0} + 16], r12",
+            "mov [{0} + 24], r13",
+            "mov [{0} + 32], r14",
+            "mov [{0} + 40], r15",
+            in(reg) registers.as_mut_ptr(),
+            options(nostack, preserves_flags),
+        );
+    }
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        std::arch::asm!(
+            "stp x19, x20, [{0}]",
+            "stp x21, x22, [{0}, #16]",
+            "stp x23, x24, [{0}, #32]",
+            "stp x25, x26, [{0}, #48]",
+            "stp x27, x28, [{0}, #64]",
+            "stp x29, x30, [{0}, #80]",
+            in(reg) registers.as_mut_ptr(),
+            options(nostack, preserves_flags),
+        );
+    }
+    registers
+}
+
+/// Frees every object that is not reachable from the roots, returning how
+/// many were freed.
+#[inline(never)]
+pub fn collect() -> usize {
+    let registers = saved_registers();
+    let registers = std::hint::black_box(&registers);
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        let mut pending: Vec<u64> = registers.iter().map(|word| *word as u64).collect();
+        for slot in &heap.roots {
+            let slot = *slot as *const u64;
+            pending.extend(unsafe { [*slot, *slot.add(1)] });
+        }
+        if let Some(base) = heap.stack_base {
+            // the stack grows down, from `base` to the registers just saved
+            let mut address = registers.as_ptr() as usize;
+            while address < base {
+                pending.push(unsafe { std::ptr::read_volatile(address as *const u64) });
+                address += size_of::<u64>();
+            }
+        }
+
+        let mut marked = HashSet::new();
+        while let Some(word) = pending.pop() {
+            let (start, kind) = match heap.containing(word) {
+                Some((start, kind)) if marked.insert(start) => (start, kind),
+                _ => continue,
+            };
+            match kind {
+                Kind::Pair => {
+                    let pair = unsafe { *(start as *const Pair) };
+                    pending.extend([pair.car, pair.cdr].iter().filter_map(|v| v.heap_object()));
+                }
+                // numbers, code addresses, floats and text: nothing that
+                // points into the heap
+                Kind::Ratio | Kind::Procedure | Kind::Vector(_) | Kind::String(_) => {}
+            }
+        }
+
+        let garbage: Vec<(u64, Kind)> = heap
+            .objects
+            .iter()
+            .filter(|(start, _)| !marked.contains(*start))
+            .map(|(start, kind)| (*start, *kind))
+            .collect();
+        for (start, kind) in &garbage {
+            heap.objects.remove(start);
+            heap.stats.live_bytes -= kind.size();
+            unsafe {
+                match kind {
+                    Kind::Ratio => drop(Box::from_raw(*start as *mut Ratio)),
+                    Kind::Procedure => drop(Box::from_raw(*start as *mut Procedure)),
+                    Kind::Pair => drop(Box::from_raw(*start as *mut Pair)),
+                    Kind::String(_) => drop(Box::from_raw(*start as *mut String)),
+                    Kind::Vector(len) => drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+                        *start as *mut u64,
+                        vector_words(*len),
+                    ))),
+                }
+            }
+        }
+        heap.stats.live_objects = heap.objects.len();
+        heap.stats.collections += 1;
+        heap.stats.freed_objects += garbage.len();
+        heap.threshold = MIN_THRESHOLD.max(2 * heap.objects.len());
+        garbage.len()
+    })
+}
@@ -0,0 +1,97 @@
+use crate::lexer::{tokenize, Token, TokenKind};
+
+const RESET: &str = "\x1b[0m";
+const NUMBER: &str = "\x1b[36m";
+const BOOLEAN: &str = "\x1b[1;36m";
+const SPECIAL_FORM: &str = "\x1b[1;35m";
+const STRING: &str = "\x1b[32m";
+const COMMENT: &str = "\x1b[90m";
+const UNMATCHED: &str = "\x1b[1;41m";
+/// Added on top of the depth colour for the paren pair under the cursor.
+const MATCHED: &str = "\x1b[1;4m";
+
+/// Paren colours, cycled by nesting depth.
+const RAINBOW: &[&str] = &[
+    "\x1b[33m", "\x1b[35m", "\x1b[34m", "\x1b[32m", "\x1b[36m", "\x1b[31m",
+];
+
+/// Pairs each paren token with its partner. `partners[i]` is the index of the
+/// token matching token `i`, and `depths[i]` is the nesting depth of a paren.
+fn match_parens(tokens: &[Token]) -> (Vec<Option<usize>>, Vec<usize>) {
+    let mut partners = vec![None; tokens.len()];
+    let mut depths = vec![0; tokens.len()];
+    let mut open = vec![];
+
+    for (i, token) in tokens.iter().enumerate() {
+        match token.kind {
+            TokenKind::LParen => {
+                depths[i] = open.len();
+                open.push(i);
+            }
+            TokenKind::RParen => {
+                if let Some(j) = open.pop() {
+                    partners[i] = Some(j);
+                    partners[j] = Some(i);
+                    depths[i] = open.len();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    (partners, depths)
+}
+
+/// Index of the paren token at `pos`, or right before it, so that the pair is
+/// highlighted both when the cursor sits on a paren and just after typing one.
+fn paren_at_cursor(tokens: &[Token], pos: usize) -> Option<usize> {
+    let is_paren = |t: &Token| matches!(t.kind, TokenKind::LParen | TokenKind::RParen);
+    tokens
+        .iter()
+        .position(|t| t.start == pos && is_paren(t))
+        .or_else(|| tokens.iter().position(|t| t.end == pos && is_paren(t)))
+}
+
+/// Returns `line` with ANSI colours: numbers, booleans, special forms,
+/// strings and comments each get their own colour, parens are coloured by depth, and the
+/// pair enclosing the cursor at `pos` (if it is on a paren) is emphasised.
+pub fn highlight(line: &str, pos: Option<usize>) -> String {
+    let tokens = tokenize(line);
+    let (partners, depths) = match_parens(&tokens);
+
+    let mut cursor_pair = (None, None);
+    if let Some(i) = pos.and_then(|pos| paren_at_cursor(&tokens, pos)) {
+        cursor_pair = (Some(i), partners[i]);
+    }
+
+    let mut out = String::with_capacity(line.len() * 2);
+    for (i, token) in tokens.iter().enumerate() {
+        let text = &line[token.start..token.end];
+        let color = match token.kind {
+            TokenKind::LParen | TokenKind::RParen if partners[i].is_none() => UNMATCHED,
+            TokenKind::LParen | TokenKind::RParen => RAINBOW[depths[i] % RAINBOW.len()],
+            TokenKind::Number => NUMBER,
+            TokenKind::Boolean => BOOLEAN,
+            TokenKind::SpecialForm | TokenKind::Quote => SPECIAL_FORM,
+            TokenKind::String | TokenKind::Char => STRING,
+            TokenKind::Comment => COMMENT,
+            TokenKind::Symbol | TokenKind::Whitespace => {
+                out.push_str(text);
+                continue;
+            }
+        };
+
+        out.push_str(color);
+        if partners[i].is_some() && (cursor_pair.0 == Some(i) || cursor_pair.1 == Some(i)) {
+            out.push_str(MATCHED);
+        }
+        out.push_str(text);
+        out.push_str(RESET);
+    }
+    out
+}
+
+/// Dims a completion hint so it reads as a suggestion rather than input.
+pub fn highlight_hint(hint: &str) -> String {
+    format!("{}{}{}", COMMENT, hint, RESET)
+}
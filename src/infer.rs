@@ -0,0 +1,477 @@
+//! Type inference, run over the definitions an expression reaches before any
+//! of them is compiled. Every function is specialised on the types of the
+//! arguments it is called with, Julia-style, so inference only has to find
+//! the return type of each specialisation: an unboxed machine type wherever
+//! the body settles one, and `Ty::Dynamic` where it does not.
+//!
+//! Recursive specialisations are solved by iterating to a fixed point. Their
+//! calls back into themselves start out unknown and do not constrain the
+//! result, which is how a recursive `factorial` gets an i64 return type from
+//! its base case; each round then feeds the return types found so far back
+//! in, so that a recursive case that changes the type (say by dividing) is
+//! seen as well. Return types only ever widen, so this terminates.
+//!
+//! A variable keeps the type of the value it is bound to, unless something
+//! in its scope `set!`s it to a value that type cannot hold; then it is
+//! stored boxed, as `Ty::Dynamic`. Finding out means inferring the scope, so
+//! each binding form's scope is inferred again until no more of its
+//! variables need boxing.
+
+use crate::lift::variable_name;
+use crate::{
+    comparison, constants, declaration_parts, definition_signature, extract_op_and_args,
+    function_parts, join_types, specialization_name, Expr, Signature, Ty, PREDICATES,
+};
+use crate::{do_parts, do_scope, let_bindings};
+use std::collections::{HashMap, HashSet};
+
+pub struct Inference<'a> {
+    definitions: HashMap<&'a str, &'a Expr>,
+    /// The declared types of every function that has any.
+    signatures: HashMap<&'a str, Signature>,
+    globals: HashMap<String, Ty>,
+    /// The return type of every specialisation seen so far, by mangled name.
+    /// Final once the outermost `return_type` call returns.
+    returns: HashMap<String, Option<Ty>>,
+    /// Specialisations whose body has been inferred in the current round.
+    visited: HashSet<String>,
+    /// Whether the current round widened any return type.
+    changed: bool,
+    /// Variables `set!` to a value their type cannot hold, in the scopes
+    /// being inferred, see `assigned_types`.
+    assigned: HashSet<String>,
+    depth: usize,
+}
+
+impl<'a> Inference<'a> {
+    /// Prepares to infer calls to the function definitions among `forms`,
+    /// with global variables of the given types. Type annotations in a
+    /// definition take precedence over a separate declaration.
+    pub fn new(forms: impl IntoIterator<Item = &'a Expr>, globals: HashMap<String, Ty>) -> Self {
+        let mut definitions = HashMap::new();
+        let mut annotations = HashMap::new();
+        let mut declarations = HashMap::new();
+        for form in forms {
+            if let Ok(Some((name, _, _))) = function_parts(form) {
+                definitions.insert(name, form);
+            }
+            if let Ok(Some((name, signature))) = definition_signature(form) {
+                annotations.insert(name, signature);
+            }
+            if let Ok(Some((name, signature))) = declaration_parts(form) {
+                declarations.insert(name, signature);
+            }
+        }
+        let mut signatures = declarations;
+        for (name, annotated) in annotations {
+            let signature = match signatures.get(name) {
+                Some(declared) => annotated.or(declared),
+                None => annotated,
+            };
+            signatures.insert(name, signature);
+        }
+        Inference {
+            definitions,
+            signatures,
+            globals,
+            returns: HashMap::new(),
+            visited: HashSet::new(),
+            changed: false,
+            assigned: HashSet::new(),
+            depth: 0,
+        }
+    }
+
+    /// The declared types of function `name`, if it has any.
+    pub fn signature(&self, name: &str) -> Option<&Signature> {
+        self.signatures.get(name)
+    }
+
+    /// The name and parameter types of the version of function `name` that
+    /// is called with arguments of types `tys`. Declared parameter types
+    /// replace the arguments' own, and a function whose parameters are all
+    /// declared has just the one version, under its own name.
+    pub fn specialization(&self, name: &str, tys: &[Ty]) -> (String, Vec<Ty>) {
+        match self.signature(name) {
+            Some(signature) if signature.is_complete() => {
+                (name.to_string(), signature.param_types(tys))
+            }
+            Some(signature) => {
+                let tys = signature.param_types(tys);
+                (specialization_name(name, &tys), tys)
+            }
+            None => (specialization_name(name, tys), tys.to_vec()),
+        }
+    }
+
+    /// Records the type of a global variable defined after inference began.
+    pub fn define_global(&mut self, name: &str, ty: Ty) {
+        self.globals.insert(name.to_string(), ty);
+    }
+
+    /// The return type of function `name` called with arguments of types
+    /// `tys`: the declared one, or else the one inferred for the version
+    /// `specialization` picks. `None` if it has no definition taking that
+    /// many arguments or its body never produces a value.
+    pub fn return_type(&mut self, name: &str, tys: &[Ty]) -> Option<Ty> {
+        if let Some(ret) = self.signature(name).and_then(|signature| signature.ret) {
+            return Some(ret);
+        }
+        let (mangled, tys) = self.specialization(name, tys);
+        if self.depth == 0 {
+            if let Some(ty) = self.returns.get(&mangled) {
+                return *ty;
+            }
+        } else if self.visited.contains(&mangled) {
+            // a recursive call, or one already inferred this round
+            return self.returns.get(&mangled).copied().flatten();
+        }
+
+        let def = *self.definitions.get(name)?;
+        let (_, params, body) = function_parts(def).ok()??;
+        if params.len() != tys.len() {
+            return None;
+        }
+        let params: Vec<&str> = params.iter().map(String::as_str).collect();
+
+        if self.depth > 0 {
+            return self.infer_body(mangled, &params, &tys, body);
+        }
+        loop {
+            self.changed = false;
+            self.visited.clear();
+            let ty = self.infer_body(mangled.clone(), &params, &tys, body);
+            if !self.changed {
+                return ty;
+            }
+        }
+    }
+
+    /// Infers one specialisation's body with the return types known so far,
+    /// widening its own return type with the result.
+    fn infer_body(
+        &mut self,
+        mangled: String,
+        params: &[&str],
+        tys: &[Ty],
+        body: &[Expr],
+    ) -> Option<Ty> {
+        self.visited.insert(mangled.clone());
+        self.depth += 1;
+        let tys = self.scope_types(params, tys.to_vec(), body, &HashMap::new());
+        let ty = self.infer_sequence(body, &bind(&HashMap::new(), params, &tys));
+        self.depth -= 1;
+
+        let previous = self.returns.get(&mangled).copied();
+        let ty = match (previous.flatten(), ty) {
+            (Some(a), Some(b)) => Some(a.join(b)),
+            (a, b) => a.or(b),
+        };
+        if previous != Some(ty) {
+            self.changed = true;
+        }
+        self.returns.insert(mangled, ty);
+        ty
+    }
+
+    /// The types to store variables `vars` as, when they are bound to values
+    /// of types `tys` and `visit` infers every expression in their scope:
+    /// those types, except that a variable the scope `set!`s to a value its
+    /// type cannot hold is boxed.
+    pub fn assigned_types<'e>(
+        &mut self,
+        vars: &[&'e str],
+        mut tys: Vec<Ty>,
+        env: &HashMap<&'e str, Ty>,
+        mut visit: impl FnMut(&mut Self, &HashMap<&'e str, Ty>),
+    ) -> Vec<Ty> {
+        // assignments to the variables of an enclosing scope of the same
+        // names are that scope's business
+        let outer: Vec<bool> = vars.iter().map(|var| self.assigned.remove(*var)).collect();
+        loop {
+            visit(self, &bind(env, vars, &tys));
+            let mut boxed = false;
+            for (var, ty) in vars.iter().zip(tys.iter_mut()) {
+                if self.assigned.remove(*var) && *ty != Ty::Dynamic {
+                    *ty = Ty::Dynamic;
+                    boxed = true;
+                }
+            }
+            if !boxed {
+                break;
+            }
+        }
+        for (var, outer) in vars.iter().zip(outer) {
+            if outer {
+                self.assigned.insert(var.to_string());
+            }
+        }
+        tys
+    }
+
+    /// The types to store variables `vars` bound to values of types `tys`
+    /// as, over the sequence `body`, see `assigned_types`.
+    pub fn scope_types<'e>(
+        &mut self,
+        vars: &[&'e str],
+        tys: Vec<Ty>,
+        body: &[Expr],
+        env: &HashMap<&'e str, Ty>,
+    ) -> Vec<Ty> {
+        if !body.iter().any(|expr| assigns(vars, expr)) {
+            return tys;
+        }
+        self.assigned_types(vars, tys, env, |inference, scope| {
+            inference.infer_sequence(body, scope);
+        })
+    }
+
+    /// The types of loop variables `vars` that start out with values of types
+    /// `tys` and are rebound to the values of `updates`, each the index of a
+    /// variable and an expression computed from all of them: the starting
+    /// types, widened until the updates fit. The expressions in `scope` can
+    /// also `set!` the variables, see `assigned_types`.
+    pub fn loop_types<'e>(
+        &mut self,
+        vars: &[&'e str],
+        mut tys: Vec<Ty>,
+        updates: &[(usize, &Expr)],
+        scope: &[&Expr],
+        env: &HashMap<&'e str, Ty>,
+    ) -> Vec<Ty> {
+        loop {
+            let loop_env = bind(env, vars, &tys);
+            let mut widened = tys.clone();
+            for (i, update) in updates {
+                if let Some(ty) = self.infer(update, &loop_env) {
+                    widened[*i] = widened[*i].join(ty);
+                }
+            }
+            if widened == tys {
+                if !scope.iter().any(|expr| assigns(vars, expr)) {
+                    return tys;
+                }
+                widened = self.assigned_types(vars, tys.clone(), env, |inference, env| {
+                    for expr in scope {
+                        inference.infer(expr, env);
+                    }
+                });
+                if widened == tys {
+                    return tys;
+                }
+            }
+            tys = widened;
+        }
+    }
+
+    /// The types of the variables of the named let `name`, which start out
+    /// with values of types `tys` and are rebound by every call to `name`
+    /// in `body`.
+    pub fn named_let_types<'e>(
+        &mut self,
+        name: &str,
+        vars: &[&'e str],
+        tys: Vec<Ty>,
+        body: &[Expr],
+        env: &HashMap<&'e str, Ty>,
+    ) -> Vec<Ty> {
+        let mut calls = vec![];
+        for expr in body {
+            loop_calls(name, expr, &mut calls);
+        }
+        let updates: Vec<(usize, &Expr)> = calls
+            .iter()
+            .flat_map(|args| args.iter().enumerate())
+            .collect();
+        let scope: Vec<&Expr> = body.iter().collect();
+        self.loop_types(vars, tys, &updates, &scope, env)
+    }
+
+    /// The type of a `begin` or function body: that of its last expression,
+    /// though the others are inferred too, for the calls they make.
+    pub fn infer_sequence(&mut self, exprs: &[Expr], env: &HashMap<&str, Ty>) -> Option<Ty> {
+        let (last, effects) = exprs.split_last()?;
+        // internal defines are visible to the expressions after them
+        let mut scope: HashMap<&str, Ty> = env.iter().map(|(name, ty)| (*name, *ty)).collect();
+        for (i, expr) in effects.iter().enumerate() {
+            let ty = self.infer(expr, &scope);
+            if let (Some(name), Some(ty)) = (variable_name(expr), ty) {
+                let ty = self.scope_types(&[name], vec![ty], &exprs[i + 1..], &scope)[0];
+                scope.insert(name, ty);
+            }
+        }
+        self.infer(last, &scope)
+    }
+
+    /// Infers the type `expr` will compile to, given the types of the
+    /// variables in `env`. `None` means it depends on a recursive call whose
+    /// type is not known yet.
+    pub fn infer(&mut self, expr: &Expr, env: &HashMap<&str, Ty>) -> Option<Ty> {
+        match expr {
+            Expr::Bool(_) => Some(Ty::Bool),
+            Expr::Char(_) | Expr::Str(_) => Some(Ty::Dynamic),
+            Expr::Float(_) => Some(Ty::F64),
+            Expr::Integer(_) => Some(Ty::I64),
+            Expr::Rational(..) => Some(Ty::Rational),
+            Expr::Vector(_) => Some(Ty::Vector),
+            Expr::Symbol(name) => env
+                .get(name.as_str())
+                .or_else(|| self.globals.get(name))
+                .copied()
+                .or_else(|| constants::lookup(name).map(|_| Ty::F64))
+                // a function used as a value
+                .or_else(|| {
+                    self.definitions
+                        .contains_key(name.as_str())
+                        .then_some(Ty::Dynamic)
+                }),
+            Expr::List(exprs) => {
+                let (op, args) = extract_op_and_args(exprs).ok()?;
+                // the arguments of anything but a special form are all
+                // inferred, for the calls and assignments in them
+                let arg_tys: Vec<Option<Ty>> = match op {
+                    "quote" | "if" | "define" | "begin" | "let" | "do" | "set!" => vec![],
+                    _ => args.iter().map(|arg| self.infer(arg, env)).collect(),
+                };
+                match op {
+                    "+" | "-" | "*" => join_types(arg_tys),
+                    "/" => join_types(arg_tys).map(|ty| match ty {
+                        Ty::I64 => Ty::Rational,
+                        ty => ty,
+                    }),
+                    "quotient" | "remainder" | "modulo" => join_types(arg_tys).map(|ty| match ty {
+                        Ty::I64 | Ty::Dynamic => ty,
+                        _ => Ty::F64,
+                    }),
+                    "exact->inexact" | "inexact" => Some(Ty::F64),
+                    "inexact->exact" | "exact" => match (*arg_tys.first()?)? {
+                        Ty::I64 => Some(Ty::I64),
+                        _ => Some(Ty::Rational),
+                    },
+                    "not" => Some(Ty::Bool),
+                    "length" | "vector-length" | "string-length" | "gc" => Some(Ty::I64),
+                    "display" | "write" | "newline" | "string-append" | "number->string"
+                    | "string->number" => Some(Ty::Dynamic),
+                    "vector" | "make-vector" => Some(Ty::Vector),
+                    "vector-ref" | "vector-set!" => Some(Ty::F64),
+                    "quote" | "cons" | "car" | "cdr" | "list" | "append" | "reverse" => {
+                        Some(Ty::Dynamic)
+                    }
+                    _ if PREDICATES.contains(&op) || comparison(op).is_some() => Some(Ty::Bool),
+                    "if" => {
+                        self.infer(args.first()?, env);
+                        let then_ty = self.infer(args.get(1)?, env);
+                        let else_ty = self.infer(args.get(2)?, env);
+                        match (then_ty, else_ty) {
+                            (Some(a), Some(b)) => Some(a.join(b)),
+                            (a, b) => a.or(b),
+                        }
+                    }
+                    "define" => self.infer(args.get(1)?, env),
+                    "begin" => self.infer_sequence(args, env),
+                    "let" => match args {
+                        [Expr::Symbol(name), Expr::List(bindings), body @ ..] => {
+                            let (vars, inits) = let_bindings(bindings).ok()?;
+                            let tys = inits
+                                .iter()
+                                .map(|init| self.infer(init, env))
+                                .collect::<Option<Vec<_>>>()?;
+                            let tys = self.named_let_types(name, &vars, tys, body, env);
+                            self.infer_sequence(body, &bind(env, &vars, &tys))
+                        }
+                        [Expr::List(bindings), body @ ..] => {
+                            let (vars, inits) = let_bindings(bindings).ok()?;
+                            let tys = inits
+                                .iter()
+                                .map(|init| self.infer(init, env))
+                                .collect::<Option<Vec<_>>>()?;
+                            let tys = self.scope_types(&vars, tys, body, env);
+                            self.infer_sequence(body, &bind(env, &vars, &tys))
+                        }
+                        _ => None,
+                    },
+                    "do" => {
+                        let (specs, test, results, body) = do_parts(args).ok()?;
+                        let vars: Vec<&str> = specs.iter().map(|(var, ..)| *var).collect();
+                        let tys = specs
+                            .iter()
+                            .map(|(_, init, _)| self.infer(init, env))
+                            .collect::<Option<Vec<_>>>();
+                        let steps: Vec<(usize, &Expr)> = specs
+                            .iter()
+                            .enumerate()
+                            .filter_map(|(i, (_, _, step))| step.map(|step| (i, step)))
+                            .collect();
+                        let scope = do_scope(test, body, results, &steps);
+                        let tys = tys.map(|tys| self.loop_types(&vars, tys, &steps, &scope, env));
+                        if results.is_empty() {
+                            return Some(Ty::Dynamic);
+                        }
+                        self.infer_sequence(results, &bind(env, &vars, &tys?))
+                    }
+                    "while" => Some(Ty::Dynamic),
+                    "set!" => {
+                        if let [Expr::Symbol(var), value] = args {
+                            let ty = env.get(var.as_str()).copied();
+                            if let (Some(ty), Some(value)) = (ty, self.infer(value, env)) {
+                                if !ty.holds(value) {
+                                    self.assigned.insert(var.clone());
+                                }
+                            }
+                        }
+                        Some(Ty::Dynamic)
+                    }
+                    _ if op.starts_with("llvm.") => Some(Ty::F64),
+                    // a call through a procedure value
+                    _ if env.contains_key(op)
+                        || (!self.definitions.contains_key(op)
+                            && self.globals.contains_key(op)) =>
+                    {
+                        Some(Ty::Dynamic)
+                    }
+                    _ => {
+                        let tys = arg_tys.into_iter().collect::<Option<Vec<Ty>>>()?;
+                        self.return_type(op, &tys)
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// `env` with `vars` bound to `tys`.
+fn bind<'e>(env: &HashMap<&'e str, Ty>, vars: &[&'e str], tys: &[Ty]) -> HashMap<&'e str, Ty> {
+    let mut scope = env.clone();
+    scope.extend(vars.iter().copied().zip(tys.iter().copied()));
+    scope
+}
+
+/// Whether `expr` `set!`s any of `vars`, outside quoted data.
+fn assigns(vars: &[&str], expr: &Expr) -> bool {
+    match expr {
+        Expr::List(exprs) => match exprs.as_slice() {
+            [Expr::Symbol(op), ..] if op == "quote" => false,
+            [Expr::Symbol(op), Expr::Symbol(var), ..]
+                if op == "set!" && vars.contains(&var.as_str()) =>
+            {
+                true
+            }
+            exprs => exprs.iter().any(|expr| assigns(vars, expr)),
+        },
+        _ => false,
+    }
+}
+
+/// Collects the arguments of every call to `name` in `expr`.
+fn loop_calls<'e>(name: &str, expr: &'e Expr, calls: &mut Vec<&'e [Expr]>) {
+    if let Expr::List(exprs) = expr {
+        match exprs.as_slice() {
+            [Expr::Symbol(op), ..] if op == "quote" => {}
+            [Expr::Symbol(op), args @ ..] if op == name => {
+                calls.push(args);
+                args.iter().for_each(|arg| loop_calls(name, arg, calls));
+            }
+            _ => exprs.iter().for_each(|expr| loop_calls(name, expr, calls)),
+        }
+    }
+}
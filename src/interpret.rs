@@ -0,0 +1,412 @@
+//! A small interpreter over `Expr`, which runs the bodies of `defmacro`
+//! macros when their uses are expanded. Code is data here: a value is just
+//! an `Expr`, a list value is an `Expr::List`, and a macro's parameters are
+//! bound to the forms it was called with, unevaluated.
+//!
+//! It knows `quote`, quasiquote templates, `if`, `begin`, `let` and named
+//! `let`, internal `define`s, and the list, number and symbol functions a
+//! macro needs to take its arguments apart and build its expansion. Local
+//! functions can be called but are not values.
+
+use crate::Expr;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// How deeply local function calls may nest before macro code is taken to
+/// recurse forever.
+const MAX_DEPTH: usize = 1000;
+
+/// A local function, from an internal `define` or a named `let`.
+#[derive(Debug)]
+struct Function {
+    name: String,
+    params: Vec<String>,
+    body: Vec<Expr>,
+    /// The variables in scope where it was defined.
+    env: Env,
+}
+
+#[derive(Clone, Debug)]
+enum Local {
+    Value(Expr),
+    Function(Rc<Function>),
+}
+
+type Env = HashMap<String, Local>;
+
+struct Interpreter<'c> {
+    /// Numbers the symbols `gensym` makes.
+    counter: &'c mut usize,
+    depth: usize,
+}
+
+/// Runs `body` with each parameter bound to its form, and returns the value
+/// of its last expression. `counter` numbers the symbols `gensym` makes, so
+/// that they differ from every other generated symbol.
+pub fn run(
+    bindings: Vec<(String, Expr)>,
+    body: &[Expr],
+    counter: &mut usize,
+) -> Result<Expr, &'static str> {
+    let env = bindings
+        .into_iter()
+        .map(|(name, form)| (name, Local::Value(form)))
+        .collect();
+    Interpreter { counter, depth: 0 }.eval_body(body, &env)
+}
+
+impl Interpreter<'_> {
+    /// Evaluates a body, whose leading `define`s add to its scope.
+    fn eval_body(&mut self, body: &[Expr], env: &Env) -> Result<Expr, &'static str> {
+        let mut env = env.clone();
+        let mut value = None;
+        for expr in body {
+            value = None;
+            match definition(expr)? {
+                Some((name, Some(params), body)) => {
+                    let function = Function {
+                        name: name.to_string(),
+                        params,
+                        body: body.to_vec(),
+                        env: env.clone(),
+                    };
+                    env.insert(name.to_string(), Local::Function(Rc::new(function)));
+                }
+                Some((name, None, value)) => {
+                    let value = self.eval(&value[0], &env)?;
+                    env.insert(name.to_string(), Local::Value(value));
+                }
+                None => value = Some(self.eval(expr, &env)?),
+            }
+        }
+        value.ok_or("a macro body needs an expression after its definitions.")
+    }
+
+    fn eval(&mut self, expr: &Expr, env: &Env) -> Result<Expr, &'static str> {
+        let exprs = match expr {
+            Expr::Symbol(name) => {
+                return match env.get(name) {
+                    Some(Local::Value(value)) => Ok(value.clone()),
+                    Some(Local::Function(_)) => Err("a function in macro code can only be called."),
+                    None => Err("unbound variable in macro code."),
+                }
+            }
+            Expr::List(exprs) => exprs,
+            _ => return Ok(expr.clone()),
+        };
+        let (op, args) = match exprs.split_first() {
+            Some((Expr::Symbol(op), args)) => (op.as_str(), args),
+            _ => return Err("macro code can only call functions by name."),
+        };
+        match (op, args) {
+            ("quote", [datum]) => Ok(datum.clone()),
+            ("quasiquote", [template]) => self.quasiquote(template, env, 1),
+            ("if", [cond, then, rest @ ..]) if rest.len() <= 1 => {
+                if self.eval(cond, env)? != Expr::Bool(false) {
+                    self.eval(then, env)
+                } else if let [otherwise] = rest {
+                    self.eval(otherwise, env)
+                } else {
+                    Ok(Expr::List(vec![]))
+                }
+            }
+            ("begin", body) => self.eval_body(body, env),
+            ("let", [Expr::Symbol(name), Expr::List(bindings), body @ ..]) => {
+                let (params, values) = self.eval_bindings(bindings, env)?;
+                let function = Rc::new(Function {
+                    name: name.clone(),
+                    params,
+                    body: body.to_vec(),
+                    env: env.clone(),
+                });
+                self.call(&function, values)
+            }
+            ("let", [Expr::List(bindings), body @ ..]) => {
+                let (vars, values) = self.eval_bindings(bindings, env)?;
+                let mut scope = env.clone();
+                for (var, value) in vars.into_iter().zip(values) {
+                    scope.insert(var, Local::Value(value));
+                }
+                self.eval_body(body, &scope)
+            }
+            ("quote" | "quasiquote" | "if" | "let", _) => {
+                Err("malformed special form in macro code.")
+            }
+            _ => {
+                let mut values = vec![];
+                for arg in args {
+                    values.push(self.eval(arg, env)?);
+                }
+                match env.get(op) {
+                    Some(Local::Function(function)) => {
+                        let function = Rc::clone(function);
+                        self.call(&function, values)
+                    }
+                    Some(Local::Value(_)) => {
+                        Err("macro code called something that is not a function.")
+                    }
+                    None => self.builtin(op, values),
+                }
+            }
+        }
+    }
+
+    /// The variables of `let` bindings and their values.
+    fn eval_bindings(
+        &mut self,
+        bindings: &[Expr],
+        env: &Env,
+    ) -> Result<(Vec<String>, Vec<Expr>), &'static str> {
+        let mut vars = vec![];
+        let mut values = vec![];
+        for binding in bindings {
+            match binding {
+                Expr::List(parts) => match parts.as_slice() {
+                    [Expr::Symbol(var), init] => {
+                        vars.push(var.clone());
+                        values.push(self.eval(init, env)?);
+                    }
+                    _ => return Err("a let binding is written (name value)."),
+                },
+                _ => return Err("a let binding is written (name value)."),
+            }
+        }
+        Ok((vars, values))
+    }
+
+    fn call(&mut self, function: &Rc<Function>, args: Vec<Expr>) -> Result<Expr, &'static str> {
+        if args.len() != function.params.len() {
+            return Err("wrong number of arguments");
+        }
+        if self.depth == MAX_DEPTH {
+            return Err("macro code recursed too deeply.");
+        }
+        let mut env = function.env.clone();
+        // so that it can call itself
+        env.insert(function.name.clone(), Local::Function(Rc::clone(function)));
+        for (param, arg) in function.params.iter().zip(args) {
+            env.insert(param.clone(), Local::Value(arg));
+        }
+        self.depth += 1;
+        let value = self.eval_body(&function.body, &env);
+        self.depth -= 1;
+        value
+    }
+
+    /// Fills in a quasiquote template `level` quasiquotes deep, evaluating
+    /// what is unquoted at the outermost one.
+    fn quasiquote(
+        &mut self,
+        template: &Expr,
+        env: &Env,
+        level: usize,
+    ) -> Result<Expr, &'static str> {
+        let items = match template {
+            Expr::List(items) => items,
+            Expr::Vector(items) => {
+                return self.quasiquote_items(items, env, level).map(Expr::Vector)
+            }
+            _ => return Ok(template.clone()),
+        };
+        match items.as_slice() {
+            [Expr::Symbol(op), expr] if op == "unquote" => {
+                if level == 1 {
+                    self.eval(expr, env)
+                } else {
+                    Ok(Expr::List(vec![
+                        items[0].clone(),
+                        self.quasiquote(expr, env, level - 1)?,
+                    ]))
+                }
+            }
+            [Expr::Symbol(op), expr] if op == "quasiquote" => Ok(Expr::List(vec![
+                items[0].clone(),
+                self.quasiquote(expr, env, level + 1)?,
+            ])),
+            _ => self.quasiquote_items(items, env, level).map(Expr::List),
+        }
+    }
+
+    fn quasiquote_items(
+        &mut self,
+        items: &[Expr],
+        env: &Env,
+        level: usize,
+    ) -> Result<Vec<Expr>, &'static str> {
+        let mut filled = vec![];
+        for item in items {
+            match item {
+                Expr::List(parts) if level == 1 => match parts.as_slice() {
+                    [Expr::Symbol(op), expr] if op == "unquote-splicing" => {
+                        match self.eval(expr, env)? {
+                            Expr::List(spliced) => filled.extend(spliced),
+                            _ => return Err("unquote-splicing needs a list."),
+                        }
+                    }
+                    _ => filled.push(self.quasiquote(item, env, level)?),
+                },
+                _ => filled.push(self.quasiquote(item, env, level)?),
+            }
+        }
+        Ok(filled)
+    }
+
+    fn builtin(&mut self, op: &str, args: Vec<Expr>) -> Result<Expr, &'static str> {
+        match (op, args.as_slice()) {
+            ("+" | "-" | "*" | "/", _) => arithmetic(op, &args),
+            ("=" | "<" | ">" | "<=" | ">=", [_, _, ..]) => {
+                let numbers = args.iter().map(number).collect::<Result<Vec<_>, _>>()?;
+                Ok(Expr::Bool(numbers.windows(2).all(|pair| match op {
+                    "=" => pair[0] == pair[1],
+                    "<" => pair[0] < pair[1],
+                    ">" => pair[0] > pair[1],
+                    "<=" => pair[0] <= pair[1],
+                    _ => pair[0] >= pair[1],
+                })))
+            }
+            ("quotient" | "remainder", [Expr::Integer(a), Expr::Integer(b)]) => match op {
+                _ if *b == 0 => Err("division by zero"),
+                "quotient" => Ok(Expr::Integer(a.wrapping_div(*b))),
+                _ => Ok(Expr::Integer(a.wrapping_rem(*b))),
+            },
+            ("not", [value]) => Ok(Expr::Bool(*value == Expr::Bool(false))),
+            ("eq?" | "equal?", [a, b]) => Ok(Expr::Bool(a == b)),
+            ("null?", [value]) => Ok(Expr::Bool(*value == Expr::List(vec![]))),
+            ("pair?", [value]) => Ok(Expr::Bool(
+                matches!(value, Expr::List(items) if !items.is_empty()),
+            )),
+            ("list?", [value]) => Ok(Expr::Bool(matches!(value, Expr::List(_)))),
+            ("symbol?", [value]) => Ok(Expr::Bool(matches!(value, Expr::Symbol(_)))),
+            ("string?", [value]) => Ok(Expr::Bool(matches!(value, Expr::Str(_)))),
+            ("number?", [value]) => Ok(Expr::Bool(number(value).is_ok())),
+            ("integer?", [value]) => Ok(Expr::Bool(matches!(value, Expr::Integer(_)))),
+            ("cons", [head, Expr::List(tail)]) => {
+                let mut list = vec![head.clone()];
+                list.extend(tail.iter().cloned());
+                Ok(Expr::List(list))
+            }
+            ("cons", [_, _]) => Err("macro code can only cons onto a list."),
+            ("car", [Expr::List(items)]) if !items.is_empty() => Ok(items[0].clone()),
+            ("cdr", [Expr::List(items)]) if !items.is_empty() => {
+                Ok(Expr::List(items[1..].to_vec()))
+            }
+            ("car" | "cdr", [_]) => Err("car and cdr need a non-empty list."),
+            ("list", _) => Ok(Expr::List(args)),
+            ("length", [Expr::List(items)]) => Ok(Expr::Integer(items.len() as i64)),
+            ("reverse", [Expr::List(items)]) => {
+                Ok(Expr::List(items.iter().rev().cloned().collect()))
+            }
+            ("append", _) => {
+                let mut list = vec![];
+                for arg in args {
+                    match arg {
+                        Expr::List(items) => list.extend(items),
+                        _ => return Err("append needs lists."),
+                    }
+                }
+                Ok(Expr::List(list))
+            }
+            ("symbol->string", [Expr::Symbol(name)]) => Ok(Expr::Str(name.clone())),
+            ("string->symbol", [Expr::Str(name)]) => Ok(Expr::Symbol(name.clone())),
+            ("number->string", [value]) if number(value).is_ok() => {
+                Ok(Expr::Str(value.to_string()))
+            }
+            ("string-append", _) => {
+                let mut joined = String::new();
+                for arg in &args {
+                    match arg {
+                        Expr::Str(text) => joined.push_str(text),
+                        _ => return Err("string-append needs strings."),
+                    }
+                }
+                Ok(Expr::Str(joined))
+            }
+            // a symbol no program can write, so nothing can capture it
+            ("gensym", []) => {
+                *self.counter += 1;
+                Ok(Expr::Symbol(format!("g~{}", self.counter)))
+            }
+            _ => Err("unknown function, or wrong arguments to one, in macro code."),
+        }
+    }
+}
+
+/// `(define (name params...) body...)` as its name, parameters and body, or
+/// `(define name value)` as its name and the value.
+#[allow(clippy::type_complexity)]
+fn definition(expr: &Expr) -> Result<Option<(&str, Option<Vec<String>>, &[Expr])>, &'static str> {
+    let exprs = match expr {
+        Expr::List(exprs) => exprs,
+        _ => return Ok(None),
+    };
+    match exprs.as_slice() {
+        [Expr::Symbol(op), Expr::List(sig), body @ ..] if op == "define" && !body.is_empty() => {
+            let names = sig
+                .iter()
+                .map(|part| match part {
+                    Expr::Symbol(name) => Ok(name.as_str()),
+                    _ => Err("a function in macro code takes plain parameters."),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            match names.split_first() {
+                Some((name, params)) => {
+                    let params = params.iter().map(|param| param.to_string()).collect();
+                    Ok(Some((name, Some(params), body)))
+                }
+                None => Err("a function definition needs a name."),
+            }
+        }
+        [Expr::Symbol(op), Expr::Symbol(name), value] if op == "define" => {
+            Ok(Some((name, None, std::slice::from_ref(value))))
+        }
+        [Expr::Symbol(op), ..] if op == "define" => Err("malformed define in macro code."),
+        _ => Ok(None),
+    }
+}
+
+/// A number in macro code, as a float for comparing.
+fn number(expr: &Expr) -> Result<f64, &'static str> {
+    match expr {
+        Expr::Integer(n) => Ok(*n as f64),
+        Expr::Float(x) => Ok(*x),
+        _ => Err("macro arithmetic only works on integers and floats."),
+    }
+}
+
+/// `+`, `-`, `*` or `/` of integers and floats: exact while every argument is
+/// an integer, except for a division that does not come out whole.
+fn arithmetic(op: &str, args: &[Expr]) -> Result<Expr, &'static str> {
+    let (mut acc, rest) = match (op, args) {
+        ("+", []) => return Ok(Expr::Integer(0)),
+        ("*", []) => return Ok(Expr::Integer(1)),
+        (_, []) => return Err("wrong number of arguments"),
+        // (- x) is (- 0 x) and (/ x) is (/ 1 x)
+        ("-", [_]) => (Expr::Integer(0), args),
+        ("/", [_]) => (Expr::Integer(1), args),
+        (_, [first, rest @ ..]) => (first.clone(), rest),
+    };
+    number(&acc)?;
+    for arg in rest {
+        number(arg)?;
+        acc = match (&acc, arg) {
+            (Expr::Integer(a), Expr::Integer(b)) => match op {
+                "+" => Expr::Integer(a.checked_add(*b).ok_or("integer overflow")?),
+                "-" => Expr::Integer(a.checked_sub(*b).ok_or("integer overflow")?),
+                "*" => Expr::Integer(a.checked_mul(*b).ok_or("integer overflow")?),
+                _ if *b == 0 => return Err("division by zero"),
+                _ if a % b == 0 => Expr::Integer(a / b),
+                _ => Expr::Float(*a as f64 / *b as f64),
+            },
+            _ => {
+                let (a, b) = (number(&acc)?, number(arg)?);
+                Expr::Float(match op {
+                    "+" => a + b,
+                    "-" => a - b,
+                    "*" => a * b,
+                    _ => a / b,
+                })
+            }
+        };
+    }
+    Ok(acc)
+}
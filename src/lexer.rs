@@ -0,0 +1,123 @@
+use crate::completion::SPECIAL_FORMS;
+use crate::{read, Expr};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenKind {
+    LParen,
+    RParen,
+    /// The `'` abbreviating `(quote ...)`, or the `` ` ``, `,` or `,@` of a
+    /// quasiquote template.
+    Quote,
+    Number,
+    /// `#t` or `#f`.
+    Boolean,
+    /// A character literal such as `#\a`.
+    Char,
+    Symbol,
+    /// A symbol naming one of `SPECIAL_FORMS`.
+    SpecialForm,
+    String,
+    /// From `;` to the end of the line.
+    Comment,
+    Whitespace,
+}
+
+/// A token covering `input[start..end]`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub start: usize,
+    pub end: usize,
+}
+
+fn is_atom_char(c: char) -> bool {
+    !(c.is_whitespace() || matches!(c, '(' | ')' | '\'' | '`' | ',' | '"' | ';'))
+}
+
+/// Classifies an atom with the reader itself, so the lexer never disagrees
+/// with `read` about what is a number.
+fn atom_kind(atom: &str) -> TokenKind {
+    match read(atom) {
+        Ok(Expr::Integer(_) | Expr::Float(_) | Expr::Rational(..)) => TokenKind::Number,
+        Ok(Expr::Bool(_)) => TokenKind::Boolean,
+        Ok(Expr::Char(_)) => TokenKind::Char,
+        _ if SPECIAL_FORMS.contains(&atom) => TokenKind::SpecialForm,
+        _ => TokenKind::Symbol,
+    }
+}
+
+/// Splits `input` into tokens. Unlike `read` this never fails: every byte of
+/// the input belongs to exactly one token, and unterminated strings simply run
+/// to the end of the input. That makes it suitable for half-typed lines.
+pub fn tokenize(input: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut chars = input.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        let mut end = start + c.len_utf8();
+        let kind = match c {
+            '(' => TokenKind::LParen,
+            ')' => TokenKind::RParen,
+            '\'' | '`' => TokenKind::Quote,
+            ',' => {
+                if let Some(&(i, '@')) = chars.peek() {
+                    end = i + 1;
+                    chars.next();
+                }
+                TokenKind::Quote
+            }
+            '"' => {
+                let mut escaped = false;
+                for (i, c) in chars.by_ref() {
+                    end = i + c.len_utf8();
+                    match c {
+                        '\\' if !escaped => escaped = true,
+                        '"' if !escaped => break,
+                        _ => escaped = false,
+                    }
+                }
+                TokenKind::String
+            }
+            ';' => {
+                while let Some(&(i, c)) = chars.peek() {
+                    if c == '\n' {
+                        break;
+                    }
+                    end = i + c.len_utf8();
+                    chars.next();
+                }
+                TokenKind::Comment
+            }
+            c if c.is_whitespace() => {
+                while let Some(&(i, c)) = chars.peek() {
+                    if !c.is_whitespace() {
+                        break;
+                    }
+                    end = i + c.len_utf8();
+                    chars.next();
+                }
+                TokenKind::Whitespace
+            }
+            _ => {
+                // the character after `#\` belongs to the atom, even `(`
+                if c == '#' && input[end..].starts_with('\\') {
+                    chars.next();
+                    if let Some((i, c)) = chars.next() {
+                        end = i + c.len_utf8();
+                    }
+                }
+                while let Some(&(i, c)) = chars.peek() {
+                    if !is_atom_char(c) {
+                        break;
+                    }
+                    end = i + c.len_utf8();
+                    chars.next();
+                }
+                atom_kind(&input[start..end])
+            }
+        };
+        tokens.push(Token { kind, start, end });
+    }
+
+    tokens
+}
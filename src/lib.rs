@@ -1,99 +1,781 @@
+use infer::Inference;
 #[warn(unused_imports)]
 use inkwell::{
+    attributes::{Attribute, AttributeLoc},
+    basic_block::BasicBlock,
     builder::Builder,
     context::Context,
+    intrinsics::Intrinsic,
     module::Module,
     passes::PassManager,
-    types::BasicMetadataTypeEnum,
-    values::{BasicMetadataValueEnum, FloatValue, FunctionValue, PointerValue},
+    types::{BasicMetadataTypeEnum, BasicType, BasicTypeEnum, StructType},
+    values::{
+        BasicMetadataValueEnum, BasicValueEnum, CallableValue, FloatValue, FunctionValue, IntValue,
+        PhiValue, PointerValue, StructValue,
+    },
+    AddressSpace, FloatPredicate, IntPredicate,
 };
 use peg::parser;
-use std::{
-    collections::HashMap,
-    fmt,
-    num::{ParseFloatError, ParseIntError},
-};
+use printer::c_name;
+use runtime::Tag;
+use std::{collections::HashMap, fmt, num::IntErrorKind};
+
+pub mod completion;
+pub mod constants;
+pub mod expand;
+pub mod gc;
+pub mod highlight;
+pub mod infer;
+pub mod interpret;
+pub mod lexer;
+pub mod lift;
+pub mod modules;
+pub mod printer;
+pub mod runtime;
+pub mod session;
+pub mod value;
+
+pub use session::{eval, Outcome, Session};
+pub use value::Value;
 
 parser! {
     grammar lisp_parser() for str {
+        pub rule program() -> Vec<Expr>
+            = _ e:(expr() ** _) _ { e }
+
         pub rule expr() -> Expr
             = _ e:(
-                number()
+                string()
+                / vector()
+                / character()
+                / boolean()
+                / special_float()
+                / radix_number()
+                / rational()
+                / number()
                 / symbol()
+                / quoted()
                 / list()) _ { e }
 
+        // neither a number nor a rational is followed by `/`, so `1/0` that
+        // fails to read is an error rather than `1` and the symbol `/0`
+        rule rational() -> Expr
+            = n:$(['+' | '-']? ['0'..='9']+ "/" ['0'..='9']+) !"/"
+                {? parse_rational(n) }
+
         rule number() -> Expr
-            = n:$(['-']?['0'..='9']+ ("." ['0'..='9']*)?)
-                { parse_number(n).unwrap() }
+            = n:$(['+' | '-']? (['0'..='9']+ ("." ['0'..='9']*)? / "." ['0'..='9']+)
+                    (['e' | 'E'] ['+' | '-']? ['0'..='9']+)?) !"/"
+                {? parse_number(n) }
+
+        rule radix_number() -> Expr
+            = "#" r:$(['x' | 'X' | 'o' | 'O' | 'b' | 'B' | 'd' | 'D'])
+                n:$(['+' | '-']? ['0'..='9' | 'a'..='f' | 'A'..='F']+)
+                {? parse_radix(r, n) }
+
+        rule string() -> Expr
+            = "\"" s:string_char()* "\"" { Expr::Str(s.into_iter().collect()) }
+
+        rule string_char() -> char
+            = "\\n" { '\n' }
+            / "\\t" { '\t' }
+            / "\\" c:['"' | '\\'] { c }
+            / [^ '"' | '\\']
 
+        rule vector() -> Expr
+            = "#(" e:(expr() ** (_)) _ ")" { Expr::Vector(e) }
+
+        rule character() -> Expr
+            = "#\\" name:$(['a'..='z']['a'..='z']+) {? char_named(name).map(Expr::Char) }
+            / "#\\" c:[_] { Expr::Char(c) }
+
+        rule boolean() -> Expr
+            = ("#true" / "#t") { Expr::Bool(true) }
+            / ("#false" / "#f") { Expr::Bool(false) }
+
+        rule special_float() -> Expr
+            = "+inf.0" { Expr::Float(f64::INFINITY) }
+            / "-inf.0" { Expr::Float(f64::NEG_INFINITY) }
+            / ['+' | '-'] "nan.0" { Expr::Float(f64::NAN) }
+
+        // `#` only starts literals like `#x1F`, so a malformed one is an
+        // error rather than a symbol; likewise a sign before digits only
+        // starts a number, so `-1e400` does not read as a symbol
         rule symbol() -> Expr
-            = s:$(['a'..='z' | 'A'..='Z' | '-' | '_' | '+' | '*' | '/' | '?' | '!' | '@' | '#' | '$' | '%' | '&' | '|' | '<' | '>' | '=' | ':' | '"']
+            = "..." { Expr::Symbol("...".into()) }
+            / !(['+' | '-'] "."? ['0'..='9']) s:$(['a'..='z' | 'A'..='Z' | '-' | '_' | '+' | '*' | '/' | '?' | '!' | '@' | '$' | '%' | '&' | '|' | '<' | '>' | '=' | ':']
                     ['a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '+' | '*' | '/' | '?' | '!' | '@' | '#' | '$' | '%' | '&' | '|' | '<' | '>' | '=' | ':' | '.' ]*  )
                 { Expr::Symbol(s.into()) }
 
+        rule quoted() -> Expr
+            = "'" e:expr() { Expr::List(vec![Expr::Symbol("quote".into()), e]) }
+            / "`" e:expr() { Expr::List(vec![Expr::Symbol("quasiquote".into()), e]) }
+            / ",@" e:expr() { Expr::List(vec![Expr::Symbol("unquote-splicing".into()), e]) }
+            / "," e:expr() { Expr::List(vec![Expr::Symbol("unquote".into()), e]) }
+
         rule list() -> Expr
             = "(" e:(expr() ** (_)) _ ")" { Expr::List(e) }
 
-        rule _() = [' '|'\t'|'\r'|'\n']*
+        rule _() = ([' '|'\t'|'\r'|'\n'] / comment())*
+
+        rule comment() = ";" [^'\n']*
     }
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Expr {
     Symbol(String),
+    Bool(bool),
+    Char(char),
+    Str(String),
     Integer(i64),
     Float(f64),
+    /// An exact fraction in lowest terms with a denominator above 1; `6/3`
+    /// reads as `Integer(2)`.
+    Rational(i64, i64),
     List(Vec<Expr>),
+    /// `#(1 2 3)`, a vector of floats.
+    Vector(Vec<Expr>),
 }
 
-fn parse_number(num_str: &str) -> Result<Expr, NumberParseError> {
-    num_str
-        .parse::<i64>()
+fn integer_error(err: std::num::ParseIntError) -> &'static str {
+    match err.kind() {
+        IntErrorKind::PosOverflow | IntErrorKind::NegOverflow => "integer literal out of range",
+        _ => "invalid digit in number literal",
+    }
+}
+
+/// Reads a decimal literal: an integer unless it has a fraction or exponent.
+fn parse_number(num_str: &str) -> Result<Expr, &'static str> {
+    if !num_str.contains(['.', 'e', 'E']) {
+        return num_str
+            .parse::<i64>()
+            .map(Expr::Integer)
+            .map_err(integer_error);
+    }
+    match num_str.parse::<f64>() {
+        Ok(x) if x.is_finite() => Ok(Expr::Float(x)),
+        _ => Err("float literal out of range"),
+    }
+}
+
+/// Reads the digits of `#x1F`, `#o17`, `#b1010` or `#d42` as an integer.
+fn parse_radix(radix: &str, digits: &str) -> Result<Expr, &'static str> {
+    let radix = match radix {
+        "x" | "X" => 16,
+        "o" | "O" => 8,
+        "b" | "B" => 2,
+        _ => 10,
+    };
+    i64::from_str_radix(digits, radix)
         .map(Expr::Integer)
-        .map_err(NumberParseError::from)
-        .or_else(|_| {
-            num_str
-                .parse::<f64>()
-                .map(Expr::Float)
-                .map_err(NumberParseError::from)
-        })
+        .map_err(integer_error)
+}
+
+fn char_named(name: &str) -> Result<char, &'static str> {
+    value::CHAR_NAMES
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, c)| *c)
+        .ok_or("unknown character name")
+}
+
+/// Reads `num/den`, normalised to lowest terms.
+fn parse_rational(rat_str: &str) -> Result<Expr, &'static str> {
+    let (num, den) = rat_str.split_once('/').unwrap();
+    let num = num
+        .parse::<i64>()
+        .map_err(|_| "rational numerator out of range")?;
+    let den = den
+        .parse::<i64>()
+        .map_err(|_| "rational denominator out of range")?;
+    if den == 0 {
+        return Err("rational with a zero denominator");
+    }
+    let ratio = runtime::Ratio::checked(num as i128, den as i128).ok_or("rational out of range")?;
+    if ratio.den == 1 {
+        Ok(Expr::Integer(ratio.num))
+    } else {
+        Ok(Expr::Rational(ratio.num, ratio.den))
+    }
 }
 
 pub fn read(input: &str) -> Result<Expr, String> {
     lisp_parser::expr(input).map_err(|e| e.to_string())
 }
 
+/// Reads every top-level form in `input`, e.g. the contents of a `.lisp` file.
+pub fn read_all(input: &str) -> Result<Vec<Expr>, String> {
+    lisp_parser::program(input).map_err(|e| e.to_string())
+}
+
+/// Writes the expression back out as Lisp source that `read` accepts.
 impl fmt::Display for Expr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            Expr::Symbol(s) => write!(f, "{}", s),
+            Expr::Bool(true) => write!(f, "#t"),
+            Expr::Bool(false) => write!(f, "#f"),
+            Expr::Char(c) => write!(f, "{}", value::format_char(*c)),
+            Expr::Str(text) => write!(f, "{}", value::format_string(text)),
+            Expr::Integer(n) => write!(f, "{}", n),
+            // `{:?}` keeps the `.0` so floats read back as floats
+            Expr::Float(n) if n.is_finite() => write!(f, "{:?}", n),
+            Expr::Float(n) => write!(f, "{}", printer::format_float(*n)),
+            Expr::Rational(num, den) => write!(f, "{}/{}", num, den),
+            Expr::List(exprs) | Expr::Vector(exprs) => {
+                if let Expr::Vector(_) = self {
+                    write!(f, "#")?;
+                }
+                write!(f, "(")?;
+                for (i, e) in exprs.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", e)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+/// The one-argument predicates, see `Compiler::compile_predicate`.
+pub const PREDICATES: &[&str] = &[
+    "zero?",
+    "positive?",
+    "negative?",
+    "even?",
+    "odd?",
+    "null?",
+    "pair?",
+    "boolean?",
+    "number?",
+    "char?",
+    "procedure?",
+    "vector?",
+    "string?",
+    "symbol?",
+];
+
+fn extract_op_and_args<'a>(exprs: &'a [Expr]) -> Result<(&'a str, &'a [Expr]), &'static str> {
+    match exprs.split_first() {
+        Some((Expr::Symbol(op), args)) => Ok((op.as_str(), args)),
+        _ => Err("expected operator"),
     }
 }
 
-#[derive(Debug)]
-enum NumberParseError {
-    Int(ParseIntError),
-    Float(ParseFloatError),
+/// The parts of `(define (name params...) body...)` or
+/// `(define (name params...) : type body...)`: the name, the parameters (each
+/// a symbol or `(x : type)`), the return type annotation if any, and the body,
+/// one or more expressions evaluated in order.
+type DefinitionForm<'e> = (&'e str, &'e [Expr], Option<&'e Expr>, &'e [Expr]);
+
+fn definition_form(expr: &Expr) -> Result<Option<DefinitionForm<'_>>, &'static str> {
+    let exprs = match expr {
+        Expr::List(exprs) => exprs,
+        _ => return Ok(None),
+    };
+    let (sig, ret, body) = match exprs.as_slice() {
+        [Expr::Symbol(op), Expr::List(sig), rest @ ..] if op == "define" => match rest {
+            [Expr::Symbol(colon), ret, body @ ..] if colon == ":" && !body.is_empty() => {
+                (sig, Some(ret), body)
+            }
+            [_, ..] => (sig, None, rest),
+            [] => return Err(
+                "define requires a variable name or function definition and a value or expression.",
+            ),
+        },
+        _ => return Ok(None),
+    };
+    let (name, params) = extract_op_and_args(sig)
+        .map_err(|_| "Function definition should start with a symbol for its name.")?;
+    Ok(Some((name, params, ret, body)))
+}
+
+/// Splits a parameter into its name and type annotation, if it has one.
+fn parameter_parts(param: &Expr) -> Result<(&str, Option<&Expr>), &'static str> {
+    match param {
+        Expr::Symbol(name) => Ok((name, None)),
+        Expr::List(parts) => match parts.as_slice() {
+            [Expr::Symbol(name), Expr::Symbol(colon), ty] if colon == ":" => Ok((name, Some(ty))),
+            _ => Err("in define: an annotated parameter is written (name : type)"),
+        },
+        _ => Err("in define: all the elements in the argument list must be symbols"),
+    }
 }
 
-impl From<ParseIntError> for NumberParseError {
-    fn from(err: ParseIntError) -> Self {
-        NumberParseError::Int(err)
+/// Splits the bindings of a `let`, `((var init) ...)`, into the variables and
+/// their initial values.
+fn let_bindings(bindings: &[Expr]) -> Result<(Vec<&str>, Vec<&Expr>), &'static str> {
+    let mut vars = vec![];
+    let mut inits = vec![];
+    for binding in bindings {
+        match binding {
+            Expr::List(parts) => match parts.as_slice() {
+                [Expr::Symbol(var), init] => {
+                    vars.push(var.as_str());
+                    inits.push(init);
+                }
+                _ => return Err("a let binding is written (name value)."),
+            },
+            _ => return Err("a let binding is written (name value)."),
+        }
     }
+    Ok((vars, inits))
 }
 
-impl From<ParseFloatError> for NumberParseError {
-    fn from(err: ParseFloatError) -> Self {
-        NumberParseError::Float(err)
+/// A `do` loop variable: its name, initial value and step, if it has one.
+type DoVariable<'e> = (&'e str, &'e Expr, Option<&'e Expr>);
+
+/// The parts of `(do ((var init step) ...) (test result...) body...)`: the
+/// variables, the test, the result expressions and the body.
+type DoForm<'e> = (Vec<DoVariable<'e>>, &'e Expr, &'e [Expr], &'e [Expr]);
+
+fn do_parts(args: &[Expr]) -> Result<DoForm<'_>, &'static str> {
+    let (specs, test, results, body) = match args {
+        [Expr::List(specs), Expr::List(exit), body @ ..] => match exit.split_first() {
+            Some((test, results)) => (specs, test, results, body),
+            None => return Err("a do loop needs a test."),
+        },
+        _ => return Err("do requires a list of variables, a test clause and a body."),
+    };
+    let mut vars = vec![];
+    for spec in specs {
+        match spec {
+            Expr::List(parts) => match parts.as_slice() {
+                [Expr::Symbol(var), init] => vars.push((var.as_str(), init, None)),
+                [Expr::Symbol(var), init, step] => vars.push((var.as_str(), init, Some(step))),
+                _ => return Err("a do variable is written (name init step)."),
+            },
+            _ => return Err("a do variable is written (name init step)."),
+        }
     }
+    Ok((vars, test, results, body))
 }
 
-fn extract_op_and_args<'a>(exprs: &'a [Expr]) -> Result<(&'a str, &'a [Expr]), &'static str> {
-    match exprs.split_first() {
-        Some((Expr::Symbol(op), args)) => Ok((op.as_str(), args)),
-        _ => Err("expected operator"),
+/// Every expression in the scope of a `do` loop's variables, given its
+/// parts as `do_parts` returns them and its steps.
+fn do_scope<'e>(
+    test: &'e Expr,
+    body: &'e [Expr],
+    results: &'e [Expr],
+    steps: &[(usize, &'e Expr)],
+) -> Vec<&'e Expr> {
+    let mut scope = vec![test];
+    scope.extend(body);
+    scope.extend(results);
+    scope.extend(steps.iter().map(|(_, step)| *step));
+    scope
+}
+
+/// Splits `(define (name params...) body...)` into its name, parameter names
+/// and body. Returns `None` for anything that is not a function definition.
+pub(crate) fn function_parts(
+    expr: &Expr,
+) -> Result<Option<(&str, Vec<String>, &[Expr])>, &'static str> {
+    let (name, params, _, body) = match definition_form(expr)? {
+        Some(form) => form,
+        None => return Ok(None),
+    };
+    let mut arg_names: Vec<String> = vec![];
+    for param in params.iter() {
+        arg_names.push(parameter_parts(param)?.0.to_string());
+    }
+    Ok(Some((name, arg_names, body)))
+}
+
+/// The types a function definition's annotations declare, or `None` for
+/// anything that is not a function definition.
+pub(crate) fn definition_signature(expr: &Expr) -> Result<Option<(&str, Signature)>, &'static str> {
+    let (name, params, ret, _) = match definition_form(expr)? {
+        Some(form) => form,
+        None => return Ok(None),
+    };
+    let mut signature = Signature {
+        params: vec![],
+        ret: ret.map(parse_ty).transpose()?,
+    };
+    for param in params.iter() {
+        let (_, ty) = parameter_parts(param)?;
+        signature.params.push(ty.map(parse_ty).transpose()?);
+    }
+    Ok(Some((name, signature)))
+}
+
+/// Splits a declaration `(: name (-> param-types... return-type))` into the
+/// name and the signature it declares. Returns `None` for anything else.
+pub(crate) fn declaration_parts(expr: &Expr) -> Result<Option<(&str, Signature)>, &'static str> {
+    let exprs = match expr {
+        Expr::List(exprs) => exprs,
+        _ => return Ok(None),
+    };
+    let (name, ty) = match exprs.as_slice() {
+        [Expr::Symbol(op), rest @ ..] if op == ":" => match rest {
+            [Expr::Symbol(name), Expr::List(ty)] => (name, ty),
+            _ => return Err("a declaration is written (: name (-> param-types... return-type))"),
+        },
+        _ => return Ok(None),
+    };
+    let tys = match ty.split_first() {
+        Some((Expr::Symbol(arrow), tys)) if arrow == "->" && !tys.is_empty() => tys,
+        _ => return Err("a declaration is written (: name (-> param-types... return-type))"),
+    };
+    let mut tys = tys.iter().map(parse_ty).collect::<Result<Vec<Ty>, _>>()?;
+    let ret = tys.pop();
+    Ok(Some((
+        name,
+        Signature {
+            params: tys.into_iter().map(Some).collect(),
+            ret,
+        },
+    )))
+}
+
+fn parse_ty(expr: &Expr) -> Result<Ty, &'static str> {
+    match expr {
+        Expr::Symbol(name) => Ty::from_name(name),
+        _ => None,
+    }
+    .ok_or("unknown type: expected f64, i64, rational, bool, vector (or f64*) or dynamic")
+}
+
+/// The machine type of a compiled value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Ty {
+    F64,
+    I64,
+    /// `{ i64, i64 }`, see `runtime::Ratio`.
+    Rational,
+    /// `i1`
+    Bool,
+    /// `%vector = { double*, i64 }`, see `runtime::Vector`.
+    Vector,
+    /// `%dynamic = { i64, i64 }`, see `runtime::Dynamic`: anything whose
+    /// type is only known at run time.
+    Dynamic,
+}
+
+impl Ty {
+    /// The type written `name` in annotations, as `Display` writes it.
+    pub fn from_name(name: &str) -> Option<Ty> {
+        // a pointer to floats, as C would see the elements
+        if name == "f64*" {
+            return Some(Ty::Vector);
+        }
+        [
+            Ty::F64,
+            Ty::I64,
+            Ty::Rational,
+            Ty::Bool,
+            Ty::Vector,
+            Ty::Dynamic,
+        ]
+        .into_iter()
+        .find(|ty| ty.to_string() == name)
+    }
+
+    /// The type of a value that is either a `self` or an `other`, and of
+    /// arithmetic mixing them: any float promotes the result to f64,
+    /// otherwise it stays exact, and integers only stay integers among
+    /// themselves. Numbers only meet booleans or vectors in a dynamic value.
+    pub fn join(self, other: Ty) -> Ty {
+        match (self, other) {
+            _ if self == other => self,
+            (Ty::Dynamic | Ty::Bool | Ty::Vector, _) | (_, Ty::Dynamic | Ty::Bool | Ty::Vector) => {
+                Ty::Dynamic
+            }
+            (Ty::F64, _) | (_, Ty::F64) => Ty::F64,
+            _ => Ty::Rational,
+        }
+    }
+
+    pub fn is_exact(self) -> bool {
+        matches!(self, Ty::I64 | Ty::Rational)
+    }
+
+    /// Whether a value of type `from` can be passed where a `self` is
+    /// declared: numbers only widen, and dynamic values are checked when the
+    /// call runs.
+    pub fn accepts(self, from: Ty) -> bool {
+        from == Ty::Dynamic || from.join(self) == self
+    }
+
+    /// Whether a variable of type `self` can hold any value of type `from`
+    /// as it is, without checking it at run time.
+    pub fn holds(self, from: Ty) -> bool {
+        from.join(self) == self
+    }
+
+    pub fn is_number(self) -> bool {
+        !matches!(self, Ty::Bool | Ty::Vector)
+    }
+}
+
+impl fmt::Display for Ty {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Ty::F64 => write!(f, "f64"),
+            Ty::I64 => write!(f, "i64"),
+            Ty::Rational => write!(f, "rational"),
+            Ty::Bool => write!(f, "bool"),
+            Ty::Vector => write!(f, "vector"),
+            Ty::Dynamic => write!(f, "dynamic"),
+        }
+    }
+}
+
+/// Joins the types of several operands, or `None` if any of them is still
+/// unknown (the result of a recursive call being inferred). A known dynamic
+/// operand settles the result regardless, and so does a float unless a
+/// dynamic one turns up.
+fn join_types(tys: impl IntoIterator<Item = Option<Ty>>) -> Option<Ty> {
+    let mut joined = Some(Ty::I64);
+    let mut float = false;
+    for ty in tys {
+        match ty {
+            Some(Ty::Dynamic) => return Some(Ty::Dynamic),
+            Some(Ty::F64) => float = true,
+            Some(ty) => joined = joined.map(|joined| joined.join(ty)),
+            None => joined = None,
+        }
+    }
+    if float {
+        Some(Ty::F64)
+    } else {
+        joined
+    }
+}
+
+/// The types declared for a function, by annotations in its definition or
+/// by a `(: name (-> ...))` declaration. `None` leaves a parameter to be
+/// specialised on the argument's type, or the return type to inference.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Signature {
+    pub params: Vec<Option<Ty>>,
+    pub ret: Option<Ty>,
+}
+
+impl Signature {
+    /// Whether every parameter has a declared type, in which case there is
+    /// only the one version of the function.
+    pub fn is_complete(&self) -> bool {
+        self.params.iter().all(Option::is_some)
+    }
+
+    /// The parameter types of the version called with arguments of types
+    /// `tys`.
+    pub fn param_types(&self, tys: &[Ty]) -> Vec<Ty> {
+        tys.iter()
+            .enumerate()
+            .map(|(i, ty)| self.params.get(i).copied().flatten().unwrap_or(*ty))
+            .collect()
+    }
+
+    /// Fills in the types `self` leaves undeclared from `other`.
+    pub fn or(mut self, other: &Signature) -> Signature {
+        for (param, declared) in self.params.iter_mut().zip(&other.params) {
+            *param = param.or(*declared);
+        }
+        self.ret = self.ret.or(other.ret);
+        self
+    }
+}
+
+/// The name a function is compiled under for the given argument types. The
+/// all-f64 version keeps the plain name; others are suffixed, e.g.
+/// `factorial.i64`.
+fn specialization_name(name: &str, tys: &[Ty]) -> String {
+    if tys.iter().all(|ty| *ty == Ty::F64) {
+        return name.to_string();
+    }
+    let mut mangled = name.to_string();
+    for ty in tys {
+        mangled.push('.');
+        mangled.push_str(&ty.to_string());
+    }
+    mangled
+}
+
+/// The names of the LLVM struct types of `Ty::Dynamic` and `Ty::Vector`,
+/// which tell them apart from the anonymous rational struct.
+const DYNAMIC_TYPE: &str = "dynamic";
+const VECTOR_TYPE: &str = "vector";
+
+fn struct_ty(ty: StructType) -> Ty {
+    match ty.get_name().map(|name| name.to_bytes()) {
+        Some(name) if name == DYNAMIC_TYPE.as_bytes() => Ty::Dynamic,
+        Some(name) if name == VECTOR_TYPE.as_bytes() => Ty::Vector,
+        _ => Ty::Rational,
+    }
+}
+
+/// The error for an operand of type `ty` where only numbers will do.
+fn not_a_number(ty: Ty) -> &'static str {
+    match ty {
+        Ty::Vector => "expected a number, got a vector.",
+        _ => "expected a number, got a boolean.",
+    }
+}
+
+fn ty_of_type(ty: BasicTypeEnum) -> Ty {
+    match ty {
+        BasicTypeEnum::IntType(int) if int.get_bit_width() == 1 => Ty::Bool,
+        BasicTypeEnum::IntType(_) => Ty::I64,
+        BasicTypeEnum::StructType(st) => struct_ty(st),
+        _ => Ty::F64,
+    }
+}
+
+/// The type a compiled function returns.
+pub fn return_ty(function: FunctionValue) -> Ty {
+    function
+        .get_type()
+        .get_return_type()
+        .map(ty_of_type)
+        .unwrap_or(Ty::F64)
+}
+
+fn param_tys(function: FunctionValue) -> Vec<Ty> {
+    function
+        .get_params()
+        .iter()
+        .map(|param| ty_of_type(param.get_type()))
+        .collect()
+}
+
+/// LLVM's `tailcc`, which every function definition and procedure entry is
+/// compiled with: a call between two such functions that is marked `tail`
+/// and returned straight away is guaranteed to reuse the caller's stack
+/// frame, whatever their parameters. LLVM 12's C API, which inkwell wraps,
+/// can only mark a call `tail`, not `musttail`, so this guarantee is the one
+/// tail calls rely on; it needs the caller and callee to return the same
+/// type, which `Compiler::compile_call` arranges.
+const TAIL_CALL_CONVENTION: u32 = 18;
+
+/// The error compiling stops with after a `set!` widens a global variable to
+/// `Ty::Dynamic`: whatever was compiled before then reads it as its old type,
+/// so the session compiles everything again.
+pub const WIDENED: &str = "a global variable was widened; compile again.";
+
+/// The runtime functions that never raise an error, so a call to one need
+/// not be followed by a check, see `Compiler::check_error`.
+const INFALLIBLE_RUNTIME: &[&str] = &[
+    "lisp_error_pending",
+    "lisp_rational_compare",
+    "lisp_box_rational",
+    "lisp_make_procedure",
+    "lisp_cons",
+    "lisp_make_string",
+    "lisp_gc",
+];
+
+/// The LLVM symbol of global variable `name`. Prefixed so that it cannot
+/// collide with a function of the same name.
+pub fn global_symbol(name: &str) -> String {
+    format!("global.{}", name)
+}
+
+/// The predicates a comparison operator compiles to on integers and floats.
+fn comparison(op: &str) -> Option<(IntPredicate, FloatPredicate)> {
+    match op {
+        "=" => Some((IntPredicate::EQ, FloatPredicate::OEQ)),
+        "<" => Some((IntPredicate::SLT, FloatPredicate::OLT)),
+        ">" => Some((IntPredicate::SGT, FloatPredicate::OGT)),
+        "<=" => Some((IntPredicate::SLE, FloatPredicate::OLE)),
+        ">=" => Some((IntPredicate::SGE, FloatPredicate::OGE)),
+        _ => None,
+    }
+}
+
+/// A compiled value together with its machine type.
+#[derive(Clone, Copy, Debug)]
+pub enum CompiledValue<'ctx> {
+    Float(FloatValue<'ctx>),
+    Int(IntValue<'ctx>),
+    Rational(StructValue<'ctx>),
+    Bool(IntValue<'ctx>),
+    Dynamic(StructValue<'ctx>),
+    Vector(StructValue<'ctx>),
+}
+
+impl<'ctx> CompiledValue<'ctx> {
+    pub fn ty(&self) -> Ty {
+        match self {
+            CompiledValue::Float(_) => Ty::F64,
+            CompiledValue::Int(_) => Ty::I64,
+            CompiledValue::Rational(_) => Ty::Rational,
+            CompiledValue::Bool(_) => Ty::Bool,
+            CompiledValue::Dynamic(_) => Ty::Dynamic,
+            CompiledValue::Vector(_) => Ty::Vector,
+        }
+    }
+
+    fn as_basic(&self) -> BasicValueEnum<'ctx> {
+        match self {
+            CompiledValue::Float(v) => (*v).into(),
+            CompiledValue::Int(v) => (*v).into(),
+            CompiledValue::Rational(v) => (*v).into(),
+            CompiledValue::Bool(v) => (*v).into(),
+            CompiledValue::Dynamic(v) => (*v).into(),
+            CompiledValue::Vector(v) => (*v).into(),
+        }
+    }
+}
+
+impl<'ctx> From<BasicValueEnum<'ctx>> for CompiledValue<'ctx> {
+    fn from(value: BasicValueEnum<'ctx>) -> Self {
+        match value {
+            BasicValueEnum::IntValue(v) if v.get_type().get_bit_width() == 1 => {
+                CompiledValue::Bool(v)
+            }
+            BasicValueEnum::IntValue(v) => CompiledValue::Int(v),
+            BasicValueEnum::StructValue(v) => match struct_ty(v.get_type()) {
+                Ty::Dynamic => CompiledValue::Dynamic(v),
+                Ty::Vector => CompiledValue::Vector(v),
+                _ => CompiledValue::Rational(v),
+            },
+            other => CompiledValue::Float(other.into_float_value()),
+        }
     }
 }
 
+/// How much of the surrounding code an expression's value is simply handed
+/// on through, which decides what a call there can be compiled as.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Tail {
+    /// Something is still to be done with the value.
+    #[default]
+    No,
+    /// The value ends the bodies of the named `let`s from this index of
+    /// `Compiler::loops` inwards, so calling one of them jumps back to its
+    /// top.
+    Loop(usize),
+    /// The value is returned from the function definition being compiled,
+    /// so any call can be a tail call.
+    Function,
+}
+
+/// A named `let` whose body is being compiled, see `compile_named_let`.
+#[derive(Clone)]
+struct NamedLoop<'ctx> {
+    name: String,
+    header: BasicBlock<'ctx>,
+    /// The variables' values at the top of the loop.
+    phis: Vec<PhiValue<'ctx>>,
+    tys: Vec<Ty>,
+    /// The inferred type of the loop's value.
+    result: Ty,
+}
+
+/// The types of local variables `local_tys` in the form inference takes.
+fn local_env(local_tys: &HashMap<String, Ty>) -> HashMap<&str, Ty> {
+    local_tys
+        .iter()
+        .map(|(name, ty)| (name.as_str(), *ty))
+        .collect()
+}
+
 pub struct Compiler<'a, 'ctx> {
     pub context: &'ctx Context,
     pub builder: &'a Builder<'ctx>,
@@ -101,7 +783,32 @@ pub struct Compiler<'a, 'ctx> {
     pub module: &'a Module<'ctx>,
     pub expr: &'a Expr,
     // scopes: Vec<HashMap<String, FloatValue<'ctx>>>,
-    pub global_scope: &'a mut HashMap<String, PointerValue<'ctx>>,
+    /// The type of every global variable; their storage belongs to the
+    /// session, see `global_symbol`.
+    pub global_scope: &'a mut HashMap<String, Ty>,
+    /// Parameters and internal defines of the function currently being
+    /// compiled.
+    locals: HashMap<String, PointerValue<'ctx>>,
+    /// The type each of `locals` was allocated with.
+    local_tys: HashMap<String, Ty>,
+    /// Whether a function definition's body is being compiled, where
+    /// `define` makes a local variable rather than a global one.
+    in_definition: bool,
+    /// Whether the expression about to be compiled is in tail position.
+    /// `compile_expr` clears it, so that it only ever applies to the one
+    /// expression.
+    tail: Tail,
+    /// Where a self tail call in the current definition jumps to, and the
+    /// parameters it stores the new arguments in, with their types.
+    #[allow(clippy::type_complexity)]
+    self_loop: Option<(BasicBlock<'ctx>, Vec<(PointerValue<'ctx>, Ty)>)>,
+    /// The named `let`s being compiled, innermost last.
+    loops: Vec<NamedLoop<'ctx>>,
+    /// Every function definition by name, to compile specialisations from.
+    definitions: HashMap<&'a str, &'a Expr>,
+    /// The return type of every specialisation, inferred before it is
+    /// compiled.
+    inference: Inference<'a>,
     fn_value_opt: Option<FunctionValue<'ctx>>,
 }
 
@@ -112,354 +819,2429 @@ impl<'a, 'ctx> Compiler<'a, 'ctx> {
         self.module.get_function(name)
     }
 
-    // fn lookup_variable(&self, var_name: &str) -> Option<&FloatValue<'ctx>> {
-    //     for scope in self.scopes.iter().rev() {
-    //         if let Some(value) = scope.get(var_name) {
-    //             return Some(value);
-    //         }
-    //     }
-    //     None
-    // }
+    fn lookup_variable(&self, var_name: &str) -> Option<PointerValue<'ctx>> {
+        if let Some(local) = self.locals.get(var_name) {
+            return Some(*local);
+        }
+        let ty = *self.global_scope.get(var_name)?;
+        Some(self.global_variable(var_name, ty))
+    }
+
+    /// A pointer to global variable `name`, declared in the module without a
+    /// definition so the session can map it to storage that outlives the
+    /// module. Its pointee type is `ty` even if an earlier reference in this
+    /// module saw the variable with a different type.
+    fn global_variable(&self, name: &str, ty: Ty) -> PointerValue<'ctx> {
+        let symbol = global_symbol(name);
+        let basic_type = self.basic_type(ty);
+        let global = self
+            .module
+            .get_global(&symbol)
+            .unwrap_or_else(|| self.module.add_global(basic_type, None, &symbol));
+        self.builder.build_pointer_cast(
+            global.as_pointer_value(),
+            basic_type.ptr_type(AddressSpace::default()),
+            name,
+        )
+    }
 
     #[inline]
     fn fn_value(&self) -> FunctionValue<'ctx> {
         self.fn_value_opt.unwrap()
     }
 
-    /// Creates a new stack allocation instruction in the entry block of the function.
-    fn create_entry_block_alloca(&self, name: &str) -> PointerValue<'ctx> {
-        let builder = self.context.create_builder();
-
-        let entry = self.fn_value().get_first_basic_block().unwrap();
-
-        match entry.get_first_instruction() {
-            Some(first_instr) => builder.position_before(&first_instr),
-            None => builder.position_at_end(entry),
+    fn basic_type(&self, ty: Ty) -> BasicTypeEnum<'ctx> {
+        match ty {
+            Ty::F64 => self.context.f64_type().into(),
+            Ty::I64 => self.context.i64_type().into(),
+            Ty::Rational => self.rational_type().into(),
+            Ty::Bool => self.context.bool_type().into(),
+            Ty::Dynamic => self.dynamic_type().into(),
+            Ty::Vector => self.vector_type().into(),
         }
+    }
 
-        builder.build_alloca(self.context.f64_type(), name)
+    fn const_bool(&self, value: bool) -> IntValue<'ctx> {
+        self.context.bool_type().const_int(value as u64, false)
     }
 
-    /// Compiles the specified `Expr` into an LLVM `FloatValue`.
-    pub fn compile_expr(&mut self, expr: &'a Expr) -> Result<FloatValue<'ctx>, &'static str> {
-        match expr {
-            Expr::Float(nb) => Ok(self.context.f64_type().const_float(*nb)),
-            Expr::Integer(nb) => Ok(self.context.f64_type().const_float(*nb as f64)),
-            Expr::Symbol(ref name) => match self.global_scope.get(name.as_str()) {
-                // Some(value) => Ok(*value),
-                Some(var) => {
-                    // println!("self.global_scope: {:?}", self.global_scope);
-
-                    Ok(self
-                        .builder
-                        .build_load(*var, name.as_str())
-                        .into_float_value())
-                }
-                None => {
-                    // println!("self.global_scope: {:?}", self.global_scope);
-                    Err("Could not find a matching variable.")
-                }
-            },
-            Expr::List(ref exprs) => {
-                let (op, args) = extract_op_and_args(exprs)?;
-                // println!("{op}({:?})", args);
-                match op {
-                    "define" => {
-                        if args.len() != 2 {
-                            return Err("define requires a variable name or function definition and a value or expression.");
-                        }
+    fn rational_type(&self) -> StructType<'ctx> {
+        let i64_type = self.context.i64_type();
+        self.context
+            .struct_type(&[i64_type.into(), i64_type.into()], false)
+    }
 
-                        if let Expr::List(func_def) = &args[0] {
-                            if func_def.len() < 1 {
-                                return Err("Function definition should have a name and may have parameters.");
-                            }
-
-                            if let Expr::Symbol(func_name) = &func_def[0] {
-                                let arg_names: Vec<String> = func_def
-                                    .iter()
-                                    .skip(1)
-                                    .filter_map(|arg| match arg {
-                                        Expr::Symbol(s) => Some(s.to_string()),
-                                        _ => None,
-                                    })
-                                    .collect();
-                                let arity = arg_names.len();
-
-                                if arity != func_def.len() - 1 {
-                                    return Err("Function arguments should be symbols.");
-                                }
-
-                                let body = self.compile_expr(&args[1])?;
-
-                                Ok(body)
-                            } else {
-                                Err("Function definition should start with a symbol for its name.")
-                            }
-                        } else {
-                            // println!("defining a variable ");
-                            // Handle the define variable case
-                            if let Expr::Symbol(var_name) = &args[0] {
-                                let value = self.compile_expr(&args[1])?;
-                                let alloca = self.create_entry_block_alloca(var_name);
-                                self.builder.build_store(alloca, value);
-                                self.global_scope.insert(var_name.clone(), alloca);
-                                Ok(value)
-                            } else {
-                                Err("define requires a variable name to be a symbol.")
-                            }
-                        }
-                    }
-                    // i think in all the cases below this we want to compile a prototype and anonymous function with zero args.
-                    _ => {
-                        let compiled_args: Result<Vec<FloatValue<'ctx>>, _> =
-                            args.into_iter().map(|arg| self.compile_expr(arg)).collect();
-                        match compiled_args {
-                            Ok(compiled_args) => {
-                                match op {
-                                    "+" => {
-                                        match compiled_args.into_iter().reduce(|lhs, rhs| {
-                                            self.builder.build_float_add(lhs, rhs, "tmpadd")
-                                        }) {
-                                            Some(result) => Ok(result),
-                                            None => Err(
-                                                "Error: Addition requires at least one argument.",
-                                            ),
-                                        }
-                                    }
-                                    "-" => {
-                                        match compiled_args.into_iter().reduce(|lhs, rhs| {
-                                            self.builder.build_float_sub(lhs, rhs, "tmpsub")
-                                        }) {
-                                            Some(result) => Ok(result),
-                                            None => Err("Error: Subtraction requires at least one argument."),
-                                        }
-                                    }
-                                    "*" => {
-                                        match compiled_args.into_iter().reduce(|lhs, rhs| {
-                                            self.builder.build_float_mul(lhs, rhs, "tmpmul")
-                                        }) {
-                                            Some(result) => Ok(result),
-                                            None => Err("Error: Multiplication requires at least one argument."),
-                                        }
-                                    }
-                                    "/" => {
-                                        match compiled_args.into_iter().reduce(|lhs, rhs| {
-                                            self.builder.build_float_div(lhs, rhs, "tmpdiv")
-                                        }) {
-                                            Some(result) => Ok(result),
-                                            None => Err(
-                                                "Error: Division requires at least one argument.",
-                                            ),
-                                        }
-                                    }
-                                    _ => {
-                                        match self.module.get_function(op) {
-                                            Some(f) => {
-                                                let mut compiled_args = vec![];
-
-                                                for arg in args.iter() {
-                                                    let foo = BasicMetadataValueEnum::FloatValue(
-                                                        self.compile_expr(arg).unwrap(),
-                                                    );
-                                                    compiled_args.push(foo);
-                                                }
-                                                let body = self
-                                                    .builder
-                                                    .build_call(
-                                                        f,
-                                                        compiled_args.as_slice(),
-                                                        "tmpcall",
-                                                    )
-                                                    .try_as_basic_value()
-                                                    .left()
-                                                    .unwrap()
-                                                    .into_float_value();
-
-                                                Ok(body)
-                                            }
-                                            None => {
-                                                let intrinsic =
-                                                    inkwell::intrinsics::Intrinsic::find(op);
-                                                if let Some(found_intrinsic) = intrinsic {
-                                                    // Get double type from the context
-                                                    let double_type = self.context.f64_type();
-
-                                                    // Get the function declaration from the intrinsic
-                                                    let intrinsic_function = found_intrinsic
-                                                        .get_declaration(
-                                                            &self.module,
-                                                            vec![double_type.into(); args.len()]
-                                                                .as_slice(),
-                                                        )
-                                                        .unwrap();
-
-                                                    // // Process the compiled arguments
-                                                    let mut compiled_args = vec![];
-                                                    for arg in args.iter() {
-                                                        let foo =
-                                                            BasicMetadataValueEnum::FloatValue(
-                                                                self.compile_expr(arg).unwrap(),
-                                                            );
-                                                        compiled_args.push(foo);
-                                                    }
-
-                                                    // Perform the intrinsic function call
-                                                    let body = self
-                                                        .builder
-                                                        .build_call(
-                                                            intrinsic_function,
-                                                            compiled_args.as_slice(),
-                                                            "intrinsic_call",
-                                                        )
-                                                        .try_as_basic_value()
-                                                        .left()
-                                                        .unwrap()
-                                                        .into_float_value();
-
-                                                    Ok(body)
-                                                } else {
-                                                    Err("no function found with that name")
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            Err(err) => Err(err),
-                        }
-                    }
-                }
-            }
-        }
+    fn dynamic_type(&self) -> StructType<'ctx> {
+        self.context
+            .get_struct_type(DYNAMIC_TYPE)
+            .unwrap_or_else(|| {
+                let i64_type = self.context.i64_type();
+                let ty = self.context.opaque_struct_type(DYNAMIC_TYPE);
+                ty.set_body(&[i64_type.into(), i64_type.into()], false);
+                ty
+            })
     }
 
-    /// Compiles the specified `Prototype` into an extern LLVM `FunctionValue`.
-    /// nargs is the number of arguments the function takes. not the number of arguments in the List
-    fn compile_prototype(
-        &self,
-        name: &str,
-        arg_names: Vec<String>,
-    ) -> Result<FunctionValue<'ctx>, &'static str> {
-        let ret_type = self.context.f64_type();
-        let nargs = arg_names.len();
-        let args_types = std::iter::repeat(ret_type)
-            .take(nargs)
-            .map(|f| f.into())
-            .collect::<Vec<BasicMetadataTypeEnum>>();
-        let args_types = args_types.as_slice();
+    fn vector_type(&self) -> StructType<'ctx> {
+        self.context
+            .get_struct_type(VECTOR_TYPE)
+            .unwrap_or_else(|| {
+                let data_type = self.context.f64_type().ptr_type(AddressSpace::default());
+                let ty = self.context.opaque_struct_type(VECTOR_TYPE);
+                ty.set_body(&[data_type.into(), self.context.i64_type().into()], false);
+                ty
+            })
+    }
 
-        let fn_type = self.context.f64_type().fn_type(args_types, false);
-        let fn_val = self.module.add_function(name, fn_type, None);
+    /// The vector whose first element is at address `data`, reading its
+    /// length from the header word before it.
+    fn vector_at(&self, data: IntValue<'ctx>) -> StructValue<'ctx> {
+        let i64_type = self.context.i64_type();
+        let header = self.builder.build_int_sub(
+            data,
+            i64_type.const_int(std::mem::size_of::<u64>() as u64, false),
+            "header",
+        );
+        let header = self.builder.build_int_to_ptr(
+            header,
+            i64_type.ptr_type(AddressSpace::default()),
+            "header",
+        );
+        let len = self.builder.build_load(header, "len");
+        let data = self.builder.build_int_to_ptr(
+            data,
+            self.context.f64_type().ptr_type(AddressSpace::default()),
+            "data",
+        );
+        let vector = self
+            .builder
+            .build_insert_value(self.vector_type().get_undef(), data, 0, "data")
+            .unwrap();
+        self.builder
+            .build_insert_value(vector, len, 1, "len")
+            .unwrap()
+            .into_struct_value()
+    }
 
-        // set arguments names
-        for (i, arg) in fn_val.get_param_iter().enumerate() {
-            arg.into_float_value().set_name(&arg_names[i]);
-        }
+    /// The data pointer and length of a vector.
+    fn vector_parts(&self, vector: StructValue<'ctx>) -> (PointerValue<'ctx>, IntValue<'ctx>) {
+        let data = self.builder.build_extract_value(vector, 0, "data").unwrap();
+        let len = self.builder.build_extract_value(vector, 1, "len").unwrap();
+        (data.into_pointer_value(), len.into_int_value())
+    }
 
-        // finally return built prototype
-        Ok(fn_val)
+    fn tag(&self, tag: Tag) -> IntValue<'ctx> {
+        self.context.i64_type().const_int(tag as u64, false)
     }
 
-    /// Compiles the specified `Function` into an LLVM `FunctionValue`.
-    fn compile_fn(&mut self) -> Result<FunctionValue<'ctx>, &'static str> {
-        let (op, args) = match self.expr {
-            // (define (square x) (* x x)))
-            Expr::List(exs) => {
-                let (op, args) = extract_op_and_args(exs)?;
-                match op {
-                    "define" => {
-                        let sig = &args.clone()[0];
-                        match sig { // (square x) or just x 
-                            Expr::List(sig_exs) => { // this arm is for function definition (square x)
-                                let (fn_name, fn_args) = extract_op_and_args(&sig_exs)?;
-                                // Extract arg_names and validate
-                                let mut arg_names: Vec<String> = vec![];
-                                for arg in fn_args.iter() {
-                                    match arg {
-                                        Expr::Symbol(s) => arg_names.push(s.to_string()),
-                                        _ => return Err("in define: all the elements in the argument list must be symbols"),
-                                    }
-                                }
-                                (fn_name, arg_names)
-                            }
-                            Expr::Symbol(s) => ("anon", vec![].into()),
-                            _ => panic!("in define: the first element in the argument list must be a symbol"),
-                        }
-                    }
-                    _ => ("anon", vec![].into()),
-                }
-            }
-            _ => ("anon", vec![].into()),
-        };
+    fn const_dynamic(&self, tag: Tag, payload: u64) -> StructValue<'ctx> {
+        let payload = self.context.i64_type().const_int(payload, false);
+        self.dynamic_type()
+            .const_named_struct(&[self.tag(tag).into(), payload.into()])
+    }
 
-        let function = self.compile_prototype(op, args.clone())?;
+    /// Builds a rational or dynamic value of struct type `ty` from its two
+    /// words.
+    fn make_pair(
+        &self,
+        ty: StructType<'ctx>,
+        first: IntValue<'ctx>,
+        second: IntValue<'ctx>,
+    ) -> StructValue<'ctx> {
+        let with_first = self
+            .builder
+            .build_insert_value(ty.get_undef(), first, 0, "first")
+            .unwrap();
+        self.builder
+            .build_insert_value(with_first, second, 1, "second")
+            .unwrap()
+            .into_struct_value()
+    }
 
-        let entry = self.context.append_basic_block(function, "entry");
+    /// The two words of a rational (numerator and denominator) or of a
+    /// dynamic value (tag and payload).
+    fn pair_parts(&self, value: StructValue<'ctx>) -> (IntValue<'ctx>, IntValue<'ctx>) {
+        let first = self.builder.build_extract_value(value, 0, "first").unwrap();
+        let second = self
+            .builder
+            .build_extract_value(value, 1, "second")
+            .unwrap();
+        (first.into_int_value(), second.into_int_value())
+    }
 
-        self.builder.position_at_end(entry);
+    /// Declares the `runtime` function `name` in the module if it is not
+    /// already, so that `runtime::link` can map it.
+    fn runtime_function(
+        &self,
+        name: &str,
+        ret: BasicTypeEnum<'ctx>,
+        params: &[BasicMetadataTypeEnum<'ctx>],
+    ) -> FunctionValue<'ctx> {
+        self.get_function(name).unwrap_or_else(|| {
+            self.module
+                .add_function(name, ret.fn_type(params, false), None)
+        })
+    }
 
-        // update fn field
-        self.fn_value_opt = Some(function);
+    /// Calls the runtime function `name`, declaring it from the types of
+    /// `args` and `ret` the first time, and returns if it raised an error.
+    fn call_runtime(
+        &self,
+        name: &str,
+        ret: BasicTypeEnum<'ctx>,
+        args: &[BasicValueEnum<'ctx>],
+    ) -> BasicValueEnum<'ctx> {
+        let result = self.build_runtime_call(name, ret, args);
+        if !INFALLIBLE_RUNTIME.contains(&name) {
+            self.check_error();
+        }
+        result
+    }
 
-        // build variables map
-        self.global_scope.reserve(args.len());
+    /// `call_runtime` without the check for an error.
+    fn build_runtime_call(
+        &self,
+        name: &str,
+        ret: BasicTypeEnum<'ctx>,
+        args: &[BasicValueEnum<'ctx>],
+    ) -> BasicValueEnum<'ctx> {
+        let params: Vec<BasicMetadataTypeEnum> =
+            args.iter().map(|arg| arg.get_type().into()).collect();
+        let function = self.runtime_function(name, ret, &params);
+        let args: Vec<BasicMetadataValueEnum> = args.iter().map(|arg| (*arg).into()).collect();
+        self.builder
+            .build_call(function, &args, "tmprt")
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+    }
 
-        for (i, arg) in function.get_param_iter().enumerate() {
-            let arg_name = args[i].as_str();
-            let alloca = self.create_entry_block_alloca(arg_name);
+    /// Returns from the function being built if the runtime has raised an
+    /// error, so that the error aborts the whole evaluation: every compiled
+    /// caller checks again when the call returns, up to the session, which
+    /// reports it. Nothing runs on the placeholder the runtime handed back.
+    fn check_error(&self) {
+        let i64_type = self.context.i64_type();
+        let pending = self
+            .build_runtime_call("lisp_error_pending", i64_type.into(), &[])
+            .into_int_value();
+        let raised = self.builder.build_int_compare(
+            IntPredicate::NE,
+            pending,
+            i64_type.const_zero(),
+            "raised",
+        );
+        let function = self.fn_value();
+        let raised_bb = self.context.append_basic_block(function, "raised");
+        let ok_bb = self.context.append_basic_block(function, "ok");
+        self.builder
+            .build_conditional_branch(raised, raised_bb, ok_bb);
+        self.builder.position_at_end(raised_bb);
+        self.bail();
+        self.builder.position_at_end(ok_bb);
+    }
 
-            self.builder.build_store(alloca, arg);
+    /// Returns from the function being built with a placeholder its caller
+    /// never uses, since an error is pending.
+    fn bail(&self) {
+        let placeholder = self.placeholder(return_ty(self.fn_value()));
+        self.builder.build_return(Some(&placeholder));
+    }
 
-            self.global_scope.insert(args[i].clone(), alloca);
+    /// An undefined value of type `ty`, for code that cannot be reached.
+    fn placeholder(&self, ty: Ty) -> BasicValueEnum<'ctx> {
+        match self.basic_type(ty) {
+            BasicTypeEnum::IntType(ty) => ty.get_undef().into(),
+            BasicTypeEnum::StructType(ty) => ty.get_undef().into(),
+            ty => ty.into_float_type().get_undef().into(),
         }
+    }
 
-        // let tmp = self.global_scope.clear();
+    /// Calls the runtime function `name`, which takes `extra` arguments and
+    /// then two rationals or two dynamic values as four i64s, and returns
+    /// `ret`.
+    fn call_pair_runtime(
+        &self,
+        name: &str,
+        ret: BasicTypeEnum<'ctx>,
+        extra: &[BasicValueEnum<'ctx>],
+        lhs: StructValue<'ctx>,
+        rhs: StructValue<'ctx>,
+    ) -> BasicValueEnum<'ctx> {
+        let (a0, a1) = self.pair_parts(lhs);
+        let (b0, b1) = self.pair_parts(rhs);
+        let mut args = extra.to_vec();
+        args.extend([a0, a1, b0, b1].map(BasicValueEnum::from));
+        self.call_runtime(name, ret, &args)
+    }
 
-        // for (k, v) in self.global_scope.iter() {
-        //     // let arg_name = args[i].as_str();
-        //     // let alloca = self.create_entry_block_alloca(k);
-        //     v.
-        //     self.builder.build_store(alloca, v.into());
-        // }
+    /// Creates a new stack allocation instruction in the entry block of the function.
+    fn create_entry_block_alloca(&self, name: &str, ty: Ty) -> PointerValue<'ctx> {
+        let builder = self.context.create_builder();
 
-        // compile body
-        let body = self.compile_expr(self.expr)?;
+        let entry = self.fn_value().get_first_basic_block().unwrap();
 
-        self.builder.build_return(Some(&body));
+        match entry.get_first_instruction() {
+            Some(first_instr) => builder.position_before(&first_instr),
+            None => builder.position_at_end(entry),
+        }
 
-        // return the whole thing after verification and optimization
-        if function.verify(true) {
-            self.fpm.run_on(&function);
+        builder.build_alloca(self.basic_type(ty), name)
+    }
 
-            Ok(function)
-        } else {
-            unsafe {
-                function.delete();
+    /// Converts `value` to `ty`. Integers widen to rationals and anything
+    /// becomes a float; a float made exact takes its exact binary value.
+    /// Narrowing to an integer truncates, which the type rules never ask for
+    /// in practice. Anything can be boxed into a dynamic value, and
+    /// unboxing one checks its type at run time.
+    fn convert(&self, value: CompiledValue<'ctx>, ty: Ty) -> CompiledValue<'ctx> {
+        let f64_type = self.context.f64_type();
+        let i64_type = self.context.i64_type();
+        match (value, ty) {
+            (_, Ty::Dynamic) => CompiledValue::Dynamic(self.to_dynamic(value)),
+            (CompiledValue::Dynamic(v), ty) => self.unbox(v, ty),
+            (CompiledValue::Int(v), Ty::F64) => CompiledValue::Float(
+                self.builder
+                    .build_signed_int_to_float(v, f64_type, "tmpconv"),
+            ),
+            (CompiledValue::Int(v), Ty::Rational) => CompiledValue::Rational(self.make_pair(
+                self.rational_type(),
+                v,
+                i64_type.const_int(1, false),
+            )),
+            (CompiledValue::Float(v), Ty::I64) => CompiledValue::Int(
+                self.builder
+                    .build_float_to_signed_int(v, i64_type, "tmpconv"),
+            ),
+            (CompiledValue::Float(v), Ty::Rational) => {
+                let ratio = self.call_runtime(
+                    "lisp_rational_from_f64",
+                    self.rational_type().into(),
+                    &[v.into()],
+                );
+                CompiledValue::Rational(ratio.into_struct_value())
+            }
+            (CompiledValue::Rational(v), Ty::F64) => {
+                let (num, den) = self.pair_parts(v);
+                let num = self
+                    .builder
+                    .build_signed_int_to_float(num, f64_type, "numconv");
+                let den = self
+                    .builder
+                    .build_signed_int_to_float(den, f64_type, "denconv");
+                CompiledValue::Float(self.builder.build_float_div(num, den, "tmpconv"))
+            }
+            (CompiledValue::Rational(v), Ty::I64) => {
+                let (num, den) = self.pair_parts(v);
+                CompiledValue::Int(self.builder.build_int_signed_div(num, den, "tmpconv"))
             }
+            _ => value,
+        }
+    }
 
-            Err("Invalid generated function.")
+    fn to_float(&self, value: CompiledValue<'ctx>) -> FloatValue<'ctx> {
+        match self.convert(value, Ty::F64) {
+            CompiledValue::Float(v) => v,
+            _ => unreachable!(),
         }
     }
 
-    /// Compiles the specified `Function` in the given `Context` and using the specified `Builder`, `PassManager`, and `Module`.
+    fn to_rational(&self, value: CompiledValue<'ctx>) -> StructValue<'ctx> {
+        match self.convert(value, Ty::Rational) {
+            CompiledValue::Rational(v) => v,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Boxes `value` into a tag and a payload.
+    fn to_dynamic(&self, value: CompiledValue<'ctx>) -> StructValue<'ctx> {
+        let i64_type = self.context.i64_type();
+        let (tag, payload) = match value {
+            CompiledValue::Dynamic(v) => return v,
+            CompiledValue::Rational(v) => {
+                let (num, den) = self.pair_parts(v);
+                return self
+                    .call_runtime(
+                        "lisp_box_rational",
+                        self.dynamic_type().into(),
+                        &[num.into(), den.into()],
+                    )
+                    .into_struct_value();
+            }
+            CompiledValue::Int(v) => (Tag::Int, v),
+            CompiledValue::Float(v) => (
+                Tag::Float,
+                self.builder
+                    .build_bitcast(v, i64_type, "floatbits")
+                    .into_int_value(),
+            ),
+            CompiledValue::Bool(v) => (
+                Tag::Bool,
+                self.builder.build_int_z_extend(v, i64_type, "boolbits"),
+            ),
+            CompiledValue::Vector(v) => {
+                let (data, _) = self.vector_parts(v);
+                (
+                    Tag::Vector,
+                    self.builder.build_ptr_to_int(data, i64_type, "vectorbits"),
+                )
+            }
+        };
+        self.make_pair(self.dynamic_type(), self.tag(tag), payload)
+    }
+
+    /// Unboxes a dynamic value as `ty`, raising a type error at run time if
+    /// it is something else. Any number converts to f64 or a rational.
+    fn unbox(&self, value: StructValue<'ctx>, ty: Ty) -> CompiledValue<'ctx> {
+        let i64_type = self.context.i64_type();
+        let (tag, payload) = self.pair_parts(value);
+        let expect = |expected: Tag| {
+            self.call_runtime(
+                "lisp_expect",
+                i64_type.into(),
+                &[tag.into(), payload.into(), self.tag(expected).into()],
+            )
+            .into_int_value()
+        };
+        match ty {
+            Ty::I64 => CompiledValue::Int(expect(Tag::Int)),
+            Ty::Bool => CompiledValue::Bool(self.builder.build_int_truncate(
+                expect(Tag::Bool),
+                self.context.bool_type(),
+                "tmpbool",
+            )),
+            Ty::F64 => CompiledValue::Float(
+                self.call_runtime(
+                    "lisp_to_f64",
+                    self.context.f64_type().into(),
+                    &[tag.into(), payload.into()],
+                )
+                .into_float_value(),
+            ),
+            Ty::Rational => CompiledValue::Rational(
+                self.call_runtime(
+                    "lisp_to_rational",
+                    self.rational_type().into(),
+                    &[tag.into(), payload.into()],
+                )
+                .into_struct_value(),
+            ),
+            Ty::Vector => {
+                let data = self
+                    .call_runtime(
+                        "lisp_expect_vector",
+                        i64_type.into(),
+                        &[tag.into(), payload.into()],
+                    )
+                    .into_int_value();
+                CompiledValue::Vector(self.vector_at(data))
+            }
+            Ty::Dynamic => CompiledValue::Dynamic(value),
+        }
+    }
+
+    /// Whether `value` counts as true. As in Scheme, only `#f` is false:
+    /// every number, zero included, is true.
+    fn truthy(&self, value: CompiledValue<'ctx>) -> IntValue<'ctx> {
+        match value {
+            CompiledValue::Bool(v) => v,
+            CompiledValue::Dynamic(v) => {
+                let (tag, payload) = self.pair_parts(v);
+                let is_bool = self.builder.build_int_compare(
+                    IntPredicate::EQ,
+                    tag,
+                    self.tag(Tag::Bool),
+                    "isbool",
+                );
+                let is_zero = self.builder.build_int_compare(
+                    IntPredicate::EQ,
+                    payload,
+                    self.context.i64_type().const_zero(),
+                    "iszero",
+                );
+                let is_false = self.builder.build_and(is_bool, is_zero, "isfalse");
+                self.builder.build_not(is_false, "truthy")
+            }
+            _ => self.const_bool(true),
+        }
+    }
+
+    fn compile_args(&mut self, args: &'a [Expr]) -> Result<Vec<CompiledValue<'ctx>>, &'static str> {
+        args.iter().map(|arg| self.compile_expr(arg)).collect()
+    }
+
+    /// Like `compile_args`, for operators that are only defined on numbers.
+    fn compile_numbers(
+        &mut self,
+        args: &'a [Expr],
+    ) -> Result<Vec<CompiledValue<'ctx>>, &'static str> {
+        let compiled_args = self.compile_args(args)?;
+        match compiled_args.iter().find(|arg| !arg.ty().is_number()) {
+            Some(arg) => Err(not_a_number(arg.ty())),
+            None => Ok(compiled_args),
+        }
+    }
+
+    /// Compiles the specified `Expr` into a typed LLVM value.
+    pub fn compile_expr(&mut self, expr: &'a Expr) -> Result<CompiledValue<'ctx>, &'static str> {
+        // only `if`, `begin`, `let`, `do` and calls pass tail position on
+        let tail = std::mem::take(&mut self.tail);
+        match expr {
+            Expr::Bool(b) => Ok(CompiledValue::Bool(self.const_bool(*b))),
+            Expr::Char(c) => Ok(CompiledValue::Dynamic(
+                self.const_dynamic(Tag::Char, *c as u64),
+            )),
+            Expr::Float(nb) => Ok(CompiledValue::Float(
+                self.context.f64_type().const_float(*nb),
+            )),
+            Expr::Integer(nb) => Ok(CompiledValue::Int(
+                self.context.i64_type().const_int(*nb as u64, true),
+            )),
+            Expr::Rational(num, den) => {
+                let i64_type = self.context.i64_type();
+                Ok(CompiledValue::Rational(
+                    self.rational_type().const_named_struct(&[
+                        i64_type.const_int(*num as u64, true).into(),
+                        i64_type.const_int(*den as u64, true).into(),
+                    ]),
+                ))
+            }
+            Expr::Str(text) => Ok(CompiledValue::Dynamic(self.make_string(text))),
+            Expr::Vector(items) => {
+                let elements = self.compile_args(items)?;
+                self.compile_vector(elements).map(CompiledValue::Vector)
+            }
+            Expr::Symbol(ref name) => match self.lookup_variable(name.as_str()) {
+                Some(var) => Ok(self.builder.build_load(var, name.as_str()).into()),
+                None => match constants::lookup(name) {
+                    Some(x) => Ok(CompiledValue::Float(self.context.f64_type().const_float(x))),
+                    None if self.definitions.contains_key(name.as_str()) => {
+                        self.compile_procedure(name)
+                    }
+                    None => Err("Could not find a matching variable."),
+                },
+            },
+            Expr::List(ref exprs) => {
+                let (op, args) = extract_op_and_args(exprs)?;
+                match op {
+                    "define" => self.compile_define(args),
+                    "if" => self.compile_if(args, tail),
+                    "begin" => self.compile_sequence(args, tail),
+                    "let" => self.compile_let(args, tail),
+                    "do" => self.compile_do(args, tail),
+                    "while" => self.compile_while(args),
+                    "set!" => self.compile_set(args),
+                    "+" | "-" | "*" | "/" => self.compile_arithmetic(op, args),
+                    "quotient" | "remainder" | "modulo" => self.compile_integer_division(op, args),
+                    "exact->inexact" | "inexact" | "inexact->exact" | "exact" => {
+                        self.compile_exactness(op, args)
+                    }
+                    "not" => self.compile_not(args),
+                    "quote" => match args {
+                        [datum] => self.compile_quote(datum).map(CompiledValue::Dynamic),
+                        _ => Err("quote takes exactly one datum."),
+                    },
+                    "cons" | "car" | "cdr" | "list" | "length" | "append" | "reverse" => {
+                        self.compile_list_operation(op, args)
+                    }
+                    "vector" | "make-vector" | "vector-ref" | "vector-set!" | "vector-length" => {
+                        self.compile_vector_operation(op, args)
+                    }
+                    "display" | "write" | "newline" | "string-append" | "string-length"
+                    | "number->string" | "string->number" => {
+                        self.compile_string_operation(op, args)
+                    }
+                    "gc" if args.is_empty() => Ok(CompiledValue::Int(
+                        self.call_runtime("lisp_gc", self.context.i64_type().into(), &[])
+                            .into_int_value(),
+                    )),
+                    _ if PREDICATES.contains(&op) => self.compile_predicate(op, args),
+                    _ => match comparison(op) {
+                        Some(predicates) => self
+                            .compile_comparison(predicates, args)
+                            .map(CompiledValue::Bool),
+                        None => self.compile_call(op, args, tail),
+                    },
+                }
+            }
+        }
+    }
+
+    fn compile_define(&mut self, args: &'a [Expr]) -> Result<CompiledValue<'ctx>, &'static str> {
+        if args.len() != 2 {
+            return Err(
+                "define requires a variable name or function definition and a value or expression.",
+            );
+        }
+
+        match &args[0] {
+            // top-level function definitions are handled by `compile_fn`
+            // nested ones have been lifted out already, see `lift`
+            Expr::List(_) => Err(
+                "function definitions are only allowed at the top level or the start of a body.",
+            ),
+            // outside of a body, see `compile_sequence`
+            Expr::Symbol(var_name) if self.in_definition => {
+                self.compile_local_define(var_name, &args[1], &[])
+            }
+            Expr::Symbol(var_name) => {
+                let value = self.compile_expr(&args[1])?;
+                // a variable that has been boxed stays boxed, see `compile_set`
+                let ty = match self.global_scope.get(var_name) {
+                    Some(Ty::Dynamic) => Ty::Dynamic,
+                    _ => value.ty(),
+                };
+                let value = self.convert(value, ty);
+                let global = self.global_variable(var_name, ty);
+                self.builder.build_store(global, value.as_basic());
+                self.global_scope.insert(var_name.clone(), ty);
+                self.inference.define_global(var_name, ty);
+                Ok(value)
+            }
+            _ => Err("define requires a variable name to be a symbol."),
+        }
+    }
+
+    /// An internal `(define name value)`, whose scope is the rest of the
+    /// body, `scope`: binds a local variable, boxed if the scope `set!`s it
+    /// to something the value's type cannot hold.
+    fn compile_local_define(
+        &mut self,
+        name: &str,
+        value: &'a Expr,
+        scope: &'a [Expr],
+    ) -> Result<CompiledValue<'ctx>, &'static str> {
+        let value = self.compile_expr(value)?;
+        let env = local_env(&self.local_tys);
+        let ty = self
+            .inference
+            .scope_types(&[name], vec![value.ty()], scope, &env)[0];
+        let value = self.convert(value, ty);
+        let alloca = self.create_entry_block_alloca(name, ty);
+        self.builder.build_store(alloca, value.as_basic());
+        self.locals.insert(name.to_string(), alloca);
+        self.local_tys.insert(name.to_string(), ty);
+        Ok(value)
+    }
+
+    /// `(begin a b c)`: evaluates each expression in order for its effects
+    /// and returns the value of the last, which is in tail position if the
+    /// `begin` is. Function bodies are compiled the same way.
+    fn compile_sequence(
+        &mut self,
+        exprs: &'a [Expr],
+        tail: Tail,
+    ) -> Result<CompiledValue<'ctx>, &'static str> {
+        let (last, effects) = exprs
+            .split_last()
+            .ok_or("begin requires at least one expression.")?;
+        for (i, expr) in effects.iter().enumerate() {
+            if let (true, Expr::List(parts)) = (self.in_definition, expr) {
+                if let [Expr::Symbol(op), Expr::Symbol(name), value] = parts.as_slice() {
+                    if op == "define" {
+                        self.compile_local_define(name, value, &exprs[i + 1..])?;
+                        continue;
+                    }
+                }
+            }
+            self.compile_expr(expr)?;
+        }
+        self.tail = tail;
+        self.compile_expr(last)
+    }
+
+    /// `(set! name value)`: stores `value` in a local or an existing global
+    /// variable. A local that is ever set to a value its type cannot hold is
+    /// boxed from the start, see `Inference::assigned_types`; a global one is
+    /// widened to `Ty::Dynamic` there and then, and everything compiled again
+    /// (see `WIDENED`), after the session boxes the value it holds.
+    fn compile_set(&mut self, args: &'a [Expr]) -> Result<CompiledValue<'ctx>, &'static str> {
+        let (name, value) = match args {
+            [Expr::Symbol(name), value] => (name, value),
+            _ => return Err("set! requires a variable name and a value."),
+        };
+        let (ptr, ty) = match self.locals.get(name.as_str()) {
+            Some(local) => (*local, self.local_tys[name.as_str()]),
+            None => match self.global_scope.get(name).copied() {
+                Some(ty) => (self.global_variable(name, ty), ty),
+                None => return Err("set! of an unbound variable."),
+            },
+        };
+        let value = self.compile_expr(value)?;
+        if !ty.holds(value.ty()) && !self.locals.contains_key(name.as_str()) {
+            self.global_scope.insert(name.clone(), Ty::Dynamic);
+            return Err(WIDENED);
+        }
+        // what inference could not foresee is checked at run time
+        if !ty.accepts(value.ty()) {
+            return Err("set! of a value the variable's type cannot hold.");
+        }
+        let value = self.convert(value, ty);
+        self.builder.build_store(ptr, value.as_basic());
+        Ok(CompiledValue::Dynamic(
+            self.const_dynamic(Tag::Unspecified, 0),
+        ))
+    }
+
+    /// `+ - * /` over any number of operands. Exact operands give an exact
+    /// result: integers stay integers except under `/`, which makes a
+    /// rational, and rational arithmetic goes through the runtime. Any float
+    /// operand makes the whole result a float, and any dynamic one leaves
+    /// the whole computation to the runtime. As in Scheme, `(+)` is 0, `(*)`
+    /// is 1, `(- x)` negates `x` and `(/ x)` is its reciprocal.
+    fn compile_arithmetic(
+        &mut self,
+        op: &str,
+        args: &'a [Expr],
+    ) -> Result<CompiledValue<'ctx>, &'static str> {
+        let mut compiled_args = self.compile_numbers(args)?;
+        let i64_type = self.context.i64_type();
+        match (op, compiled_args.as_slice()) {
+            ("+", []) => return Ok(CompiledValue::Int(i64_type.const_zero())),
+            ("*", []) => return Ok(CompiledValue::Int(i64_type.const_int(1, false))),
+            ("-", []) => return Err("Error: Subtraction requires at least one argument."),
+            ("/", []) => return Err("Error: Division requires at least one argument."),
+            // fneg, since 0.0 - x is 0.0 rather than -0.0 for x = 0.0
+            ("-", [CompiledValue::Float(x)]) => {
+                return Ok(CompiledValue::Float(
+                    self.builder.build_float_neg(*x, "tmpneg"),
+                ))
+            }
+            ("-", [_]) => compiled_args.insert(0, CompiledValue::Int(i64_type.const_zero())),
+            ("/", [_]) => compiled_args.insert(0, CompiledValue::Int(i64_type.const_int(1, false))),
+            _ => {}
+        }
+
+        let mut ty = compiled_args
+            .iter()
+            .fold(Ty::I64, |ty, arg| ty.join(arg.ty()));
+        if op == "/" && ty == Ty::I64 {
+            ty = Ty::Rational;
+        }
+
+        if ty == Ty::I64 {
+            let ints = compiled_args.into_iter().map(|arg| match arg {
+                CompiledValue::Int(v) => v,
+                _ => unreachable!(),
+            });
+            return ints
+                .reduce(|lhs, rhs| self.checked_int_arithmetic(op, lhs, rhs))
+                .map(CompiledValue::Int)
+                .ok_or("arithmetic without operands");
+        }
+
+        if ty == Ty::Rational {
+            let name = match op {
+                "+" => "lisp_rational_add",
+                "-" => "lisp_rational_sub",
+                "*" => "lisp_rational_mul",
+                _ => "lisp_rational_div",
+            };
+            let ret = self.rational_type().into();
+            let rationals: Vec<StructValue<'ctx>> = compiled_args
+                .into_iter()
+                .map(|arg| self.to_rational(arg))
+                .collect();
+            return rationals
+                .into_iter()
+                .reduce(|lhs, rhs| {
+                    self.call_pair_runtime(name, ret, &[], lhs, rhs)
+                        .into_struct_value()
+                })
+                .map(CompiledValue::Rational)
+                .ok_or("arithmetic without operands");
+        }
+
+        // operand types only known at run time: the runtime dispatches on
+        // them with the same rules
+        if ty == Ty::Dynamic {
+            let code = match op {
+                "+" => 0,
+                "-" => 1,
+                "*" => 2,
+                _ => 3,
+            };
+            let code = self.context.i64_type().const_int(code, false).into();
+            let ret = self.dynamic_type().into();
+            let values: Vec<StructValue<'ctx>> = compiled_args
+                .into_iter()
+                .map(|arg| self.to_dynamic(arg))
+                .collect();
+            return values
+                .into_iter()
+                .reduce(|lhs, rhs| {
+                    self.call_pair_runtime("lisp_dynamic_arithmetic", ret, &[code], lhs, rhs)
+                        .into_struct_value()
+                })
+                .map(CompiledValue::Dynamic)
+                .ok_or("arithmetic without operands");
+        }
+
+        let floats: Vec<FloatValue<'ctx>> = compiled_args
+            .into_iter()
+            .map(|arg| self.to_float(arg))
+            .collect();
+        floats
+            .into_iter()
+            .reduce(|lhs, rhs| match op {
+                "+" => self.builder.build_float_add(lhs, rhs, "tmpadd"),
+                "-" => self.builder.build_float_sub(lhs, rhs, "tmpsub"),
+                "*" => self.builder.build_float_mul(lhs, rhs, "tmpmul"),
+                _ => self.builder.build_float_div(lhs, rhs, "tmpdiv"),
+            })
+            .map(CompiledValue::Float)
+            .ok_or("arithmetic without operands")
+    }
+
+    /// `lhs op rhs` for `op` one of `+ - *` on integers, raising an error
+    /// rather than wrapping round if the result does not fit in 64 bits.
+    fn checked_int_arithmetic(
+        &self,
+        op: &str,
+        lhs: IntValue<'ctx>,
+        rhs: IntValue<'ctx>,
+    ) -> IntValue<'ctx> {
+        let name = match op {
+            "+" => "llvm.sadd.with.overflow",
+            "-" => "llvm.ssub.with.overflow",
+            _ => "llvm.smul.with.overflow",
+        };
+        let i64_type = self.context.i64_type();
+        let intrinsic = Intrinsic::find(name)
+            .and_then(|intrinsic| intrinsic.get_declaration(self.module, &[i64_type.into()]))
+            .unwrap();
+        let result = self
+            .builder
+            .build_call(intrinsic, &[lhs.into(), rhs.into()], "tmpchecked")
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_struct_value();
+        let value = self
+            .builder
+            .build_extract_value(result, 0, "tmpint")
+            .unwrap()
+            .into_int_value();
+        let overflowed = self
+            .builder
+            .build_extract_value(result, 1, "overflowed")
+            .unwrap()
+            .into_int_value();
+
+        let function = self.fn_value();
+        let overflow_bb = self.context.append_basic_block(function, "overflow");
+        let ok_bb = self.context.append_basic_block(function, "nooverflow");
+        self.builder
+            .build_conditional_branch(overflowed, overflow_bb, ok_bb);
+        self.builder.position_at_end(overflow_bb);
+        self.build_runtime_call("lisp_overflow_error", i64_type.into(), &[]);
+        self.bail();
+        self.builder.position_at_end(ok_bb);
+        value
+    }
+
+    /// `quotient`, `remainder` and `modulo`: truncating division, remainder
+    /// with the sign of the dividend, and remainder with the sign of the
+    /// divisor. Exact on integers, and defined on floats like R7RS does;
+    /// operands only known at run time go to the runtime, which keeps
+    /// integers exact the same way.
+    fn compile_integer_division(
+        &mut self,
+        op: &str,
+        args: &'a [Expr],
+    ) -> Result<CompiledValue<'ctx>, &'static str> {
+        if args.len() != 2 {
+            return Err("quotient, remainder and modulo take exactly two arguments.");
+        }
+        let (lhs, rhs) = match self.compile_numbers(args)?.as_slice() {
+            [lhs, rhs] => (*lhs, *rhs),
+            _ => unreachable!(),
+        };
+
+        let code = match op {
+            "quotient" => 0,
+            "remainder" => 1,
+            _ => 2,
+        };
+        let code = self.context.i64_type().const_int(code, false);
+
+        if let (CompiledValue::Dynamic(_), _) | (_, CompiledValue::Dynamic(_)) = (lhs, rhs) {
+            let ret = self.dynamic_type().into();
+            let (lhs, rhs) = (self.to_dynamic(lhs), self.to_dynamic(rhs));
+            return Ok(CompiledValue::Dynamic(
+                self.call_pair_runtime("lisp_dynamic_division", ret, &[code.into()], lhs, rhs)
+                    .into_struct_value(),
+            ));
+        }
+
+        if let (CompiledValue::Int(a), CompiledValue::Int(b)) = (lhs, rhs) {
+            let zero = self.context.i64_type().const_zero();
+            let b = self
+                .call_runtime(
+                    "lisp_check_divisor",
+                    b.get_type().into(),
+                    &[code.into(), a.into(), b.into()],
+                )
+                .into_int_value();
+            let result = match op {
+                "quotient" => self.builder.build_int_signed_div(a, b, "tmpquot"),
+                "remainder" => self.builder.build_int_signed_rem(a, b, "tmprem"),
+                _ => {
+                    let rem = self.builder.build_int_signed_rem(a, b, "tmprem");
+                    let nonzero =
+                        self.builder
+                            .build_int_compare(IntPredicate::NE, rem, zero, "nonzero");
+                    let sign = self.builder.build_xor(rem, b, "sign");
+                    let signs_differ =
+                        self.builder
+                            .build_int_compare(IntPredicate::SLT, sign, zero, "signs");
+                    let adjust = self.builder.build_and(nonzero, signs_differ, "adjust");
+                    let adjusted = self.builder.build_int_add(rem, b, "tmpadj");
+                    self.builder
+                        .build_select(adjust, adjusted, rem, "tmpmod")
+                        .into_int_value()
+                }
+            };
+            return Ok(CompiledValue::Int(result));
+        }
+
+        let a = self.to_float(lhs);
+        let b = self.to_float(rhs);
+        let zero = self.context.f64_type().const_zero();
+        let result = match op {
+            "quotient" => {
+                let div = self.builder.build_float_div(a, b, "tmpdiv");
+                self.call_intrinsic("llvm.trunc", &[div])?
+            }
+            "remainder" => self.builder.build_float_rem(a, b, "tmprem"),
+            _ => {
+                let rem = self.builder.build_float_rem(a, b, "tmprem");
+                let nonzero =
+                    self.builder
+                        .build_float_compare(FloatPredicate::ONE, rem, zero, "nonzero");
+                let rem_neg =
+                    self.builder
+                        .build_float_compare(FloatPredicate::OLT, rem, zero, "remneg");
+                let b_neg =
+                    self.builder
+                        .build_float_compare(FloatPredicate::OLT, b, zero, "divneg");
+                let signs_differ = self.builder.build_xor(rem_neg, b_neg, "signs");
+                let adjust = self.builder.build_and(nonzero, signs_differ, "adjust");
+                let adjusted = self.builder.build_float_add(rem, b, "tmpadj");
+                self.builder
+                    .build_select(adjust, adjusted, rem, "tmpmod")
+                    .into_float_value()
+            }
+        };
+        Ok(CompiledValue::Float(result))
+    }
+
+    /// `exact->inexact` (or `inexact`) and `inexact->exact` (or `exact`).
+    fn compile_exactness(
+        &mut self,
+        op: &str,
+        args: &'a [Expr],
+    ) -> Result<CompiledValue<'ctx>, &'static str> {
+        let arg = match self.compile_numbers(args)?.as_slice() {
+            [arg] => *arg,
+            _ => return Err("exactness conversions take exactly one argument."),
+        };
+        Ok(match op {
+            "exact->inexact" | "inexact" => self.convert(arg, Ty::F64),
+            _ if arg.ty().is_exact() => arg,
+            _ => self.convert(arg, Ty::Rational),
+        })
+    }
+
+    /// Compiles the test of an `if` to an `i1`, see `truthy`.
+    fn compile_condition(&mut self, expr: &'a Expr) -> Result<IntValue<'ctx>, &'static str> {
+        let value = self.compile_expr(expr)?;
+        Ok(self.truthy(value))
+    }
+
+    /// `(< a b c)` holds when every adjacent pair does.
+    fn compile_comparison(
+        &mut self,
+        predicates: (IntPredicate, FloatPredicate),
+        args: &'a [Expr],
+    ) -> Result<IntValue<'ctx>, &'static str> {
+        let compiled_args = self.compile_numbers(args)?;
+        let mut result: Option<IntValue<'ctx>> = None;
+
+        for pair in compiled_args.windows(2) {
+            let cmp = self.compare(predicates, pair[0], pair[1]);
+            result = Some(match result {
+                Some(acc) => self.builder.build_and(acc, cmp, "tmpand"),
+                None => cmp,
+            });
+        }
+
+        result.ok_or("comparisons require at least two arguments.")
+    }
+
+    /// Compares two numbers in the type they join to.
+    fn compare(
+        &self,
+        (int_predicate, float_predicate): (IntPredicate, FloatPredicate),
+        a: CompiledValue<'ctx>,
+        b: CompiledValue<'ctx>,
+    ) -> IntValue<'ctx> {
+        match (a, b) {
+            (CompiledValue::Int(a), CompiledValue::Int(b)) => {
+                self.builder
+                    .build_int_compare(int_predicate, a, b, "tmpcmp")
+            }
+            (a, b) if a.ty().join(b.ty()) == Ty::Dynamic => {
+                let i64_type = self.context.i64_type();
+                let code = match int_predicate {
+                    IntPredicate::EQ => 0,
+                    IntPredicate::SLT => 1,
+                    IntPredicate::SGT => 2,
+                    IntPredicate::SLE => 3,
+                    _ => 4,
+                };
+                let holds = self
+                    .call_pair_runtime(
+                        "lisp_dynamic_compare",
+                        i64_type.into(),
+                        &[i64_type.const_int(code, false).into()],
+                        self.to_dynamic(a),
+                        self.to_dynamic(b),
+                    )
+                    .into_int_value();
+                self.builder.build_int_compare(
+                    IntPredicate::NE,
+                    holds,
+                    i64_type.const_zero(),
+                    "tmpcmp",
+                )
+            }
+            // exact comparison through the runtime, against zero
+            (a, b) if a.ty().join(b.ty()) == Ty::Rational => {
+                let a = self.to_rational(a);
+                let b = self.to_rational(b);
+                let ordering = self
+                    .call_pair_runtime(
+                        "lisp_rational_compare",
+                        self.context.i64_type().into(),
+                        &[],
+                        a,
+                        b,
+                    )
+                    .into_int_value();
+                self.builder.build_int_compare(
+                    int_predicate,
+                    ordering,
+                    self.context.i64_type().const_zero(),
+                    "tmpcmp",
+                )
+            }
+            (a, b) => {
+                let a = self.to_float(a);
+                let b = self.to_float(b);
+                self.builder
+                    .build_float_compare(float_predicate, a, b, "tmpcmp")
+            }
+        }
+    }
+
+    /// `(not x)` is `#t` only for `#f`.
+    fn compile_not(&mut self, args: &'a [Expr]) -> Result<CompiledValue<'ctx>, &'static str> {
+        let arg = match args {
+            [arg] => self.compile_expr(arg)?,
+            _ => return Err("not takes exactly one argument."),
+        };
+        let truthy = self.truthy(arg);
+        Ok(CompiledValue::Bool(
+            self.builder.build_not(truthy, "tmpnot"),
+        ))
+    }
+
+    /// The one-argument predicates in `PREDICATES`. The type predicates test
+    /// the tag of a dynamic value, and fold to constants for anything else.
+    fn compile_predicate(
+        &mut self,
+        op: &str,
+        args: &'a [Expr],
+    ) -> Result<CompiledValue<'ctx>, &'static str> {
+        let arg = match args {
+            [arg] => self.compile_expr(arg)?,
+            _ => return Err("predicates take exactly one argument."),
+        };
+        let zero = CompiledValue::Int(self.context.i64_type().const_zero());
+        let result = match (op, arg) {
+            (
+                "boolean?" | "number?" | "char?" | "procedure?" | "null?" | "pair?" | "vector?"
+                | "string?" | "symbol?",
+                CompiledValue::Dynamic(v),
+            ) => {
+                let (tag, _) = self.pair_parts(v);
+                let (predicate, expected) = match op {
+                    "number?" => (IntPredicate::ULE, Tag::LAST_NUMBER),
+                    "boolean?" => (IntPredicate::EQ, Tag::Bool),
+                    "char?" => (IntPredicate::EQ, Tag::Char),
+                    "procedure?" => (IntPredicate::EQ, Tag::Procedure),
+                    "null?" => (IntPredicate::EQ, Tag::Nil),
+                    "vector?" => (IntPredicate::EQ, Tag::Vector),
+                    "string?" => (IntPredicate::EQ, Tag::String),
+                    "symbol?" => (IntPredicate::EQ, Tag::Symbol),
+                    _ => (IntPredicate::EQ, Tag::Pair),
+                };
+                self.builder
+                    .build_int_compare(predicate, tag, self.tag(expected), "tmptag")
+            }
+            ("boolean?", _) => self.const_bool(arg.ty() == Ty::Bool),
+            ("vector?", _) => self.const_bool(arg.ty() == Ty::Vector),
+            ("number?", _) => self.const_bool(arg.ty().is_number()),
+            ("char?" | "procedure?" | "null?" | "pair?" | "string?" | "symbol?", _) => {
+                self.const_bool(false)
+            }
+            _ if !arg.ty().is_number() => return Err(not_a_number(arg.ty())),
+            ("zero?", _) => self.compare((IntPredicate::EQ, FloatPredicate::OEQ), arg, zero),
+            ("positive?", _) => self.compare((IntPredicate::SGT, FloatPredicate::OGT), arg, zero),
+            ("negative?", _) => self.compare((IntPredicate::SLT, FloatPredicate::OLT), arg, zero),
+            _ => self.compile_parity(op == "even?", arg)?,
+        };
+        Ok(CompiledValue::Bool(result))
+    }
+
+    /// `even?` or `odd?`. Non-integral numbers are neither.
+    fn compile_parity(
+        &self,
+        even: bool,
+        value: CompiledValue<'ctx>,
+    ) -> Result<IntValue<'ctx>, &'static str> {
+        if let CompiledValue::Int(v) = value {
+            let i64_type = self.context.i64_type();
+            let rem = self
+                .builder
+                .build_int_signed_rem(v, i64_type.const_int(2, false), "tmprem");
+            let predicate = if even {
+                IntPredicate::EQ
+            } else {
+                IntPredicate::NE
+            };
+            return Ok(self.builder.build_int_compare(
+                predicate,
+                rem,
+                i64_type.const_zero(),
+                "tmpparity",
+            ));
+        }
+
+        let f64_type = self.context.f64_type();
+        let x = self.to_float(value);
+        let rem = self
+            .builder
+            .build_float_rem(x, f64_type.const_float(2.0), "tmprem");
+        let (rem, expected) = if even {
+            (rem, 0.0)
+        } else {
+            (self.call_intrinsic("llvm.fabs", &[rem])?, 1.0)
+        };
+        Ok(self.builder.build_float_compare(
+            FloatPredicate::OEQ,
+            rem,
+            f64_type.const_float(expected),
+            "tmpparity",
+        ))
+    }
+
+    /// `(quote datum)` for a literal, a symbol or a list of them, built out of
+    /// fresh pairs each time it is evaluated.
+    fn compile_quote(&mut self, datum: &'a Expr) -> Result<StructValue<'ctx>, &'static str> {
+        match datum {
+            Expr::List(items) => {
+                let mut list = self.const_dynamic(Tag::Nil, 0);
+                for item in items.iter().rev() {
+                    let item = self.compile_quote(item)?;
+                    list = self.cons(item, list);
+                }
+                Ok(list)
+            }
+            Expr::Symbol(name) => Ok(self.const_dynamic(Tag::Symbol, runtime::intern(name))),
+            _ => {
+                let value = self.compile_expr(datum)?;
+                Ok(self.to_dynamic(value))
+            }
+        }
+    }
+
+    fn cons(&self, car: StructValue<'ctx>, cdr: StructValue<'ctx>) -> StructValue<'ctx> {
+        self.call_pair_runtime("lisp_cons", self.dynamic_type().into(), &[], car, cdr)
+            .into_struct_value()
+    }
+
+    /// The list primitives. Pairs live on the runtime's heap, so everything
+    /// but `length` takes and returns dynamic values, and the runtime raises
+    /// an error for anything that is not a pair or list where one is needed.
+    fn compile_list_operation(
+        &mut self,
+        op: &str,
+        args: &'a [Expr],
+    ) -> Result<CompiledValue<'ctx>, &'static str> {
+        let values: Vec<StructValue<'ctx>> = self
+            .compile_args(args)?
+            .into_iter()
+            .map(|arg| self.to_dynamic(arg))
+            .collect();
+        let nil = self.const_dynamic(Tag::Nil, 0);
+        let dynamic_type: BasicTypeEnum = self.dynamic_type().into();
+        let call = |name: &str, ret: BasicTypeEnum<'ctx>, value: StructValue<'ctx>| {
+            let (tag, payload) = self.pair_parts(value);
+            self.call_runtime(name, ret, &[tag.into(), payload.into()])
+        };
+        let result = match (op, values.as_slice()) {
+            ("list", _) => values
+                .iter()
+                .rev()
+                .fold(nil, |list, value| self.cons(*value, list)),
+            // every list but the last is copied, and the last is shared
+            ("append", _) => match values.split_last() {
+                Some((last, lists)) => lists.iter().rev().fold(*last, |tail, list| {
+                    self.call_pair_runtime("lisp_append", dynamic_type, &[], *list, tail)
+                        .into_struct_value()
+                }),
+                None => nil,
+            },
+            ("cons", [car, cdr]) => self.cons(*car, *cdr),
+            ("cons", _) => return Err("cons takes exactly two arguments."),
+            ("length", [list]) => {
+                let length = call("lisp_length", self.context.i64_type().into(), *list);
+                return Ok(CompiledValue::Int(length.into_int_value()));
+            }
+            (_, [value]) => {
+                let name = match op {
+                    "car" => "lisp_car",
+                    "cdr" => "lisp_cdr",
+                    _ => "lisp_reverse",
+                };
+                call(name, dynamic_type, *value).into_struct_value()
+            }
+            _ => return Err("car, cdr, length and reverse take exactly one argument."),
+        };
+        Ok(CompiledValue::Dynamic(result))
+    }
+
+    /// A fresh string copied from a constant in the module, which does not
+    /// outlive the evaluation that compiled it.
+    fn make_string(&self, text: &str) -> StructValue<'ctx> {
+        let i64_type = self.context.i64_type();
+        let constant = self.builder.build_global_string_ptr(text, "str");
+        let address =
+            self.builder
+                .build_ptr_to_int(constant.as_pointer_value(), i64_type, "straddr");
+        let len = i64_type.const_int(text.len() as u64, false);
+        self.call_runtime(
+            "lisp_make_string",
+            self.dynamic_type().into(),
+            &[address.into(), len.into()],
+        )
+        .into_struct_value()
+    }
+
+    /// Strings and output. Strings only exist as dynamic values, and
+    /// everything but `string-length` takes and returns dynamic values.
+    fn compile_string_operation(
+        &mut self,
+        op: &str,
+        args: &'a [Expr],
+    ) -> Result<CompiledValue<'ctx>, &'static str> {
+        let values: Vec<StructValue<'ctx>> = self
+            .compile_args(args)?
+            .into_iter()
+            .map(|arg| self.to_dynamic(arg))
+            .collect();
+        let dynamic_type: BasicTypeEnum = self.dynamic_type().into();
+        let result = match (op, values.as_slice()) {
+            ("newline", []) => self.call_runtime("lisp_newline", dynamic_type, &[]),
+            ("string-append", _) => {
+                let empty = self.make_string("");
+                let appended = values.iter().fold(empty, |appended, value| {
+                    self.call_pair_runtime(
+                        "lisp_string_append",
+                        dynamic_type,
+                        &[],
+                        appended,
+                        *value,
+                    )
+                    .into_struct_value()
+                });
+                appended.into()
+            }
+            ("string-length", [value]) => {
+                let (tag, payload) = self.pair_parts(*value);
+                let length = self.call_runtime(
+                    "lisp_string_length",
+                    self.context.i64_type().into(),
+                    &[tag.into(), payload.into()],
+                );
+                return Ok(CompiledValue::Int(length.into_int_value()));
+            }
+            ("newline", _) => return Err("newline takes no arguments."),
+            (_, [value]) => {
+                let name = match op {
+                    "display" => "lisp_display",
+                    "write" => "lisp_write",
+                    "number->string" => "lisp_number_to_string",
+                    _ => "lisp_string_to_number",
+                };
+                let (tag, payload) = self.pair_parts(*value);
+                self.call_runtime(name, dynamic_type, &[tag.into(), payload.into()])
+            }
+            _ => return Err("this string operation takes exactly one argument."),
+        };
+        Ok(CompiledValue::Dynamic(result.into_struct_value()))
+    }
+
+    /// A fresh vector holding `elements`, which must be numbers.
+    fn compile_vector(
+        &self,
+        elements: Vec<CompiledValue<'ctx>>,
+    ) -> Result<StructValue<'ctx>, &'static str> {
+        let i64_type = self.context.i64_type();
+        let len = i64_type.const_int(elements.len() as u64, false);
+        let vector = self.allocate_vector(len, self.context.f64_type().const_zero());
+        let (data, _) = self.vector_parts(vector);
+        for (i, element) in elements.into_iter().enumerate() {
+            let x = self.element(element)?;
+            let index = i64_type.const_int(i as u64, false);
+            let address = unsafe { self.builder.build_in_bounds_gep(data, &[index], "elt") };
+            self.builder.build_store(address, x);
+        }
+        Ok(vector)
+    }
+
+    fn allocate_vector(&self, len: IntValue<'ctx>, fill: FloatValue<'ctx>) -> StructValue<'ctx> {
+        let data = self.call_runtime(
+            "lisp_make_vector",
+            self.context.i64_type().into(),
+            &[len.into(), fill.into()],
+        );
+        self.vector_at(data.into_int_value())
+    }
+
+    fn element(&self, value: CompiledValue<'ctx>) -> Result<FloatValue<'ctx>, &'static str> {
+        match value.ty() {
+            ty if ty.is_number() => Ok(self.to_float(value)),
+            ty => Err(not_a_number(ty)),
+        }
+    }
+
+    /// The vector primitives. Elements are always f64, and an index is
+    /// checked against the length before every load or store, which are
+    /// plain ones through a `double*` otherwise.
+    fn compile_vector_operation(
+        &mut self,
+        op: &str,
+        args: &'a [Expr],
+    ) -> Result<CompiledValue<'ctx>, &'static str> {
+        let values = self.compile_args(args)?;
+        if op == "vector" {
+            return self.compile_vector(values).map(CompiledValue::Vector);
+        }
+        if op == "make-vector" {
+            let (len, fill) = match values.as_slice() {
+                [len] => (*len, self.context.f64_type().const_zero()),
+                [len, fill] => (*len, self.element(*fill)?),
+                _ => return Err("make-vector takes a length and an optional fill."),
+            };
+            let len = self.index(len)?;
+            return Ok(CompiledValue::Vector(self.allocate_vector(len, fill)));
+        }
+
+        let (vector, rest) = values
+            .split_first()
+            .ok_or("vector operations take a vector first.")?;
+        let vector = match vector {
+            CompiledValue::Vector(v) => *v,
+            CompiledValue::Dynamic(_) => match self.convert(*vector, Ty::Vector) {
+                CompiledValue::Vector(v) => v,
+                _ => unreachable!(),
+            },
+            _ => return Err("expected a vector."),
+        };
+        let (data, len) = self.vector_parts(vector);
+        let (index, value) = match (op, rest) {
+            ("vector-length", []) => return Ok(CompiledValue::Int(len)),
+            ("vector-ref", [index]) => (self.index(*index)?, None),
+            ("vector-set!", [index, value]) => (self.index(*index)?, Some(self.element(*value)?)),
+            ("vector-length", _) => return Err("vector-length takes exactly one argument."),
+            ("vector-ref", _) => return Err("vector-ref takes a vector and an index."),
+            _ => return Err("vector-set! takes a vector, an index and a value."),
+        };
+
+        let function = self.fn_value();
+        let in_bounds_bb = self.context.append_basic_block(function, "inbounds");
+        let out_of_bounds_bb = self.context.append_basic_block(function, "outofbounds");
+        // unsigned, so that a negative index is out of bounds too
+        let in_bounds = self
+            .builder
+            .build_int_compare(IntPredicate::ULT, index, len, "inbounds");
+        self.builder
+            .build_conditional_branch(in_bounds, in_bounds_bb, out_of_bounds_bb);
+
+        self.builder.position_at_end(out_of_bounds_bb);
+        self.build_runtime_call(
+            "lisp_index_error",
+            self.context.f64_type().into(),
+            &[index.into(), len.into()],
+        );
+        self.bail();
+
+        self.builder.position_at_end(in_bounds_bb);
+        let address = unsafe { self.builder.build_in_bounds_gep(data, &[index], "elt") };
+        Ok(CompiledValue::Float(match value {
+            Some(x) => {
+                self.builder.build_store(address, x);
+                x
+            }
+            None => self.builder.build_load(address, "elt").into_float_value(),
+        }))
+    }
+
+    /// A vector length or index, which must be an integer.
+    fn index(&self, value: CompiledValue<'ctx>) -> Result<IntValue<'ctx>, &'static str> {
+        match value {
+            CompiledValue::Int(v) => Ok(v),
+            CompiledValue::Dynamic(_) => {
+                Ok(self.convert(value, Ty::I64).as_basic().into_int_value())
+            }
+            _ => Err("a vector index must be an integer."),
+        }
+    }
+
+    /// Binds `name` to a new local variable of type `ty` holding `value`,
+    /// returning whatever it shadows, for `unbind`.
+    fn bind(
+        &mut self,
+        name: &str,
+        ty: Ty,
+        value: BasicValueEnum<'ctx>,
+    ) -> Option<(PointerValue<'ctx>, Ty)> {
+        let alloca = self.create_entry_block_alloca(name, ty);
+        self.builder.build_store(alloca, value);
+        let shadowed = self.locals.insert(name.to_string(), alloca);
+        shadowed.zip(self.local_tys.insert(name.to_string(), ty))
+    }
+
+    /// Undoes `bind`, given what it returned.
+    fn unbind(&mut self, name: &str, shadowed: Option<(PointerValue<'ctx>, Ty)>) {
+        match shadowed {
+            Some((alloca, ty)) => {
+                self.locals.insert(name.to_string(), alloca);
+                self.local_tys.insert(name.to_string(), ty);
+            }
+            None => {
+                self.locals.remove(name);
+                self.local_tys.remove(name);
+            }
+        }
+    }
+
+    /// `(let ((var init) ...) body...)`, or a named let, see
+    /// `compile_named_let`. Every `init` is evaluated before any of the
+    /// variables is bound.
+    fn compile_let(
+        &mut self,
+        args: &'a [Expr],
+        tail: Tail,
+    ) -> Result<CompiledValue<'ctx>, &'static str> {
+        let (bindings, body) = match args {
+            [Expr::Symbol(name), Expr::List(bindings), body @ ..] => {
+                return self.compile_named_let(name, bindings, body, tail)
+            }
+            [Expr::List(bindings), body @ ..] => (bindings, body),
+            _ => return Err("let requires a list of bindings and a body."),
+        };
+        let (vars, inits) = let_bindings(bindings)?;
+        let mut values = vec![];
+        for init in inits {
+            values.push(self.compile_expr(init)?);
+        }
+        let env = local_env(&self.local_tys);
+        let tys = self.inference.scope_types(
+            &vars,
+            values.iter().map(CompiledValue::ty).collect(),
+            body,
+            &env,
+        );
+        let shadowed: Vec<_> = vars
+            .iter()
+            .zip(tys)
+            .zip(values)
+            .map(|((var, ty), value)| {
+                let value = self.convert(value, ty);
+                self.bind(var, ty, value.as_basic())
+            })
+            .collect();
+        let value = self.compile_sequence(body, tail)?;
+        for (var, shadowed) in vars.iter().zip(shadowed).rev() {
+            self.unbind(var, shadowed);
+        }
+        Ok(value)
+    }
+
+    /// Scheme's named let, `(let name ((var init) ...) body...)`: the body
+    /// runs with each `var` bound to its `init`, and calling `name` from its
+    /// tail position runs it again with the variables bound to the
+    /// arguments instead. That compiles to a jump back to the top of a loop,
+    /// not a call; a named let that calls `name` from anywhere else has
+    /// been lifted to a function of its own, see `lift`.
+    fn compile_named_let(
+        &mut self,
+        name: &str,
+        bindings: &'a [Expr],
+        body: &'a [Expr],
+        tail: Tail,
+    ) -> Result<CompiledValue<'ctx>, &'static str> {
+        let (vars, inits) = let_bindings(bindings)?;
+        let mut values = vec![];
+        for init in inits {
+            values.push(self.compile_expr(init)?);
+        }
+        let env = local_env(&self.local_tys);
+        let tys = self.inference.named_let_types(
+            name,
+            &vars,
+            values.iter().map(CompiledValue::ty).collect(),
+            body,
+            &env,
+        );
+        let mut scope = env.clone();
+        scope.extend(vars.iter().copied().zip(tys.iter().copied()));
+        let result = self
+            .inference
+            .infer_sequence(body, &scope)
+            .unwrap_or(Ty::Dynamic);
+
+        let (header, phis, shadowed) = self.enter_loop(&vars, &tys, values);
+        self.loops.push(NamedLoop {
+            name: name.to_string(),
+            header,
+            phis,
+            tys,
+            result,
+        });
+        let body_tail = match tail {
+            Tail::No => Tail::Loop(self.loops.len() - 1),
+            tail => tail,
+        };
+        let value = self.compile_sequence(body, body_tail)?;
+        self.loops.pop();
+        for (var, shadowed) in vars.iter().zip(shadowed).rev() {
+            self.unbind(var, shadowed);
+        }
+        Ok(value)
+    }
+
+    /// `(do ((var init step) ...) (test result...) body...)`: binds each
+    /// `var` to its `init`, then until `test` holds runs the body and
+    /// rebinds every variable that has a `step` to its value, computing them
+    /// all before rebinding any. Returns the value of the last `result`.
+    fn compile_do(
+        &mut self,
+        args: &'a [Expr],
+        tail: Tail,
+    ) -> Result<CompiledValue<'ctx>, &'static str> {
+        let (specs, test, results, body) = do_parts(args)?;
+        let vars: Vec<&str> = specs.iter().map(|(var, ..)| *var).collect();
+        let mut values = vec![];
+        for &(_, init, _) in &specs {
+            values.push(self.compile_expr(init)?);
+        }
+        let steps: Vec<(usize, &Expr)> = specs
+            .iter()
+            .enumerate()
+            .filter_map(|(i, (_, _, step))| step.map(|step| (i, step)))
+            .collect();
+        let env = local_env(&self.local_tys);
+        let tys = self.inference.loop_types(
+            &vars,
+            values.iter().map(CompiledValue::ty).collect(),
+            &steps,
+            &do_scope(test, body, results, &steps),
+            &env,
+        );
+
+        let (header, phis, shadowed) = self.enter_loop(&vars, &tys, values);
+        let function = self.fn_value();
+        let cond = self.compile_condition(test)?;
+        let body_bb = self.context.append_basic_block(function, "dobody");
+        let exit_bb = self.context.append_basic_block(function, "doexit");
+        self.builder
+            .build_conditional_branch(cond, exit_bb, body_bb);
+
+        self.builder.position_at_end(body_bb);
+        for expr in body {
+            self.compile_expr(expr)?;
+        }
+        let mut next = vec![];
+        for &(var, _, step) in &specs {
+            next.push(match step {
+                Some(step) => self.compile_expr(step)?,
+                // whatever the body left it as
+                None => self.builder.build_load(self.locals[var], var).into(),
+            });
+        }
+        self.continue_loop(header, &phis, &tys, next)?;
+
+        self.builder.position_at_end(exit_bb);
+        let value = if results.is_empty() {
+            CompiledValue::Dynamic(self.const_dynamic(Tag::Unspecified, 0))
+        } else {
+            self.compile_sequence(results, tail)?
+        };
+        for (var, shadowed) in vars.iter().zip(shadowed).rev() {
+            self.unbind(var, shadowed);
+        }
+        Ok(value)
+    }
+
+    /// `(while test body...)`: runs the body for as long as `test` holds.
+    fn compile_while(&mut self, args: &'a [Expr]) -> Result<CompiledValue<'ctx>, &'static str> {
+        let (test, body) = args
+            .split_first()
+            .ok_or("while requires a test and a body.")?;
+        let function = self.fn_value();
+        let header = self.context.append_basic_block(function, "while");
+        self.builder.build_unconditional_branch(header);
+        self.builder.position_at_end(header);
+        let cond = self.compile_condition(test)?;
+        let body_bb = self.context.append_basic_block(function, "whilebody");
+        let exit_bb = self.context.append_basic_block(function, "whileexit");
+        self.builder
+            .build_conditional_branch(cond, body_bb, exit_bb);
+
+        self.builder.position_at_end(body_bb);
+        for expr in body {
+            self.compile_expr(expr)?;
+        }
+        self.builder.build_unconditional_branch(header);
+
+        self.builder.position_at_end(exit_bb);
+        Ok(CompiledValue::Dynamic(
+            self.const_dynamic(Tag::Unspecified, 0),
+        ))
+    }
+
+    /// Starts a loop over variables `vars` of types `tys`, which start out
+    /// as `values`: branches to a new header block whose phi nodes carry the
+    /// variables round the loop, and binds each variable to a local holding
+    /// its phi's value, so that the body can `set!` it. Returns the header,
+    /// the phis and what the variables shadow.
+    #[allow(clippy::type_complexity)]
+    fn enter_loop(
+        &mut self,
+        vars: &[&str],
+        tys: &[Ty],
+        values: Vec<CompiledValue<'ctx>>,
+    ) -> (
+        BasicBlock<'ctx>,
+        Vec<PhiValue<'ctx>>,
+        Vec<Option<(PointerValue<'ctx>, Ty)>>,
+    ) {
+        // the types were widened from the values', so they always fit
+        let values: Vec<BasicValueEnum> = values
+            .into_iter()
+            .zip(tys)
+            .map(|(value, ty)| self.convert(value, *ty).as_basic())
+            .collect();
+        let preheader = self.builder.get_insert_block().unwrap();
+        let header = self.context.append_basic_block(self.fn_value(), "loop");
+        self.builder.build_unconditional_branch(header);
+        self.builder.position_at_end(header);
+
+        let mut phis = vec![];
+        for ((var, ty), value) in vars.iter().zip(tys).zip(&values) {
+            let phi = self.builder.build_phi(self.basic_type(*ty), var);
+            phi.add_incoming(&[(value, preheader)]);
+            phis.push(phi);
+        }
+        let shadowed = vars
+            .iter()
+            .zip(tys)
+            .zip(&phis)
+            .map(|((var, ty), phi)| self.bind(var, *ty, phi.as_basic_value()))
+            .collect();
+        (header, phis, shadowed)
+    }
+
+    /// Goes round a loop started by `enter_loop` again, with the variables
+    /// bound to `values`.
+    fn continue_loop(
+        &mut self,
+        header: BasicBlock<'ctx>,
+        phis: &[PhiValue<'ctx>],
+        tys: &[Ty],
+        values: Vec<CompiledValue<'ctx>>,
+    ) -> Result<(), &'static str> {
+        let mut converted = vec![];
+        for (value, ty) in values.into_iter().zip(tys) {
+            if !ty.accepts(value.ty()) {
+                return Err("a loop variable cannot hold the value it is rebound to.");
+            }
+            converted.push(self.convert(value, *ty).as_basic());
+        }
+        let latch = self.builder.get_insert_block().unwrap();
+        for (phi, value) in phis.iter().zip(&converted) {
+            phi.add_incoming(&[(value, latch)]);
+        }
+        self.builder.build_unconditional_branch(header);
+        Ok(())
+    }
+
+    /// Carries on after a tail call, which has already returned or jumped,
+    /// in a new block that nothing branches to. The enclosing forms finish
+    /// building there around a placeholder of type `ty`, what the call would
+    /// otherwise have produced, that is never used.
+    fn after_tail_call(&self, ty: Ty) -> CompiledValue<'ctx> {
+        let block = self
+            .context
+            .append_basic_block(self.fn_value(), "aftertail");
+        self.builder.position_at_end(block);
+        self.placeholder(ty).into()
+    }
+
+    fn compile_if(
+        &mut self,
+        args: &'a [Expr],
+        tail: Tail,
+    ) -> Result<CompiledValue<'ctx>, &'static str> {
+        if args.len() != 3 {
+            return Err("if requires a test, a consequent and an alternative.");
+        }
+
+        let cond = self.compile_condition(&args[0])?;
+
+        let function = self.fn_value();
+        let then_bb = self.context.append_basic_block(function, "then");
+        let else_bb = self.context.append_basic_block(function, "else");
+        let merge_bb = self.context.append_basic_block(function, "ifcont");
+
+        self.builder
+            .build_conditional_branch(cond, then_bb, else_bb);
+
+        // compile both branches before branching to the merge block, since
+        // one of them may need promoting to the type of the other
+        self.builder.position_at_end(then_bb);
+        self.tail = tail;
+        let then_val = self.compile_expr(&args[1])?;
+        let then_end = self.builder.get_insert_block().unwrap();
+
+        self.builder.position_at_end(else_bb);
+        self.tail = tail;
+        let else_val = self.compile_expr(&args[2])?;
+        let else_end = self.builder.get_insert_block().unwrap();
+
+        let ty = then_val.ty().join(else_val.ty());
+
+        // converting can call the runtime, which can end the block
+        self.builder.position_at_end(then_end);
+        let then_val = self.convert(then_val, ty).as_basic();
+        let then_end = self.builder.get_insert_block().unwrap();
+        self.builder.build_unconditional_branch(merge_bb);
+
+        self.builder.position_at_end(else_end);
+        let else_val = self.convert(else_val, ty).as_basic();
+        let else_end = self.builder.get_insert_block().unwrap();
+        self.builder.build_unconditional_branch(merge_bb);
+
+        self.builder.position_at_end(merge_bb);
+        let phi = self.builder.build_phi(self.basic_type(ty), "iftmp");
+        phi.add_incoming(&[(&then_val, then_end), (&else_val, else_end)]);
+
+        Ok(phi.as_basic_value().into())
+    }
+
+    /// Calls the LLVM intrinsic `name`, declared for doubles.
+    fn call_intrinsic(
+        &self,
+        name: &str,
+        args: &[FloatValue<'ctx>],
+    ) -> Result<FloatValue<'ctx>, &'static str> {
+        let intrinsic = Intrinsic::find(name).ok_or("no function found with that name")?;
+
+        // overloaded math intrinsics are declared for a single type, however
+        // many operands they take
+        let double_type = self.context.f64_type();
+        let overloads: Vec<BasicTypeEnum> = if intrinsic.is_overloaded() {
+            vec![double_type.into()]
+        } else {
+            vec![]
+        };
+        let intrinsic_function = intrinsic
+            .get_declaration(self.module, &overloads)
+            .ok_or("could not declare intrinsic")?;
+
+        let compiled_args: Vec<BasicMetadataValueEnum> =
+            args.iter().map(|arg| (*arg).into()).collect();
+        Ok(self
+            .builder
+            .build_call(
+                intrinsic_function,
+                compiled_args.as_slice(),
+                "intrinsic_call",
+            )
+            .try_as_basic_value()
+            .left()
+            .ok_or("intrinsic does not return a value")?
+            .into_float_value())
+    }
+
+    fn compile_call(
+        &mut self,
+        op: &str,
+        args: &'a [Expr],
+        tail: Tail,
+    ) -> Result<CompiledValue<'ctx>, &'static str> {
+        let compiled_args = self.compile_args(args)?;
+        let tys: Vec<Ty> = compiled_args.iter().map(CompiledValue::ty).collect();
+
+        // going round a named let again
+        if let Some(index) = self.loops.iter().rposition(|named| named.name == op) {
+            let in_tail = match tail {
+                Tail::Function => true,
+                Tail::Loop(outermost) => outermost <= index,
+                Tail::No => false,
+            };
+            if !in_tail {
+                return Err("a named let can only be called from tail position in its body.");
+            }
+            let named = self.loops[index].clone();
+            if compiled_args.len() != named.phis.len() {
+                return Err("wrong number of arguments");
+            }
+            self.continue_loop(named.header, &named.phis, &named.tys, compiled_args)?;
+            return Ok(self.after_tail_call(named.result));
+        }
+
+        // a parameter or global variable holding a procedure; parameters
+        // shadow definitions, which shadow global variables
+        if self.locals.contains_key(op) || !self.definitions.contains_key(op) {
+            if let Some(var) = self.lookup_variable(op) {
+                let callee = self.builder.build_load(var, op).into();
+                return Ok(self.compile_dynamic_call(callee, compiled_args, tail));
+            }
+        }
+
+        match self.get_or_specialize(op, &tys)? {
+            Some(f) => {
+                let params = param_tys(f);
+                if params.len() != compiled_args.len() {
+                    return Err("wrong number of arguments");
+                }
+                if !params
+                    .iter()
+                    .zip(&tys)
+                    .all(|(param, ty)| param.accepts(*ty))
+                {
+                    return Err("an argument does not match the declared parameter type.");
+                }
+                let compiled_args: Vec<CompiledValue> = compiled_args
+                    .into_iter()
+                    .zip(params)
+                    .map(|(arg, ty)| self.convert(arg, ty))
+                    .collect();
+
+                // a tail call must return what this function does, so one to
+                // a function returning something else calls a version of it
+                // that converts its result itself
+                let own = return_ty(self.fn_value());
+                let f = if tail == Tail::Function
+                    && return_ty(f) != own
+                    && own.accepts(return_ty(f))
+                    && self.definitions.contains_key(op)
+                {
+                    self.get_or_specialize_returning(op, &tys, own)?
+                } else {
+                    f
+                };
+
+                // a self tail call is a loop: rebind the parameters and
+                // start the body over
+                if let Some((body, params)) = self.self_loop.clone() {
+                    if tail == Tail::Function && f == self.fn_value() {
+                        for ((param, ty), arg) in params.into_iter().zip(compiled_args) {
+                            let arg = self.convert(arg, ty);
+                            self.builder.build_store(param, arg.as_basic());
+                        }
+                        self.builder.build_unconditional_branch(body);
+                        return Ok(self.after_tail_call(return_ty(f)));
+                    }
+                }
+
+                let compiled_args: Vec<BasicMetadataValueEnum> = compiled_args
+                    .iter()
+                    .map(|arg| arg.as_basic().into())
+                    .collect();
+                let call = self
+                    .builder
+                    .build_call(f, compiled_args.as_slice(), "tmpcall");
+                call.set_call_convention(f.get_call_conventions());
+                let result = call.try_as_basic_value().left().unwrap();
+                if tail == Tail::Function
+                    && f.get_call_conventions() == TAIL_CALL_CONVENTION
+                    && return_ty(f) == own
+                {
+                    call.set_tail_call(true);
+                    self.builder.build_return(Some(&result));
+                    return Ok(self.after_tail_call(return_ty(f)));
+                }
+                self.check_error();
+                Ok(result.into())
+            }
+            None => {
+                if let Some(ty) = tys.iter().find(|ty| !ty.is_number()) {
+                    return Err(not_a_number(*ty));
+                }
+                let floats: Vec<FloatValue<'ctx>> = compiled_args
+                    .into_iter()
+                    .map(|arg| self.to_float(arg))
+                    .collect();
+                self.call_intrinsic(op, &floats).map(CompiledValue::Float)
+            }
+        }
+    }
+
+    /// Calls a procedure value, which must take as many arguments as it is
+    /// given. If it is not a procedure, or takes a different number of
+    /// arguments, the runtime raises an error and the call returns. Its code
+    /// is always an entry point, see `compile_entry`, so from tail position
+    /// in a function that returns a dynamic value this is a tail call.
+    fn compile_dynamic_call(
+        &self,
+        callee: CompiledValue<'ctx>,
+        args: Vec<CompiledValue<'ctx>>,
+        tail: Tail,
+    ) -> CompiledValue<'ctx> {
+        let i64_type = self.context.i64_type();
+        let (tag, payload) = self.pair_parts(self.to_dynamic(callee));
+        let nargs = i64_type.const_int(args.len() as u64, false);
+        let code = self
+            .call_runtime(
+                "lisp_check_procedure",
+                i64_type.into(),
+                &[tag.into(), payload.into(), nargs.into()],
+            )
+            .into_int_value();
+        let args: Vec<BasicMetadataValueEnum> = args
+            .into_iter()
+            .map(|arg| self.to_dynamic(arg).into())
+            .collect();
+
+        let dynamic_type = self.dynamic_type();
+        let params: Vec<BasicMetadataTypeEnum> = vec![dynamic_type.into(); args.len()];
+        let fn_type = dynamic_type.fn_type(&params, false);
+        let pointer =
+            self.builder
+                .build_int_to_ptr(code, fn_type.ptr_type(AddressSpace::default()), "code");
+        let callee = CallableValue::try_from(pointer).unwrap();
+        let call = self.builder.build_call(callee, &args, "tmpcall");
+        call.set_call_convention(TAIL_CALL_CONVENTION);
+        let result = call.try_as_basic_value().left().unwrap();
+        if tail == Tail::Function && return_ty(self.fn_value()) == Ty::Dynamic {
+            call.set_tail_call(true);
+            self.builder.build_return(Some(&result));
+            return self.after_tail_call(Ty::Dynamic);
+        }
+        self.check_error();
+        CompiledValue::Dynamic(result.into_struct_value())
+    }
+
+    /// The function `name` as a procedure value. It points to an entry
+    /// point taking and returning dynamic values, so that any caller can
+    /// call it without knowing its types.
+    fn compile_procedure(&mut self, name: &str) -> Result<CompiledValue<'ctx>, &'static str> {
+        let def = self.definitions[name];
+        let (_, params, _) = function_parts(def)?.unwrap();
+        let entry_name = format!("{}.procedure", name);
+        let entry = match self.get_function(&entry_name) {
+            Some(f) => f,
+            None => self.compile_entry(name, &entry_name, params.len())?,
+        };
+
+        let i64_type = self.context.i64_type();
+        let code = self.builder.build_ptr_to_int(
+            entry.as_global_value().as_pointer_value(),
+            i64_type,
+            "code",
+        );
+        let arity = i64_type.const_int(params.len() as u64, false);
+        Ok(CompiledValue::Dynamic(
+            self.call_runtime(
+                "lisp_make_procedure",
+                self.dynamic_type().into(),
+                &[code.into(), arity.into()],
+            )
+            .into_struct_value(),
+        ))
+    }
+
+    /// Compiles `entry_name`, which unboxes its dynamic arguments to call the
+    /// version of `name` for its declared parameter types (dynamic where
+    /// undeclared) that boxes its own result, as a tail call. Procedure calls
+    /// are `tailcc` calls to entries like this one.
+    fn compile_entry(
+        &mut self,
+        name: &str,
+        entry_name: &str,
+        arity: usize,
+    ) -> Result<FunctionValue<'ctx>, &'static str> {
+        let tys = vec![Ty::Dynamic; arity];
+        let target = self.get_or_specialize_returning(name, &tys, Ty::Dynamic)?;
+        // compiling it may have needed the procedure already
+        if let Some(entry) = self.get_function(entry_name) {
+            return Ok(entry);
+        }
+
+        let dynamic_type = self.dynamic_type();
+        let params: Vec<BasicMetadataTypeEnum> = vec![dynamic_type.into(); arity];
+        let entry =
+            self.module
+                .add_function(entry_name, dynamic_type.fn_type(&params, false), None);
+        entry.set_call_conventions(TAIL_CALL_CONVENTION);
+
+        let saved_block = self.builder.get_insert_block();
+        let saved_fn = self.fn_value_opt.replace(entry);
+        let block = self.context.append_basic_block(entry, "entry");
+        self.builder.position_at_end(block);
+
+        let args: Vec<BasicMetadataValueEnum> = entry
+            .get_param_iter()
+            .zip(param_tys(target))
+            .map(|(arg, ty)| self.convert(arg.into(), ty).as_basic().into())
+            .collect();
+        let call = self.builder.build_call(target, &args, "tmpcall");
+        call.set_call_convention(target.get_call_conventions());
+        call.set_tail_call(true);
+        let result = call.try_as_basic_value().left().unwrap();
+        self.builder.build_return(Some(&result));
+
+        self.fn_value_opt = saved_fn;
+        if let Some(block) = saved_block {
+            self.builder.position_at_end(block);
+        }
+
+        if entry.verify(true) {
+            self.fpm.run_on(&entry);
+            Ok(entry)
+        } else {
+            unsafe {
+                entry.delete();
+            }
+            Err("Invalid generated function.")
+        }
+    }
+
+    /// Finds the version of function `name` to call with arguments of types
+    /// `tys`, compiling it from its definition the first time it is needed.
+    /// See `Inference::specialization`.
+    fn get_or_specialize(
+        &mut self,
+        name: &str,
+        tys: &[Ty],
+    ) -> Result<Option<FunctionValue<'ctx>>, &'static str> {
+        let (mangled, tys) = self.inference.specialization(name, tys);
+        if let Some(f) = self.get_function(&mangled) {
+            return Ok(Some(f));
+        }
+
+        match self.definitions.get(name).copied() {
+            Some(def) => {
+                let (_, params, body) = function_parts(def)?.unwrap();
+                if params.len() != tys.len() {
+                    return Err("wrong number of arguments");
+                }
+                if let Some(signature) = self.inference.signature(name) {
+                    if signature.params.len() != params.len() {
+                        return Err("the declared type has the wrong number of parameters.");
+                    }
+                }
+                let ret = self
+                    .inference
+                    .return_type(name, &tys)
+                    .unwrap_or(Ty::Dynamic);
+                let in_definition = std::mem::replace(&mut self.in_definition, true);
+                let function = self.compile_function(&mangled, &params, &tys, body, ret);
+                self.in_definition = in_definition;
+                function.map(Some)
+            }
+            // declared without a definition we could specialise, so arguments
+            // are converted to whatever it takes
+            None => Ok(self.get_function(name)),
+        }
+    }
+
+    /// Compiles `c_name(name)`, which calls `function` with the C calling
+    /// convention, for C code to call through the declarations
+    /// `printer::c_header` writes. It takes one more argument than
+    /// `function`, where it hands over the error the call raised, see
+    /// `runtime::lisp_report_error`, and passes booleans zero-extended as C
+    /// does.
+    fn compile_c_entry(
+        &mut self,
+        name: &str,
+        function: FunctionValue<'ctx>,
+    ) -> Result<(), &'static str> {
+        let entry_name = c_name(name);
+        if self.get_function(&entry_name).is_some() {
+            return Ok(());
+        }
+        let fn_type = function.get_type();
+        let error_type = self
+            .context
+            .i8_type()
+            .ptr_type(AddressSpace::default())
+            .ptr_type(AddressSpace::default());
+        let mut params: Vec<BasicMetadataTypeEnum> = fn_type
+            .get_param_types()
+            .into_iter()
+            .map(BasicMetadataTypeEnum::from)
+            .collect();
+        params.push(error_type.into());
+        let ret = fn_type.get_return_type().unwrap();
+        let entry = self
+            .module
+            .add_function(&entry_name, ret.fn_type(&params, false), None);
+        let zeroext = self
+            .context
+            .create_enum_attribute(Attribute::get_named_enum_kind_id("zeroext"), 0);
+        let is_bool =
+            |ty: BasicTypeEnum| matches!(ty, BasicTypeEnum::IntType(ty) if ty.get_bit_width() == 1);
+        if is_bool(ret) {
+            entry.add_attribute(AttributeLoc::Return, zeroext);
+        }
+        for (index, ty) in fn_type.get_param_types().into_iter().enumerate() {
+            if is_bool(ty) {
+                entry.add_attribute(AttributeLoc::Param(index as u32), zeroext);
+            }
+        }
+
+        let saved_block = self.builder.get_insert_block();
+        let block = self.context.append_basic_block(entry, "entry");
+        self.builder.position_at_end(block);
+        let mut args: Vec<BasicMetadataValueEnum> =
+            entry.get_param_iter().map(|arg| arg.into()).collect();
+        args.pop();
+        let error = entry.get_last_param().unwrap();
+        let call = self.builder.build_call(function, &args, "tmpcall");
+        call.set_call_convention(function.get_call_conventions());
+        let result = call.try_as_basic_value().left().unwrap();
+        // C code cannot check for a pending error, so it is handed over
+        self.build_runtime_call(
+            "lisp_report_error",
+            self.context.i64_type().into(),
+            &[error],
+        );
+        self.builder.build_return(Some(&result));
+        if let Some(block) = saved_block {
+            self.builder.position_at_end(block);
+        }
+
+        if entry.verify(true) {
+            self.fpm.run_on(&entry);
+            Ok(())
+        } else {
+            unsafe {
+                entry.delete();
+            }
+            Err("Invalid generated function.")
+        }
+    }
+
+    /// The version of function `name` for arguments of types `tys` that
+    /// returns `ret`, converting whatever its body produces: the one
+    /// `get_or_specialize` finds if that already returns `ret`, or else one
+    /// compiled for the purpose, for a tail call from a function returning
+    /// `ret`, see `compile_call`.
+    fn get_or_specialize_returning(
+        &mut self,
+        name: &str,
+        tys: &[Ty],
+        ret: Ty,
+    ) -> Result<FunctionValue<'ctx>, &'static str> {
+        let function = self
+            .get_or_specialize(name, tys)?
+            .ok_or("Could not find a matching function.")?;
+        if return_ty(function) == ret {
+            return Ok(function);
+        }
+        let (mangled, tys) = self.inference.specialization(name, tys);
+        let mangled = format!("{}.returning.{}", mangled, ret);
+        if let Some(f) = self.get_function(&mangled) {
+            return Ok(f);
+        }
+        let def = self
+            .definitions
+            .get(name)
+            .copied()
+            .ok_or("Could not find a matching function.")?;
+        let (_, params, body) = function_parts(def)?.unwrap();
+        let in_definition = std::mem::replace(&mut self.in_definition, true);
+        let function = self.compile_function(&mangled, &params, &tys, body, ret);
+        self.in_definition = in_definition;
+        function
+    }
+
+    /// Compiles the specified `Prototype` into an extern LLVM `FunctionValue`.
+    /// nargs is the number of arguments the function takes. not the number of arguments in the List
+    fn compile_prototype(
+        &self,
+        name: &str,
+        arg_names: &[String],
+        arg_tys: &[Ty],
+        ret: Ty,
+    ) -> Result<FunctionValue<'ctx>, &'static str> {
+        let args_types = arg_tys
+            .iter()
+            .map(|ty| self.basic_type(*ty).into())
+            .collect::<Vec<BasicMetadataTypeEnum>>();
+        let args_types = args_types.as_slice();
+
+        let fn_type = self.basic_type(ret).fn_type(args_types, false);
+        let fn_val = self.module.add_function(name, fn_type, None);
+
+        // set arguments names
+        for (i, arg) in fn_val.get_param_iter().enumerate() {
+            match arg {
+                BasicValueEnum::FloatValue(v) => v.set_name(&arg_names[i]),
+                BasicValueEnum::IntValue(v) => v.set_name(&arg_names[i]),
+                BasicValueEnum::StructValue(v) => v.set_name(&arg_names[i]),
+                _ => {}
+            }
+        }
+
+        // finally return built prototype
+        Ok(fn_val)
+    }
+
+    /// Compiles `body` as function `name` taking `params` of types `tys` and
+    /// returning `ret`. This can happen while another function is half built,
+    /// when a call needs a new specialisation, so the builder position,
+    /// current function and locals are saved and restored around it.
+    fn compile_function(
+        &mut self,
+        name: &str,
+        params: &[String],
+        tys: &[Ty],
+        body: &'a [Expr],
+        ret: Ty,
+    ) -> Result<FunctionValue<'ctx>, &'static str> {
+        let function = self.compile_prototype(name, params, tys, ret)?;
+        if self.in_definition {
+            function.set_call_conventions(TAIL_CALL_CONVENTION);
+        }
+
+        let saved_block = self.builder.get_insert_block();
+        let saved_fn = self.fn_value_opt.replace(function);
+        let saved_locals = std::mem::take(&mut self.locals);
+        let saved_tys = std::mem::take(&mut self.local_tys);
+        let saved_loops = std::mem::take(&mut self.loops);
+
+        let entry = self.context.append_basic_block(function, "entry");
+
+        self.builder.position_at_end(entry);
+
+        // build variables map, boxing any parameter the body sets to
+        // something its type cannot hold
+        let vars: Vec<&str> = params.iter().map(String::as_str).collect();
+        let local_tys = self
+            .inference
+            .scope_types(&vars, tys.to_vec(), body, &HashMap::new());
+        let mut allocas = vec![];
+        for (i, arg) in function.get_param_iter().enumerate() {
+            let arg_name = params[i].as_str();
+            let alloca = self.create_entry_block_alloca(arg_name, local_tys[i]);
+
+            let arg = self.convert(arg.into(), local_tys[i]);
+            self.builder.build_store(alloca, arg.as_basic());
+
+            self.locals.insert(params[i].clone(), alloca);
+            self.local_tys.insert(params[i].clone(), local_tys[i]);
+            allocas.push((alloca, local_tys[i]));
+        }
+
+        // the body gets a block of its own for self tail calls to jump to
+        let saved_loop = if self.in_definition {
+            let body_bb = self.context.append_basic_block(function, "body");
+            self.builder.build_unconditional_branch(body_bb);
+            self.builder.position_at_end(body_bb);
+            self.self_loop.replace((body_bb, allocas))
+        } else {
+            self.self_loop.take()
+        };
+
+        // compile body
+        let body = self
+            .compile_sequence(
+                body,
+                if self.in_definition {
+                    Tail::Function
+                } else {
+                    Tail::No
+                },
+            )
+            .and_then(|body| {
+                if ret.accepts(body.ty()) {
+                    Ok(self.convert(body, ret))
+                } else {
+                    Err("the body does not match the declared return type.")
+                }
+            });
+        if let Ok(body) = body {
+            self.builder.build_return(Some(&body.as_basic()));
+        }
+
+        self.locals = saved_locals;
+        self.local_tys = saved_tys;
+        self.self_loop = saved_loop;
+        self.loops = saved_loops;
+        self.fn_value_opt = saved_fn;
+        if let Some(block) = saved_block {
+            self.builder.position_at_end(block);
+        }
+
+        // return the whole thing after verification and optimization
+        if body.is_ok() && function.verify(true) {
+            self.fpm.run_on(&function);
+
+            Ok(function)
+        } else {
+            unsafe {
+                function.delete();
+            }
+
+            body.and(Err("Invalid generated function."))
+        }
+    }
+
+    /// Compiles the specified `Function` into an LLVM `FunctionValue`.
+    fn compile_fn(&mut self) -> Result<FunctionValue<'ctx>, &'static str> {
+        match function_parts(self.expr)? {
+            // (define (square x) (* x x))) compiles the all-f64 version (or
+            // the declared types, if any); the others are specialised from it
+            // when a call needs them. It may already have been specialised on
+            // demand by an earlier definition.
+            Some((name, params, _)) => {
+                // reject unknown types in annotations, which inference skips
+                definition_signature(self.expr)?;
+                let tys = vec![Ty::F64; params.len()];
+                let function = self
+                    .get_or_specialize(name, &tys)?
+                    .ok_or("Could not find a matching function.")?;
+                // a function whose types are all declared can be called from C
+                if self
+                    .inference
+                    .signature(name)
+                    .map_or(false, Signature::is_complete)
+                {
+                    self.compile_c_entry(name, function)?;
+                }
+                Ok(function)
+            }
+            None => {
+                let ret = self
+                    .inference
+                    .infer(self.expr, &HashMap::new())
+                    .unwrap_or(Ty::Dynamic);
+                self.compile_function("anon", &[], &[], std::slice::from_ref(self.expr), ret)
+            }
+        }
+    }
+
+    /// Compiles the specified `Function` in the given `Context` and using the specified `Builder`, `PassManager`, and `Module`.
+    /// `definitions` are the earlier top-level forms; the function
+    /// definitions among them can be called from `expr`.
     pub fn compile(
         context: &'ctx Context,
         builder: &'a Builder<'ctx>,
         pass_manager: &'a PassManager<FunctionValue<'ctx>>,
         module: &'a Module<'ctx>,
-        expr: &Expr,
-        global_scope: &'a mut HashMap<String, PointerValue<'ctx>>,
+        expr: &'a Expr,
+        definitions: &'a [Expr],
+        global_scope: &'a mut HashMap<String, Ty>,
     ) -> Result<FunctionValue<'ctx>, &'static str> {
-        let mut compiler = Compiler {
+        Compiler::new(
+            context,
+            builder,
+            pass_manager,
+            module,
+            expr,
+            definitions,
+            global_scope,
+        )
+        .compile_fn()
+    }
+
+    /// Compiles the version of function `name` for arguments of types `tys`
+    /// into `module`, as a call with arguments of those types would, see
+    /// `get_or_specialize`. `definitions` are as for `compile`, and include
+    /// the definition of `name`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn compile_version(
+        context: &'ctx Context,
+        builder: &'a Builder<'ctx>,
+        pass_manager: &'a PassManager<FunctionValue<'ctx>>,
+        module: &'a Module<'ctx>,
+        name: &str,
+        tys: &[Ty],
+        definitions: &'a [Expr],
+        global_scope: &'a mut HashMap<String, Ty>,
+    ) -> Result<FunctionValue<'ctx>, &'static str> {
+        let def = definitions
+            .iter()
+            .find(|def| matches!(function_parts(def), Ok(Some((defined, _, _))) if defined == name))
+            .ok_or("Could not find a matching function.")?;
+        Compiler::new(
+            context,
+            builder,
+            pass_manager,
+            module,
+            def,
+            definitions,
+            global_scope,
+        )
+        .get_or_specialize(name, tys)?
+        .ok_or("Could not find a matching function.")
+    }
+
+    fn new(
+        context: &'ctx Context,
+        builder: &'a Builder<'ctx>,
+        pass_manager: &'a PassManager<FunctionValue<'ctx>>,
+        module: &'a Module<'ctx>,
+        expr: &'a Expr,
+        definitions: &'a [Expr],
+        global_scope: &'a mut HashMap<String, Ty>,
+    ) -> Self {
+        let mut functions = HashMap::new();
+        for def in definitions.iter().chain(std::iter::once(expr)) {
+            if let Ok(Some((name, _, _))) = function_parts(def) {
+                functions.insert(name, def);
+            }
+        }
+
+        let inference = Inference::new(
+            definitions.iter().chain(std::iter::once(expr)),
+            global_scope.clone(),
+        );
+        Compiler {
             context,
             builder,
             fpm: pass_manager,
             module,
             expr,
             global_scope, // scopes: vec![global_scope],
+            locals: HashMap::new(),
+            local_tys: HashMap::new(),
+            in_definition: false,
+            tail: Tail::No,
+            self_loop: None,
+            loops: vec![],
+            definitions: functions,
+            inference,
             fn_value_opt: None,
-        };
-        // Directly call the modified compile_expr method
-        compiler.compile_fn()
+        }
     }
 }
@@ -0,0 +1,588 @@
+//! Lambda lifting of internal function definitions. A function defined in
+//! another's body, as in
+//!
+//! ```scheme
+//! (define (sum-to n)
+//!   (define (go i acc) (if (> i n) acc (go (+ i 1) (+ acc i))))
+//!   (go 1 0))
+//! ```
+//!
+//! becomes a top-level function of its own, `sum-to.go`, that takes the
+//! enclosing variables it uses (`n`) as extra parameters after its own, and
+//! every call to it passes them along. It therefore sees their values as of
+//! the call, which is all it can see of them, and so these functions are not
+//! closures. Two things are errors as a result:
+//!
+//! - assigning one of those variables with `set!` from inside the internal
+//!   function, since the assignment would only change its copy;
+//! - using the internal function as a value rather than calling it, since a
+//!   procedure value carries no variables with it.
+//!
+//! An internal function that uses no enclosing variables is lifted all the
+//! same, and is a value like any other function. Variables bound inside a
+//! body, by `let`, `do` or `define`, hide internal functions and enclosing
+//! variables of the same name as usual.
+//!
+//! A named let whose name is only called from tail position in its body
+//! compiles to a loop. One called anywhere else, as in
+//!
+//! ```scheme
+//! (let count ((n 3)) (if (= n 0) 0 (+ 1 (count (- n 1)))))
+//! ```
+//!
+//! is lifted the same way instead, to a function of its variables and the
+//! enclosing ones it uses, and the let becomes a call to it. The same two
+//! things are errors in its body.
+//!
+//! The lifted functions are ordinary definitions as far as inference and the
+//! compiler are concerned, so they are specialised like any other.
+
+use crate::{definition_form, let_bindings, parameter_parts, Expr};
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
+/// An internal function as its callers see it.
+#[derive(Clone, Debug)]
+struct Lifted {
+    name: String,
+    /// The enclosing variables passed after the arguments.
+    captured: Vec<String>,
+}
+
+/// Splits a function definition into the functions defined in its body,
+/// lifted to the top level, followed by the definition without them. Any
+/// other form comes last in the same way, after the functions lifted from
+/// the named lets in it that are not loops.
+pub fn lift_definitions(expr: &Expr) -> Result<Vec<Expr>, &'static str> {
+    let mut lifted = vec![];
+    match definition_form(expr)? {
+        Some((name, params, ret, body)) => {
+            let body = lift_body(name, &[], &HashMap::new(), params, body, &mut lifted)?;
+            lifted.push(definition(name, params.to_vec(), ret, body));
+        }
+        None => lifted.push(expr.clone()),
+    }
+
+    let mut names: HashSet<String> = lifted
+        .iter()
+        .filter_map(|form| Some(definition_form(form).ok()??.0.to_string()))
+        .collect();
+    let mut forms = vec![];
+    for form in &lifted {
+        let form = match definition_form(form)? {
+            Some((name, params, ret, body)) => {
+                let mut bound = binding_names(params);
+                bound.extend(body.iter().filter_map(variable_name));
+                let mut rewritten = vec![];
+                for expr in body {
+                    rewritten.push(lift_named_lets(expr, name, &bound, &mut names, &mut forms)?);
+                }
+                definition(name, params.to_vec(), ret, rewritten)
+            }
+            None => lift_named_lets(form, "let", &[], &mut names, &mut forms)?,
+        };
+        forms.push(form);
+    }
+    Ok(forms)
+}
+
+/// Lifts the functions defined in `body`, a function `prefix` taking
+/// `params` after the enclosing variables `enclosing`, and returns what is
+/// left of the body with calls to them rewritten. `calls` are the internal
+/// functions of the enclosing bodies.
+fn lift_body(
+    prefix: &str,
+    enclosing: &[String],
+    calls: &HashMap<String, Lifted>,
+    params: &[Expr],
+    body: &[Expr],
+    lifted: &mut Vec<Expr>,
+) -> Result<Vec<Expr>, &'static str> {
+    let mut calls = calls.clone();
+    let mut own = vec![];
+    for param in params {
+        own.push(parameter_parts(param)?.0.to_string());
+    }
+    let mut inner = vec![];
+    let mut rest = vec![];
+    for expr in body {
+        match definition_form(expr)? {
+            Some(form) => inner.push(form),
+            None => {
+                if let Some(var) = variable_name(expr) {
+                    own.push(var.to_string());
+                }
+                rest.push(expr);
+            }
+        }
+    }
+    // a variable of this body hides an enclosing function of the same name
+    for var in &own {
+        calls.remove(var);
+    }
+
+    let mut assigned = HashSet::new();
+    for expr in &rest {
+        assignments(expr, &[], &mut assigned);
+    }
+    if enclosing
+        .iter()
+        .any(|var| assigned.contains(var.as_str()) && !own.contains(var))
+    {
+        return Err("set! of an enclosing variable from an internal function.");
+    }
+
+    // every variable visible here that each internal function refers to,
+    // anywhere in its body
+    let visible: Vec<&String> = enclosing.iter().chain(own.iter()).collect();
+    let mut used = vec![];
+    let mut captured: Vec<Vec<String>> = vec![];
+    let mut inner_params = vec![];
+    for (name, params, _, body) in &inner {
+        // its own internal variables are not the enclosing ones
+        let defined: Vec<&str> = body.iter().filter_map(variable_name).collect();
+        let mut symbols = HashSet::new();
+        for expr in body.iter() {
+            free_symbols(expr, &defined, &mut symbols);
+        }
+        let mut names = vec![];
+        for param in params.iter() {
+            names.push(parameter_parts(param)?.0);
+        }
+        let mut vars: Vec<String> = vec![];
+        for var in &visible {
+            if symbols.contains(var.as_str())
+                && !names.contains(&var.as_str())
+                && !vars.contains(var)
+            {
+                vars.push(var.to_string());
+            }
+        }
+        calls.insert(
+            name.to_string(),
+            Lifted {
+                name: format!("{}.{}", prefix, name),
+                captured: vec![],
+            },
+        );
+        used.push(symbols);
+        captured.push(vars);
+        inner_params.push(names);
+    }
+
+    // a function also needs whatever the functions it calls need, which for
+    // mutually recursive ones takes a few rounds to settle
+    loop {
+        for ((name, ..), vars) in inner.iter().zip(&captured) {
+            calls.get_mut(*name).unwrap().captured = vars.clone();
+        }
+        let mut changed = false;
+        for (i, vars) in captured.iter_mut().enumerate() {
+            for (callee, callee_lifted) in &calls {
+                if !used[i].contains(callee.as_str()) {
+                    continue;
+                }
+                for var in &callee_lifted.captured {
+                    if !vars.contains(var) && !inner_params[i].contains(&var.as_str()) {
+                        vars.push(var.clone());
+                        changed = true;
+                    }
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    // in the order they were declared, whichever call brought them in
+    for ((name, ..), vars) in inner.iter().zip(&mut captured) {
+        vars.sort_by_key(|var| visible.iter().position(|visible| *visible == var));
+        calls.get_mut(*name).unwrap().captured = vars.clone();
+    }
+
+    for ((name, params, ret, body), vars) in inner.iter().zip(&captured) {
+        let lifted_name = format!("{}.{}", prefix, name);
+        let body = lift_body(&lifted_name, vars, &calls, params, body, lifted)?;
+        let mut params = params.to_vec();
+        params.extend(vars.iter().cloned().map(Expr::Symbol));
+        lifted.push(definition(&lifted_name, params, *ret, body));
+    }
+
+    if rest.is_empty() {
+        return Err("a function body needs an expression after its definitions.");
+    }
+    rest.into_iter()
+        .map(|expr| rewrite(expr, &calls, &[]))
+        .collect()
+}
+
+/// Lifts each named let in `expr` that is not a loop, see `tail_calls_only`,
+/// to a function named after `prefix` and the let, pushed onto `lifted`, and
+/// calls that instead. `bound` are the local variables in scope, which the
+/// function takes after the let's own when it uses them; `names` are the
+/// functions lifted so far, which it must not clash with.
+fn lift_named_lets(
+    expr: &Expr,
+    prefix: &str,
+    bound: &[&str],
+    names: &mut HashSet<String>,
+    lifted: &mut Vec<Expr>,
+) -> Result<Expr, &'static str> {
+    let exprs = match expr {
+        Expr::List(exprs) => exprs,
+        _ => return Ok(expr.clone()),
+    };
+    let (name, bindings, body) = match exprs.as_slice() {
+        [Expr::Symbol(op), Expr::Symbol(name), Expr::List(bindings), body @ ..]
+            if op == "let" && !tail_calls_only(name, body) =>
+        {
+            (name, bindings, body)
+        }
+        _ => {
+            let mut rewritten = exprs.clone();
+            for (path, inner) in scoped_parts(exprs) {
+                *at_mut(&mut rewritten, &path) = lift_named_lets(
+                    at(exprs, &path),
+                    prefix,
+                    &extend(bound, inner),
+                    names,
+                    lifted,
+                )?;
+            }
+            return Ok(Expr::List(rewritten));
+        }
+    };
+
+    let (vars, inits) = let_bindings(bindings)?;
+    let mut own = vars.clone();
+    own.push(name);
+    own.extend(body.iter().filter_map(variable_name));
+    let mut symbols = HashSet::new();
+    let mut assigned = HashSet::new();
+    for expr in body {
+        free_symbols(expr, &own, &mut symbols);
+        assignments(expr, &own, &mut assigned);
+    }
+    let mut captured: Vec<String> = vec![];
+    for var in bound {
+        if symbols.contains(var) && !captured.iter().any(|captured| captured == var) {
+            captured.push(var.to_string());
+        }
+    }
+    if captured.iter().any(|var| assigned.contains(var.as_str())) {
+        return Err("set! of an enclosing variable from a named let that is not a loop.");
+    }
+
+    let mut lifted_name = format!("{}.{}", prefix, name);
+    let mut n = 2;
+    while !names.insert(lifted_name.clone()) {
+        lifted_name = format!("{}.{}.{}", prefix, name, n);
+        n += 1;
+    }
+    let calls = HashMap::from([(
+        name.to_string(),
+        Lifted {
+            name: lifted_name.clone(),
+            captured: captured.clone(),
+        },
+    )]);
+    let mut params: Vec<Expr> = vars
+        .iter()
+        .map(|var| Expr::Symbol(var.to_string()))
+        .collect();
+    params.extend(captured.iter().cloned().map(Expr::Symbol));
+    let mut inner_bound = binding_names(&params);
+    inner_bound.extend(body.iter().filter_map(variable_name));
+    let mut lifted_body = vec![];
+    for expr in body {
+        let expr = rewrite(expr, &calls, &[])?;
+        lifted_body.push(lift_named_lets(
+            &expr,
+            &lifted_name,
+            &inner_bound,
+            names,
+            lifted,
+        )?);
+    }
+    lifted.push(definition(&lifted_name, params, None, lifted_body));
+
+    let mut call = vec![Expr::Symbol(lifted_name)];
+    for init in inits {
+        call.push(lift_named_lets(init, prefix, bound, names, lifted)?);
+    }
+    call.extend(captured.into_iter().map(Expr::Symbol));
+    Ok(Expr::List(call))
+}
+
+/// Whether `name` is only called from tail position in `body`, and then not
+/// in the arguments, so that a named let of that name compiles to a loop.
+/// A call from a nested named let counts only when that one is a loop too.
+fn tail_calls_only(name: &str, body: &[Expr]) -> bool {
+    match body.split_last() {
+        Some((last, effects)) => {
+            !effects.iter().any(|expr| uses(name, expr)) && tail_call_only(name, last)
+        }
+        None => true,
+    }
+}
+
+/// Whether `name` is only called from tail position in `expr`.
+fn tail_call_only(name: &str, expr: &Expr) -> bool {
+    let exprs = match expr {
+        Expr::List(exprs) => exprs,
+        _ => return !uses(name, expr),
+    };
+    let inits_use = |bindings: &[Expr]| {
+        bindings.iter().any(|binding| match binding {
+            Expr::List(parts) => parts.iter().skip(1).any(|init| uses(name, init)),
+            _ => false,
+        })
+    };
+    match exprs.as_slice() {
+        [Expr::Symbol(op), args @ ..] if op == name => !args.iter().any(|arg| uses(name, arg)),
+        [Expr::Symbol(op), test, branches @ ..] if op == "if" => {
+            !uses(name, test) && branches.iter().all(|branch| tail_call_only(name, branch))
+        }
+        [Expr::Symbol(op), body @ ..] if op == "begin" => tail_calls_only(name, body),
+        [Expr::Symbol(op), Expr::Symbol(inner), Expr::List(bindings), body @ ..] if op == "let" => {
+            let hidden = inner == name || binding_names(bindings).contains(&name);
+            !inits_use(bindings)
+                && (hidden || tail_calls_only(inner, body) && tail_calls_only(name, body))
+        }
+        [Expr::Symbol(op), Expr::List(bindings), body @ ..] if op == "let" => {
+            !inits_use(bindings)
+                && (binding_names(bindings).contains(&name) || tail_calls_only(name, body))
+        }
+        _ => !uses(name, expr),
+    }
+}
+
+/// Whether `expr` refers to the variable `name`.
+fn uses(name: &str, expr: &Expr) -> bool {
+    let mut symbols = HashSet::new();
+    free_symbols(expr, &[], &mut symbols);
+    symbols.contains(name)
+}
+
+/// `(define (name params...) : ret body...)`, or without the `: ret`.
+fn definition(name: &str, params: Vec<Expr>, ret: Option<&Expr>, body: Vec<Expr>) -> Expr {
+    let mut sig = vec![Expr::Symbol(name.to_string())];
+    sig.extend(params);
+    let mut form = vec![Expr::Symbol("define".to_string()), Expr::List(sig)];
+    if let Some(ret) = ret {
+        form.push(Expr::Symbol(":".to_string()));
+        form.push(ret.clone());
+    }
+    form.extend(body);
+    Expr::List(form)
+}
+
+/// The variable `(define name value)` defines.
+pub(crate) fn variable_name(expr: &Expr) -> Option<&str> {
+    match expr {
+        Expr::List(exprs) => match exprs.as_slice() {
+            [Expr::Symbol(op), Expr::Symbol(name), _] if op == "define" => Some(name),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Where an expression sits in a list form: the index of each list on the
+/// way down to it.
+pub(crate) type Path = Vec<usize>;
+
+/// The elements of the list form `exprs` that are expressions, however deep
+/// in it, each with the variables the form binds over it: a `let` binds its
+/// variables over its body, a named `let` its name too, a `do` its variables
+/// over everything but their initial values, and a body the variables it
+/// defines over all of itself. Names being bound and quoted data are left
+/// out.
+pub(crate) fn scoped_parts(exprs: &[Expr]) -> Vec<(Path, Vec<&str>)> {
+    let outer = |paths: Vec<Path>| -> Vec<(Path, Vec<&str>)> {
+        paths.into_iter().map(|path| (path, vec![])).collect()
+    };
+    match exprs {
+        [Expr::Symbol(op), ..] if op == "quote" => vec![],
+        [Expr::Symbol(op), Expr::Symbol(name), Expr::List(bindings), ..] if op == "let" => {
+            let mut bound = binding_names(bindings);
+            bound.push(name);
+            let mut parts = outer(binding_values(2, bindings, 1..2));
+            parts.extend(body_parts(exprs, 3, bound));
+            parts
+        }
+        [Expr::Symbol(op), Expr::List(bindings), ..] if op == "let" => {
+            let mut parts = outer(binding_values(1, bindings, 1..2));
+            parts.extend(body_parts(exprs, 2, binding_names(bindings)));
+            parts
+        }
+        [Expr::Symbol(op), Expr::List(specs), Expr::List(exit), ..] if op == "do" => {
+            let vars = binding_names(specs);
+            let mut parts = outer(binding_values(1, specs, 1..2));
+            let steps = binding_values(1, specs, 2..3);
+            let exit = (0..exit.len()).map(|k| vec![2, k]);
+            parts.extend(
+                steps
+                    .into_iter()
+                    .chain(exit)
+                    .map(|path| (path, vars.clone())),
+            );
+            parts.extend(body_parts(exprs, 3, vars));
+            parts
+        }
+        [Expr::Symbol(op), Expr::List(sig), ..] if op == "define" => {
+            let params = sig.get(1..).unwrap_or_default();
+            body_parts(exprs, 2, binding_names(params))
+        }
+        [Expr::Symbol(op), Expr::Symbol(_), _] if op == "define" => outer(vec![vec![2]]),
+        [Expr::Symbol(op), ..] if op == "begin" => body_parts(exprs, 1, vec![]),
+        _ => outer((0..exprs.len()).map(|i| vec![i]).collect()),
+    }
+}
+
+/// The names of `(name value...)` bindings, or of parameters, which are
+/// names or `(name : type)`.
+fn binding_names(bindings: &[Expr]) -> Vec<&str> {
+    bindings
+        .iter()
+        .filter_map(|binding| match binding {
+            Expr::Symbol(name) => Some(name.as_str()),
+            Expr::List(parts) => match parts.first() {
+                Some(Expr::Symbol(name)) => Some(name.as_str()),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect()
+}
+
+/// The paths to the parts `range` of each of the `(name value...)` bindings
+/// at element `i` of a list form.
+fn binding_values(i: usize, bindings: &[Expr], range: Range<usize>) -> Vec<Path> {
+    let mut paths = vec![];
+    for (j, binding) in bindings.iter().enumerate() {
+        if let Expr::List(parts) = binding {
+            let range = range.start..range.end.min(parts.len());
+            paths.extend(range.map(|k| vec![i, j, k]));
+        }
+    }
+    paths
+}
+
+/// The elements of `exprs` from `from` on, a body, with `bound` and the
+/// variables it defines bound over each.
+fn body_parts<'e>(
+    exprs: &'e [Expr],
+    from: usize,
+    mut bound: Vec<&'e str>,
+) -> Vec<(Path, Vec<&'e str>)> {
+    bound.extend(exprs[from..].iter().filter_map(variable_name));
+    (from..exprs.len())
+        .map(|i| (vec![i], bound.clone()))
+        .collect()
+}
+
+/// The expression at `path` in `exprs`, see `scoped_parts`.
+pub(crate) fn at<'e>(exprs: &'e [Expr], path: &[usize]) -> &'e Expr {
+    let (last, lists) = path.split_last().unwrap();
+    let mut exprs = exprs;
+    for i in lists {
+        match &exprs[*i] {
+            Expr::List(inner) => exprs = inner,
+            _ => unreachable!("scoped_parts only goes down lists"),
+        }
+    }
+    &exprs[*last]
+}
+
+/// The expression at `path` in `exprs`, to replace.
+pub(crate) fn at_mut<'e>(exprs: &'e mut [Expr], path: &[usize]) -> &'e mut Expr {
+    let (last, lists) = path.split_last().unwrap();
+    let mut exprs = exprs;
+    for i in lists {
+        match &mut exprs[*i] {
+            Expr::List(inner) => exprs = inner,
+            _ => unreachable!("scoped_parts only goes down lists"),
+        }
+    }
+    &mut exprs[*last]
+}
+
+/// `bound` and then `inner`.
+pub(crate) fn extend<'e>(bound: &[&'e str], inner: Vec<&'e str>) -> Vec<&'e str> {
+    bound.iter().copied().chain(inner).collect()
+}
+
+/// Every variable `expr` refers to but does not bind itself, outside quoted
+/// data, unless it is one of `bound`.
+fn free_symbols<'e>(expr: &'e Expr, bound: &[&'e str], symbols: &mut HashSet<&'e str>) {
+    match expr {
+        Expr::Symbol(name) if !bound.contains(&name.as_str()) => {
+            symbols.insert(name);
+        }
+        Expr::List(exprs) => {
+            for (path, inner) in scoped_parts(exprs) {
+                free_symbols(at(exprs, &path), &extend(bound, inner), symbols);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Every variable `expr` assigns with `set!` that it does not bind itself,
+/// unless it is one of `bound`.
+fn assignments<'e>(expr: &'e Expr, bound: &[&'e str], assigned: &mut HashSet<&'e str>) {
+    if let Expr::List(exprs) = expr {
+        if let [Expr::Symbol(op), Expr::Symbol(name), _] = exprs.as_slice() {
+            if op == "set!" && !bound.contains(&name.as_str()) {
+                assigned.insert(name);
+            }
+        }
+        for (path, inner) in scoped_parts(exprs) {
+            assignments(at(exprs, &path), &extend(bound, inner), assigned);
+        }
+    }
+}
+
+/// Renames the internal functions in `expr` to their lifted names, passing
+/// the variables they captured to each call. Names in `bound` are variables
+/// bound inside the body, which hide the internal functions.
+fn rewrite(
+    expr: &Expr,
+    calls: &HashMap<String, Lifted>,
+    bound: &[&str],
+) -> Result<Expr, &'static str> {
+    let lifted = |name: &str| calls.get(name).filter(|_| !bound.contains(&name));
+    match expr {
+        Expr::Symbol(name) => match lifted(name) {
+            Some(lifted) if lifted.captured.is_empty() => Ok(Expr::Symbol(lifted.name.clone())),
+            Some(_) => Err(
+                "an internal function that uses enclosing variables can only be called, not used as a value.",
+            ),
+            None => Ok(expr.clone()),
+        },
+        Expr::List(exprs) => match exprs.as_slice() {
+            [Expr::Symbol(op), args @ ..] if lifted(op).is_some() => {
+                let lifted = &calls[op];
+                if lifted.captured.iter().any(|var| bound.contains(&var.as_str())) {
+                    return Err("an internal function is called where a variable it uses is hidden.");
+                }
+                let mut call = vec![Expr::Symbol(lifted.name.clone())];
+                for arg in args {
+                    call.push(rewrite(arg, calls, bound)?);
+                }
+                call.extend(lifted.captured.iter().cloned().map(Expr::Symbol));
+                Ok(Expr::List(call))
+            }
+            _ => {
+                let mut rewritten = exprs.clone();
+                for (path, inner) in scoped_parts(exprs) {
+                    *at_mut(&mut rewritten, &path) =
+                        rewrite(at(exprs, &path), calls, &extend(bound, inner))?;
+                }
+                Ok(Expr::List(rewritten))
+            }
+        },
+        _ => Ok(expr.clone()),
+    }
+}
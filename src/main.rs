@@ -1,38 +1,245 @@
 use inkwell::context::Context;
-use inkwell::intrinsics::Intrinsic;
-use inkwell::passes::PassManager;
-use inkwell::OptimizationLevel;
+use lisp_repl::completion::Symbols;
+use lisp_repl::highlight;
+use lisp_repl::printer::{describe_definition, Verbosity};
 use lisp_repl::*;
+use rustyline::completion::{Completer, Pair};
 use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
 use rustyline::history::History;
 use rustyline::validate::MatchingBracketValidator;
 use rustyline::{Cmd, Editor, EventHandler, KeyCode, KeyEvent, Modifiers};
-use rustyline::{Completer, Helper, Highlighter, Hinter, Validator};
-use std::collections::HashMap;
-use std::path::Path;
-
-#[derive(Completer, Helper, Highlighter, Hinter, Validator)]
+use rustyline::{Helper, Validator};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+#[derive(Helper, Validator)]
 struct InputValidator {
     #[rustyline(Validator)]
     brackets: MatchingBracketValidator,
+    // shared with the eval loop, which refreshes it from the session
+    symbols: Rc<RefCell<Symbols>>,
+    // false when stdout is not a terminal, e.g. when piping a session to a file
+    colored: bool,
+}
+
+impl Completer for InputValidator {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let (start, names) = self.symbols.borrow().complete(line, pos);
+        let candidates = names
+            .into_iter()
+            .map(|name| Pair {
+                display: name.clone(),
+                replacement: name,
+            })
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Highlighter for InputValidator {
+    fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+        if self.colored {
+            Cow::Owned(highlight::highlight(line, Some(pos)))
+        } else {
+            Cow::Borrowed(line)
+        }
+    }
+
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        if self.colored {
+            Cow::Owned(highlight::highlight_hint(hint))
+        } else {
+            Cow::Borrowed(hint)
+        }
+    }
+
+    // re-highlight on cursor moves too, so the paren pair under it follows
+    fn highlight_char(&self, line: &str, _pos: usize) -> bool {
+        self.colored && !line.is_empty()
+    }
+}
+
+impl Hinter for InputValidator {
+    type Hint = String;
+
+    fn hint(&self, line: &str, pos: usize, _ctx: &rustyline::Context<'_>) -> Option<String> {
+        self.symbols.borrow().hint(line, pos)
+    }
+}
+
+/// Where history goes unless `--history` or `LISP_REPL_HISTORY` say
+/// otherwise: `$XDG_STATE_HOME/lisp_repl/history.txt`, falling back to
+/// `~/.local/state/lisp_repl/history.txt`.
+fn default_history_path() -> PathBuf {
+    if let Some(path) = std::env::var_os("LISP_REPL_HISTORY") {
+        return PathBuf::from(path);
+    }
+
+    let state_home = std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")));
+
+    match state_home {
+        Some(dir) => dir.join("lisp_repl").join("history.txt"),
+        None => PathBuf::from("history.txt"),
+    }
+}
+
+/// Prints what a successfully evaluated form produced.
+fn print_outcome(expr: &Expr, outcome: &Outcome, session: &Session) {
+    match outcome {
+        Outcome::Value(value) => {
+            match describe_definition(expr, Some(value), session.definitions()) {
+                Some(def) => println!("{} = {}", def, value),
+                None if *value == Value::Unspecified => {}
+                None => println!("{}", value),
+            }
+        }
+        Outcome::Defined => {
+            if let Some(def) = describe_definition(expr, None, session.definitions()) {
+                println!("{}", def);
+            }
+        }
+    }
+}
+
+/// Makes completion and hints offer what `session` has defined so far.
+fn refresh_symbols(symbols: &RefCell<Symbols>, session: &Session) {
+    let globals = session.globals();
+    symbols.borrow_mut().refresh(
+        session.definitions(),
+        session.imports(),
+        globals.iter().map(|(name, _)| *name),
+        session.macro_names(),
+    );
+}
+
+/// Lists every function, global variable and (unshadowed) constant.
+fn print_environment(session: &Session) {
+    for def in session.definitions() {
+        if let Some(def) = describe_definition(def, None, session.definitions()) {
+            println!("{}", def);
+        }
+    }
+    let globals = session.globals();
+    for (name, value) in &globals {
+        println!("{}: {} = {}", name, value.type_name(), value);
+    }
+    for (name, value) in constants::CONSTANTS {
+        if !globals.iter().any(|(global, _)| global == name) {
+            println!("{}: f64 = {} (constant)", name, Value::Float(*value));
+        }
+    }
+}
+
+/// Handles a `:command` line, returning an error message if it failed.
+fn run_command(session: &mut Session, line: &str) -> Result<(), String> {
+    let mut words = line.split_whitespace();
+    match (words.next(), words.next()) {
+        (Some(":env"), None) => {
+            print_environment(session);
+            Ok(())
+        }
+        (Some(":save"), Some(path)) => session
+            .save(path)
+            .map(|_| println!("saved {} forms to {}", session.transcript().len(), path))
+            .map_err(|err| format!("cannot write {}: {}", path, err)),
+        (Some(":save"), None) => Err("usage: :save <file.lisp>".to_string()),
+        (Some(":header"), Some(path)) => std::fs::write(path, session.c_header())
+            .map(|_| println!("wrote C declarations to {}", path))
+            .map_err(|err| format!("cannot write {}: {}", path, err)),
+        (Some(":header"), None) => Err("usage: :header <file.h>".to_string()),
+        (Some(":objects"), Some(dir)) => {
+            for path in session.write_objects(Path::new(dir))? {
+                println!("wrote {}", path.display());
+            }
+            Ok(())
+        }
+        (Some(":objects"), None) => Err("usage: :objects <directory>".to_string()),
+        (Some(":macroexpand"), Some(_)) => {
+            let form = read(line.trim_start().trim_start_matches(":macroexpand"))?;
+            println!("{}", session.macroexpand(&form)?);
+            Ok(())
+        }
+        (Some(":macroexpand"), None) => Err("usage: :macroexpand <form>".to_string()),
+        (Some(":heap"), None) => {
+            let stats = gc::stats();
+            println!(
+                "{} objects ({} bytes) live, {} freed by {} collections",
+                stats.live_objects, stats.live_bytes, stats.freed_objects, stats.collections
+            );
+            Ok(())
+        }
+        (Some(command), _) => Err(format!("unknown command {}", command)),
+        (None, _) => Ok(()),
+    }
+}
+
+const USAGE: &str = "usage: lisp_repl [-v | -vv | --verbose[=<level>] | --dp | --dc] \
+                     [--history <file>] [--replay <file.lisp>] [--no-prelude]";
+
+/// Reports a command line that cannot be understood, and exits.
+fn usage_error(message: &str) -> ! {
+    eprintln!("Error: {}\n{}", message, USAGE);
+    std::process::exit(2);
 }
 
 fn main() -> Result<(), ReadlineError> {
-    let mut display_parser_output = true;
-    let mut display_compiler_output = false;
+    let mut verbosity_level = 0;
+    let mut history_path = default_history_path();
+    let mut replay_path = None;
+    let mut prelude = true;
 
-    for arg in std::env::args() {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
         match arg.as_str() {
-            "--dp" => display_parser_output = true,
-            "--dc" => display_compiler_output = true,
-            _ => (),
+            "-v" | "--verbose" => verbosity_level += 1,
+            "-vv" => verbosity_level += 2,
+            "--dp" => verbosity_level = verbosity_level.max(1),
+            "--dc" => verbosity_level = verbosity_level.max(2),
+            "--history" => match args.next() {
+                Some(path) => history_path = PathBuf::from(path),
+                None => usage_error("--history needs a file"),
+            },
+            "--replay" => match args.next() {
+                Some(path) => replay_path = Some(path),
+                None => usage_error("--replay needs a file"),
+            },
+            "--no-prelude" => prelude = false,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ => match arg.strip_prefix("--verbose=") {
+                Some(level) => match level.parse() {
+                    Ok(level) => verbosity_level = level,
+                    Err(_) => usage_error(&format!("invalid verbosity level {}", level)),
+                },
+                None => usage_error(&format!("unknown option {}", arg)),
+            },
         }
     }
 
-    println!("p{} c{}", display_parser_output, display_compiler_output);
+    let symbols = Rc::new(RefCell::new(Symbols::new()));
+    let colored = std::io::stdout().is_terminal();
 
     let h = InputValidator {
         brackets: MatchingBracketValidator::new(),
+        symbols: Rc::clone(&symbols),
+        colored,
     };
 
     let mut rl = Editor::new()?;
@@ -43,103 +250,40 @@ fn main() -> Result<(), ReadlineError> {
         EventHandler::Simple(Cmd::Newline),
     );
 
-    let history_path = "history.txt";
-
     // Load history from the history file if it exists
-    if Path::new(history_path).exists() {
-        rl.load_history(history_path)?;
+    if history_path.exists() {
+        rl.load_history(&history_path)?;
     }
 
     let context = Context::create();
-    let module = context.create_module("repl");
-    let builder = context.create_builder();
-    let fpm = PassManager::create(&module);
-    // fpm.add_instruction_combining_pass();
-    // fpm.add_reassociate_pass();
-    // fpm.add_gvn_pass();
-    // fpm.add_cfg_simplification_pass();
-    // fpm.add_basic_alias_analysis_pass();
-    // fpm.add_promote_memory_to_register_pass();
-    // fpm.add_instruction_combining_pass();
-    // fpm.add_reassociate_pass();
-    // fpm.initialize();
-    let mut global_scope = HashMap::new();
-
-    let mut previous_exprs: Vec<Expr> = Vec::new();
-    
-    let ee = module
-    .create_jit_execution_engine(OptimizationLevel::None)
-    .unwrap();
-
-    // let result = Compiler::compile(
-    //     &context,
-    //     &builder,
-    //     &fpm,
-    //     &module,
-    //     &read("(define (square x) (* x x))").unwrap(),
-    //     &mut global_scope,
-    // );
-    // println!("{:?}\n\n", result);
-    // println!("{}", module.print_to_string().to_string());
-
-    // let result2 = Compiler::compile(
-    //     &context,
-    //     &builder,
-    //     &fpm,
-    //     &module,
-    //     &read("(square 2)").unwrap(),
-    //     &mut global_scope,
-    // );
-
-    // let result3 = Compiler::compile(
-    //     &context,
-    //     &builder,
-    //     &fpm,
-    //     &module,
-    //     &read("(llvm.fabs -2.5)").unwrap(),
-    //     &mut global_scope,
-    // );
-
-    // let result4 = Compiler::compile(
-    //     &context,
-    //     &builder,
-    //     &fpm,
-    //     &module,
-    //     &read("(+ 2 2)").unwrap(),
-    //     &mut global_scope,
-    // )
-    // .unwrap();
-    // println!("r4{:?}\n\n", result4);
-
-
-
-    // let sq = unsafe {
-    //     ee.get_function::<unsafe extern "C" fn(f64) -> f64>("square")
-    //         .ok()
-    // }
-    // .unwrap();
-    // let maybe_fn = unsafe { ee.get_function::<unsafe extern "C" fn() -> f64>("anon") }.unwrap();
-
-    // // // todo figure out the name mangling
-    // let maybe_fn2 = unsafe { ee.get_function::<unsafe extern "C" fn() -> f64>("anon.1") }.unwrap();
-
-    // unsafe {
-    //     println!(
-    //         "YO YO YO THE EXECUTION ENGINE GOT: {:?}\n\n and {:?}",
-    //         maybe_fn.call(),
-    //         maybe_fn2.call()
-    //     );
-    // }
-    // println!("{:#?}", module.get_functions().collect::<Vec<_>>());
+    let mut session = Session::new(&context);
+    session.verbosity = Verbosity::from_level(verbosity_level);
 
-    // unsafe {
-    //     println!("=> {}", sq.call(3.0));
-    // }
+    if prelude {
+        if let Err(err) = session.load_prelude() {
+            eprintln!("Error: {}", err);
+        }
+    }
 
-    let mut loop_counter = 0; // used for module name
+    if let Some(path) = replay_path {
+        match session.replay(&path) {
+            Ok(_) => {
+                println!(
+                    "replayed {} forms from {}",
+                    session.transcript().len(),
+                    path
+                );
+            }
+            Err(err) => eprintln!("Error: {}", err),
+        }
+    }
 
     loop {
-        let prompt_str = format! {"\x1b[1;32mmylisp[HIST:{} | LOOP: {}]>>\x1b[0m ", rl.history().len().to_string(),loop_counter};
+        refresh_symbols(&symbols, &session);
+        let mut prompt_str = format! {"mylisp[HIST:{} | LOOP: {}]>> ", rl.history().len().to_string(), session.loop_counter()};
+        if colored {
+            prompt_str = format!("\x1b[1;32m{}\x1b[0m", prompt_str);
+        }
 
         let readline = rl.readline(prompt_str.as_str());
         match readline {
@@ -148,91 +292,18 @@ fn main() -> Result<(), ReadlineError> {
                 if line.is_empty() {
                     continue;
                 }
-                match read(&line) {
-                    Ok(expr) => {
-                        let mod_name = format!("repl_{}", loop_counter);
-                        let module = context.create_module(&mod_name);
-                        loop_counter += 1;
-
-                        // recompile every previously parsed function into the new module
-                        // this clears the anon so we dont get anon.1 anon.2 etc
-                        for prev in &previous_exprs {
-                            Compiler::compile(
-                                &context,
-                                &builder,
-                                &fpm,
-                                &module,
-                                prev,
-                                &mut global_scope,
-                            )
-                            .expect("Cannot re-add previously compiled function.");
-                        }
-
-                        if display_parser_output {
-                            println!("{:?}", expr);
-                        }
-
-                        match Compiler::compile(
-                            &context,
-                            &builder,
-                            &fpm,
-                            &module,
-                            &expr,
-                            &mut global_scope,
-                        ) {
-                            Ok(result) => {
-                                println!(
-                                    "GLOBAL_SCOPE:\n\n {:?}\n\nMODULE CONTENTS: \n\n{}",
-                                    global_scope,
-                                    module.to_string()
-                                );
-
-                                let function_name = result.get_name().to_str().unwrap();
-                                // I assume this returns a &str
-
-                                if function_name.contains("anon") {
-                                    // previous_exprs.push(expr);
-                                    let ee = module
-                                        .create_jit_execution_engine(OptimizationLevel::None)
-                                        .unwrap();
-                                    let maybe_fn = unsafe {
-                                        ee.get_function::<unsafe extern "C" fn() -> f64>(
-                                            function_name,
-                                        )
-                                    };
-
-                                    let compiled_fn = match maybe_fn {
-                                        Ok(f) => f,
-                                        Err(err) => {
-                                            println!("!> Error during execution: {:?}", err);
-                                            continue;
-                                        }
-                                    };
-                                    
-                                    unsafe {
-                                        println!("about to call ");
-                                        println!("CALL=> {}", compiled_fn.call());
-                                    }
-                                } else {
-                                    previous_exprs.push(expr);
-
-                                    println!("NON_ANON");
-                                    println!("{:?}\n\n", result);
-                                }
-                            }
-
-                            Err(err) => {
-                                println!(
-                                    "GLOBAL_SCOPE:\n\n {:#?}\n\nMODULE CONTENTS: \n\n{}",
-                                    global_scope,
-                                    module.to_string()
-                                );
-
-                                println!("Error: {}", err)
-                            }
-                        }
+                if line.starts_with(':') {
+                    if let Err(err) = run_command(&mut session, &line) {
+                        eprintln!("Error: {}", err);
                     }
-                    Err(err) => println!("Error: {}", err),
+                    continue;
+                }
+                match read(&line) {
+                    Ok(expr) => match session.eval(&expr) {
+                        Ok(outcome) => print_outcome(&expr, &outcome, &session),
+                        Err(err) => eprintln!("Error: {}", err),
+                    },
+                    Err(err) => eprintln!("Error: {}", err),
                 }
             }
             Err(ReadlineError::Interrupted) => {
@@ -244,13 +315,16 @@ fn main() -> Result<(), ReadlineError> {
                 break;
             }
             Err(err) => {
-                println!("Error: {:?}", err);
+                eprintln!("Error: {:?}", err);
                 break;
             }
         }
     }
 
-    rl.save_history("history.txt")?;
+    if let Some(dir) = history_path.parent() {
+        // best effort: a missing state directory should not lose the session
+        let _ = std::fs::create_dir_all(dir);
+    }
+    rl.save_history(&history_path)?;
     Ok(())
 }
-// is_x86_feature_detected!("avx2");
\ No newline at end of file
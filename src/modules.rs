@@ -0,0 +1,144 @@
+//! Modules: `.lisp` files that start with a header naming the module and
+//! what it exports,
+//!
+//! ```scheme
+//! (module geometry (export area))
+//! (define pi 3.14159)
+//! (define (area r) (* pi r r))
+//! ```
+//!
+//! and are loaded with `(import "geometry.lisp")`, or `(import (lib
+//! geometry))` to look for `geometry.lisp` in the session's library
+//! directories. A module's definitions get qualified names,
+//! `geometry::area` and `geometry::pi`, so that they cannot clash with
+//! anyone else's, and importing it makes the exported names stand for the
+//! qualified ones. The rest stay reachable by their qualified names.
+//!
+//! Once a module has been evaluated, its functions are compiled into an LLVM
+//! module of their own, and later modules declare them and link to that code
+//! rather than compile them again; see `Session::compile_unit`. Calls with
+//! other argument types than that code was compiled for still specialise
+//! them from their definitions where they are made. The same LLVM modules
+//! are written out as object files, one per module, by the REPL's
+//! `:objects` command; see `Session::write_objects`.
+
+use crate::lift::{at, at_mut, extend, scoped_parts, variable_name};
+use crate::{declaration_parts, function_parts, Expr};
+use std::collections::HashMap;
+
+/// What an `import` names.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Import {
+    /// `(import "path/file.lisp")`
+    File(String),
+    /// `(import (lib name))`
+    Library(String),
+}
+
+/// The module header `(module name (export names...))` as the module's
+/// name and exports, or `None` for any other form.
+pub fn module_header(expr: &Expr) -> Result<Option<(&str, Vec<&str>)>, &'static str> {
+    let exprs = match expr {
+        Expr::List(exprs) => exprs,
+        _ => return Ok(None),
+    };
+    let (name, export) = match exprs.as_slice() {
+        [Expr::Symbol(op), Expr::Symbol(name), Expr::List(export)] if op == "module" => {
+            (name, export)
+        }
+        [Expr::Symbol(op), ..] if op == "module" => {
+            return Err("a module header is written (module name (export names...)).")
+        }
+        _ => return Ok(None),
+    };
+    match export.split_first() {
+        Some((Expr::Symbol(op), names)) if op == "export" => names
+            .iter()
+            .map(|name| match name {
+                Expr::Symbol(name) => Ok(name.as_str()),
+                _ => Err("a module can only export names."),
+            })
+            .collect::<Result<_, _>>()
+            .map(|names| Some((name.as_str(), names))),
+        _ => Err("a module header is written (module name (export names...))."),
+    }
+}
+
+/// What `(import ...)` imports, or `None` for any other form.
+pub fn import_target(expr: &Expr) -> Result<Option<Import>, &'static str> {
+    let exprs = match expr {
+        Expr::List(exprs) => exprs,
+        _ => return Ok(None),
+    };
+    match exprs.as_slice() {
+        [Expr::Symbol(op), Expr::Str(path)] if op == "import" => {
+            Ok(Some(Import::File(path.clone())))
+        }
+        [Expr::Symbol(op), Expr::List(lib)] if op == "import" => match lib.as_slice() {
+            [Expr::Symbol(lib), Expr::Symbol(name)] if lib == "lib" => {
+                Ok(Some(Import::Library(name.clone())))
+            }
+            _ => Err("import requires a file name or (lib name)."),
+        },
+        [Expr::Symbol(op), ..] if op == "import" => {
+            Err("import requires a file name or (lib name).")
+        }
+        _ => Ok(None),
+    }
+}
+
+/// The name of the function, declaration or global variable `expr`
+/// defines, if it is a definition.
+pub fn defined_name(expr: &Expr) -> Result<Option<&str>, &'static str> {
+    if let Some((name, _, _)) = function_parts(expr)? {
+        return Ok(Some(name));
+    }
+    if let Some((name, _)) = declaration_parts(expr)? {
+        return Ok(Some(name));
+    }
+    Ok(variable_name(expr))
+}
+
+/// The name definition `name` of module `module` is compiled under.
+pub fn qualify(module: &str, name: &str) -> String {
+    format!("{}::{}", module, name)
+}
+
+/// Renames the name the top-level form `expr` defines, if `scope` maps it,
+/// and every variable it refers to that `scope` maps, outside quoted data.
+/// Local variables of the same names keep them, and so do the references to
+/// them.
+pub fn rename(expr: &Expr, scope: &HashMap<String, String>) -> Expr {
+    let mut renamed = rename_free(expr, scope, &[]);
+    if let Expr::List(exprs) = &mut renamed {
+        let name = match exprs.as_mut_slice() {
+            [Expr::Symbol(op), Expr::List(sig), ..] if op == "define" => sig.first_mut(),
+            [Expr::Symbol(op), name, _] if op == "define" => Some(name),
+            _ => None,
+        };
+        if let Some(name) = name {
+            *name = rename_free(name, scope, &[]);
+        }
+    }
+    renamed
+}
+
+/// Renames the variables `expr` refers to that `scope` maps, unless they are
+/// one of `bound`.
+fn rename_free(expr: &Expr, scope: &HashMap<String, String>, bound: &[&str]) -> Expr {
+    match expr {
+        Expr::Symbol(name) if !bound.contains(&name.as_str()) => match scope.get(name) {
+            Some(renamed) => Expr::Symbol(renamed.clone()),
+            None => expr.clone(),
+        },
+        Expr::List(exprs) => {
+            let mut renamed = exprs.clone();
+            for (path, inner) in scoped_parts(exprs) {
+                *at_mut(&mut renamed, &path) =
+                    rename_free(at(exprs, &path), scope, &extend(bound, inner));
+            }
+            Expr::List(renamed)
+        }
+        _ => expr.clone(),
+    }
+}
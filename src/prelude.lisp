@@ -0,0 +1,33 @@
+;; The standard prelude, defined in every REPL session started without
+;; --no-prelude. A definition of the same name in the session replaces the
+;; one here.
+
+(define (abs x) (if (< x 0) (- x) x))
+;; min and max also take one argument, or more than two, see FOLDED in
+;; session.rs
+(define (min a b) (if (< b a) b a))
+(define (max a b) (if (> b a) b a))
+(define (square x) (* x x))
+(define (cube x) (* x x x))
+
+;; lists
+
+(define (sum xs)
+  (let loop ((xs xs) (total 0))
+    (if (null? xs) total (loop (cdr xs) (+ total (car xs))))))
+
+(define (mean xs) (/ (sum xs) (length xs)))
+
+(define (map f xs)
+  (if (null? xs) '() (cons (f (car xs)) (map f (cdr xs)))))
+
+(define (filter keep? xs)
+  (if (null? xs)
+      '()
+      (if (keep? (car xs))
+          (cons (car xs) (filter keep? (cdr xs)))
+          (filter keep? (cdr xs)))))
+
+;; (fold f init '(a b c)) is (f (f (f init a) b) c)
+(define (fold f init xs)
+  (if (null? xs) init (fold f (f init (car xs)) (cdr xs))))
@@ -0,0 +1,166 @@
+use crate::{declaration_parts, Expr, Ty, Value};
+
+/// How much of the read/compile/run pipeline the REPL echoes back.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Only results and errors.
+    #[default]
+    Quiet,
+    /// Also the parsed `Expr` of every line.
+    Parse,
+    /// Also the global scope, the module IR and the JIT call trace.
+    Trace,
+}
+
+impl Verbosity {
+    /// Maps a `-v` count (or `--verbose=N`) onto a level, saturating at `Trace`.
+    pub fn from_level(level: usize) -> Self {
+        match level {
+            0 => Verbosity::Quiet,
+            1 => Verbosity::Parse,
+            _ => Verbosity::Trace,
+        }
+    }
+}
+
+/// Formats a float the way a Scheme REPL would: integral values keep a
+/// trailing `.0` so they do not look exact, infinities and NaN are
+/// `+inf.0`/`-inf.0`/`+nan.0`, and very large or small magnitudes use
+/// exponent form. Exact integers are `Value::Int` and print without the `.0`.
+pub fn format_float(x: f64) -> String {
+    if x.is_nan() {
+        return "+nan.0".to_string();
+    }
+    if x.is_infinite() {
+        return if x > 0.0 { "+inf.0" } else { "-inf.0" }.to_string();
+    }
+
+    let magnitude = x.abs();
+    if magnitude != 0.0 && !(1e-7..1e21).contains(&magnitude) {
+        format!("{:e}", x)
+    } else if x.fract() == 0.0 {
+        format!("{:.1}", x)
+    } else {
+        format!("{}", x)
+    }
+}
+
+/// Describes what a `define` form introduced, e.g. `square: (x) -> ?`, or
+/// `dot: ((x : f64) (n : i64)) -> f64` with annotations or with the
+/// declaration `(: dot (-> f64 i64 f64))` among `definitions`, or `x: i64`
+/// for a variable whose evaluated `value` is known. The return type of a
+/// function with neither depends on its arguments and is shown as `?`. A
+/// declaration is described by the type it declares. Returns `None` for
+/// anything that is not a definition.
+pub fn describe_definition(
+    expr: &Expr,
+    value: Option<&Value>,
+    definitions: &[Expr],
+) -> Option<String> {
+    let exprs = match expr {
+        Expr::List(exprs) => exprs,
+        _ => return None,
+    };
+
+    match exprs.as_slice() {
+        [Expr::Symbol(op), Expr::List(sig), rest @ ..] if op == "define" => {
+            let (name, params) = match sig.split_first()? {
+                (Expr::Symbol(name), params) => (name, params),
+                _ => return None,
+            };
+            let declared = definitions
+                .iter()
+                .find_map(|def| match declaration_parts(def) {
+                    Ok(Some((declared, signature))) if declared == name => Some(signature),
+                    _ => None,
+                })
+                .filter(|signature| signature.params.len() == params.len());
+            let params = params
+                .iter()
+                .enumerate()
+                .map(|(i, p)| match (p, &declared) {
+                    (Expr::Symbol(p), Some(signature)) => match signature.params[i] {
+                        Some(ty) => format!("({} : {})", p, ty),
+                        None => p.to_string(),
+                    },
+                    (Expr::Symbol(_) | Expr::List(_), _) => p.to_string(),
+                    _ => "?".to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(" ");
+            let ret = match (rest, declared.and_then(|signature| signature.ret)) {
+                ([Expr::Symbol(colon), ret, _, ..], _) if colon == ":" => ret.to_string(),
+                (_, Some(ret)) => ret.to_string(),
+                _ => "?".to_string(),
+            };
+            Some(format!("{}: ({}) -> {}", name, params, ret))
+        }
+        [Expr::Symbol(op), Expr::Symbol(name), ty] if op == ":" => {
+            Some(format!("{}: {}", name, ty))
+        }
+        [Expr::Symbol(op), Expr::Symbol(name), ..] if op == "define" => {
+            let ty = value.map(Value::type_name).unwrap_or("f64");
+            Some(format!("{}: {}", name, ty))
+        }
+        _ => None,
+    }
+}
+
+/// The C type compiled code passes a `ty` as, see `c_header`.
+fn c_type(ty: Ty) -> &'static str {
+    match ty {
+        Ty::F64 => "double",
+        Ty::I64 => "int64_t",
+        Ty::Bool => "bool",
+        Ty::Rational => "struct lisp_rational",
+        Ty::Vector => "struct lisp_vector",
+        Ty::Dynamic => "struct lisp_value",
+    }
+}
+
+/// The name C code calls function `name` by, when its types are all
+/// declared: `c_` and the name, with anything C does not allow in an
+/// identifier replaced by `_`.
+pub fn c_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("c_{}", name)
+}
+
+/// A C header declaring `functions`, each a name with its parameter and
+/// return types, under their C names, see `c_name`. They are defined in the
+/// object files the REPL's `:objects` command writes.
+pub fn c_header(functions: &[(String, Vec<Ty>, Ty)]) -> String {
+    let mut header = String::from(
+        "#pragma once\n\
+         #include <stdbool.h>\n\
+         #include <stdint.h>\n\
+         \n\
+         struct lisp_rational { int64_t num; int64_t den; };\n\
+         /* a vector of floats, f64* in annotations */\n\
+         struct lisp_vector { double *data; int64_t len; };\n\
+         /* any value, tagged with its type */\n\
+         struct lisp_value { uint64_t tag; uint64_t payload; };\n\
+         \n\
+         /* each function's last argument, unless null, is set to the error\n\
+            the call raised, to free with lisp_free_error, or to null if it\n\
+            raised none; the value returned after an error is meaningless */\n\
+         void lisp_free_error(char *error);\n\
+         \n",
+    );
+    for (name, params, ret) in functions {
+        let mut params: Vec<&str> = params.iter().map(|ty| c_type(*ty)).collect();
+        params.push("char **error");
+        let params = params.join(", ");
+        header.push_str(&format!(
+            "/* {} */\n{} {}({});\n",
+            name,
+            c_type(*ret),
+            c_name(name),
+            params
+        ));
+    }
+    header
+}
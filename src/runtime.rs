@@ -0,0 +1,1025 @@
+//! Functions that compiled code calls into. They are declared by name in each
+//! module as needed and mapped into its execution engine by `link`, and are
+//! exported unmangled under the same names for the object files
+//! `Session::write_objects` writes.
+//!
+//! Errors such as a failed type check cannot unwind through compiled code.
+//! Instead `raise` records the first one and the function hands back a
+//! harmless placeholder. Compiled code asks `lisp_error_pending` after every
+//! call that can raise and returns straight away if so, as does each of its
+//! callers in turn, and the session reports the error (discarding the
+//! result) once the outermost call returns.
+
+use crate::gc::{self, allocate};
+use crate::{Expr, Value};
+use inkwell::{execution_engine::ExecutionEngine, module::Module};
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::ffi::{c_char, CString};
+use std::io::{self, Write};
+use std::ptr;
+
+thread_local! {
+    static ERROR: RefCell<Option<String>> = RefCell::new(None);
+    /// Every symbol quoted so far, numbered in order, see `intern`.
+    static SYMBOLS: RefCell<Vec<String>> = RefCell::new(vec![]);
+    static OUTPUT: RefCell<Box<dyn Write>> = RefCell::new(Box::new(io::stdout()));
+}
+
+/// Records a Lisp error unless one is already pending.
+pub fn raise(message: impl Into<String>) {
+    ERROR.with(|error| {
+        error.borrow_mut().get_or_insert_with(|| message.into());
+    });
+}
+
+/// Takes the pending error, if compiled code raised one.
+pub fn take_error() -> Option<String> {
+    ERROR.with(|error| error.borrow_mut().take())
+}
+
+/// The number of the symbol `name`, the same for every occurrence of it, so
+/// that compiled code can compare symbols by their payloads.
+pub fn intern(name: &str) -> u64 {
+    SYMBOLS.with(|symbols| {
+        let mut symbols = symbols.borrow_mut();
+        let number = match symbols.iter().position(|symbol| symbol == name) {
+            Some(number) => number,
+            None => {
+                symbols.push(name.to_string());
+                symbols.len() - 1
+            }
+        };
+        number as u64
+    })
+}
+
+fn symbol_name(number: u64) -> String {
+    SYMBOLS.with(|symbols| symbols.borrow()[number as usize].clone())
+}
+
+/// 1 if an error has been raised and not yet taken, else 0.
+#[no_mangle]
+pub extern "C" fn lisp_error_pending() -> u64 {
+    ERROR.with(|error| error.borrow().is_some()) as u64
+}
+
+/// Takes the pending error for C code that called a `c_` entry, see
+/// `printer::c_header`: stores it at `error` as a string to free with
+/// `lisp_free_error`, or null if there was none, and returns 1 or 0 to
+/// match. A null `error` discards it.
+///
+/// # Safety
+///
+/// `error` must be null or valid to write a pointer to.
+#[no_mangle]
+pub unsafe extern "C" fn lisp_report_error(error: *mut *mut c_char) -> u64 {
+    let message = take_error();
+    let raised = message.is_some() as u64;
+    if !error.is_null() {
+        *error = message.map_or(ptr::null_mut(), |message| {
+            CString::new(message.replace('\0', "\\0"))
+                .unwrap()
+                .into_raw()
+        });
+    }
+    raised
+}
+
+/// Frees an error `lisp_report_error` handed over.
+///
+/// # Safety
+///
+/// `error` must be null or come from `lisp_report_error`, and not have been
+/// freed already.
+#[no_mangle]
+pub unsafe extern "C" fn lisp_free_error(error: *mut c_char) {
+    if !error.is_null() {
+        drop(CString::from_raw(error));
+    }
+}
+
+/// Sends what `display`, `write` and `newline` print to `output` rather than
+/// standard output, returning the writer it replaces.
+pub fn set_output(output: Box<dyn Write>) -> Box<dyn Write> {
+    OUTPUT.with(|current| current.replace(output))
+}
+
+fn print(text: &str) {
+    let result = OUTPUT.with(|output| {
+        let mut output = output.borrow_mut();
+        output
+            .write_all(text.as_bytes())
+            .and_then(|_| output.flush())
+    });
+    if let Err(err) = result {
+        raise(format!("cannot write output: {}", err));
+    }
+}
+
+/// The tag of a `Dynamic`, saying how to read its payload. The numeric tags
+/// come first so that `number?` is a single comparison.
+#[repr(u64)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tag {
+    /// The payload is the integer.
+    Int = 0,
+    /// The payload is the bits of the float.
+    Float = 1,
+    /// The payload points to a `Ratio`.
+    Rational = 2,
+    /// The payload is 0 or 1.
+    Bool = 3,
+    /// The payload is the Unicode scalar value.
+    Char = 4,
+    /// The payload points to a `Procedure`.
+    Procedure = 5,
+    /// The empty list. The payload is 0.
+    Nil = 6,
+    /// The payload points to a `Pair`.
+    Pair = 7,
+    /// The payload points to the elements of a vector, see `Vector`.
+    Vector = 8,
+    /// The payload points to a `String`.
+    String = 9,
+    /// What `display` and the like return. The payload is 0.
+    Unspecified = 10,
+    /// The payload is the symbol's number, see `intern`. Symbols are never
+    /// freed, so they are not heap objects.
+    Symbol = 11,
+}
+
+impl Tag {
+    pub const LAST_NUMBER: Tag = Tag::Rational;
+
+    fn from_u64(tag: u64) -> Option<Tag> {
+        [
+            Tag::Int,
+            Tag::Float,
+            Tag::Rational,
+            Tag::Bool,
+            Tag::Char,
+            Tag::Procedure,
+            Tag::Nil,
+            Tag::Pair,
+            Tag::Vector,
+            Tag::String,
+            Tag::Unspecified,
+            Tag::Symbol,
+        ]
+        .into_iter()
+        .find(|t| *t as u64 == tag)
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Tag::Int => "an integer",
+            Tag::Float => "a float",
+            Tag::Rational => "a rational",
+            Tag::Bool => "a boolean",
+            Tag::Char => "a character",
+            Tag::Procedure => "a procedure",
+            Tag::Nil => "the empty list",
+            Tag::Pair => "a pair",
+            Tag::Vector => "a vector",
+            Tag::String => "a string",
+            Tag::Unspecified => "an unspecified value",
+            Tag::Symbol => "a symbol",
+        }
+    }
+}
+
+/// A value whose type is only known at run time, as compiled code passes it
+/// around: `{ i64, i64 }`, a tag and a payload. Anything bigger than a word
+/// lives on the heap and the payload points to it, as pairs, vectors and
+/// strings do.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Dynamic {
+    pub tag: u64,
+    pub payload: u64,
+}
+
+/// A procedure value: the address of a function taking `arity` `Dynamic`s
+/// and returning one.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Procedure {
+    pub code: u64,
+    pub arity: u64,
+}
+
+/// A cons cell.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pair {
+    pub car: Dynamic,
+    pub cdr: Dynamic,
+}
+
+impl Dynamic {
+    pub fn int(n: i64) -> Dynamic {
+        Dynamic {
+            tag: Tag::Int as u64,
+            payload: n as u64,
+        }
+    }
+
+    pub fn float(x: f64) -> Dynamic {
+        Dynamic {
+            tag: Tag::Float as u64,
+            payload: x.to_bits(),
+        }
+    }
+
+    pub fn bool(b: bool) -> Dynamic {
+        Dynamic {
+            tag: Tag::Bool as u64,
+            payload: b as u64,
+        }
+    }
+
+    pub const NIL: Dynamic = Dynamic {
+        tag: Tag::Nil as u64,
+        payload: 0,
+    };
+
+    pub const UNSPECIFIED: Dynamic = Dynamic {
+        tag: Tag::Unspecified as u64,
+        payload: 0,
+    };
+
+    pub fn string(text: String) -> Dynamic {
+        Dynamic {
+            tag: Tag::String as u64,
+            payload: gc::allocate_string(text),
+        }
+    }
+
+    /// The text of this value, if it is a string.
+    fn text(&self) -> Option<&str> {
+        match self.tag() {
+            Some(Tag::String) => Some(unsafe { &*(self.payload as *const String) }),
+            _ => None,
+        }
+    }
+
+    pub fn cons(car: Dynamic, cdr: Dynamic) -> Dynamic {
+        Dynamic {
+            tag: Tag::Pair as u64,
+            payload: allocate(Pair { car, cdr }),
+        }
+    }
+
+    /// The address of the heap object the payload points to, if it does.
+    pub(crate) fn heap_object(self) -> Option<u64> {
+        let boxed = matches!(
+            self.tag(),
+            Some(Tag::Rational | Tag::Procedure | Tag::Pair | Tag::Vector | Tag::String)
+        );
+        boxed.then_some(self.payload)
+    }
+
+    /// The pair this value points to, if it is one.
+    fn pair(self) -> Option<Pair> {
+        match self.tag() {
+            Some(Tag::Pair) => Some(unsafe { *(self.payload as *const Pair) }),
+            _ => None,
+        }
+    }
+
+    /// The elements of this proper list consed onto `tail` in reverse order,
+    /// or `None` after raising an error if it is not a proper list. Walks the
+    /// list in place rather than collecting it into a `Vec`, which the
+    /// garbage collector would not see into.
+    fn reverse_onto(self, tail: Dynamic) -> Option<Dynamic> {
+        let mut reversed = tail;
+        let mut rest = self;
+        while let Some(pair) = rest.pair() {
+            reversed = Dynamic::cons(pair.car, reversed);
+            rest = pair.cdr;
+        }
+        if rest.tag() != Some(Tag::Nil) {
+            raise(format!("expected a list, got {}", self.type_name()));
+            return None;
+        }
+        Some(reversed)
+    }
+
+    /// Boxes a rational, keeping integral ones unboxed.
+    pub fn rational(ratio: Ratio) -> Dynamic {
+        if ratio.den == 1 {
+            return Dynamic::int(ratio.num);
+        }
+        Dynamic {
+            tag: Tag::Rational as u64,
+            payload: allocate(ratio),
+        }
+    }
+
+    fn tag(self) -> Option<Tag> {
+        Tag::from_u64(self.tag)
+    }
+
+    fn type_name(self) -> &'static str {
+        self.tag().map_or("an unknown value", Tag::name)
+    }
+
+    /// Decodes the value for the host.
+    pub fn value(self) -> Value {
+        match self.tag() {
+            Some(Tag::Int) => Value::Int(self.payload as i64),
+            Some(Tag::Float) => Value::Float(f64::from_bits(self.payload)),
+            Some(Tag::Rational) => unsafe { *(self.payload as *const Ratio) }.value(),
+            Some(Tag::Bool) => Value::Bool(self.payload != 0),
+            Some(Tag::Char) => {
+                Value::Char(char::from_u32(self.payload as u32).unwrap_or('\u{fffd}'))
+            }
+            Some(Tag::Procedure) => Value::Procedure,
+            Some(Tag::Nil) => Value::Nil,
+            Some(Tag::Pair) => {
+                let pair = self.pair().unwrap();
+                Value::Pair(Box::new(pair.car.value()), Box::new(pair.cdr.value()))
+            }
+            Some(Tag::String) => Value::Str(self.text().unwrap().to_string()),
+            Some(Tag::Unspecified) => Value::Unspecified,
+            Some(Tag::Symbol) => Value::Symbol(symbol_name(self.payload)),
+            Some(Tag::Vector) => Value::Vector(unsafe { vector_elements(self.payload) }.to_vec()),
+            None => panic!("invalid dynamic value tag {}", self.tag),
+        }
+    }
+}
+
+/// A vector of floats as compiled code passes it around, as
+/// `%vector = { double*, i64 }`. The elements are allocated together with
+/// a header word holding the length, just before the first element, so
+/// that a dynamic value only needs the `data` pointer.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Vector {
+    pub data: u64,
+    pub len: i64,
+}
+
+impl Vector {
+    pub fn value(self) -> Value {
+        Value::Vector(unsafe { vector_elements(self.data) }.to_vec())
+    }
+}
+
+/// The header and data of the empty vector handed back after an error, so
+/// that compiled code always has a vector to read the length of.
+static EMPTY_VECTOR: [u64; 2] = [0, 0];
+
+fn empty_vector() -> u64 {
+    EMPTY_VECTOR[1..].as_ptr() as u64
+}
+
+/// The elements of the vector whose first element is at `data`.
+///
+/// # Safety
+///
+/// `data` must come from `lisp_make_vector` and the vector still be live.
+unsafe fn vector_elements<'v>(data: u64) -> &'v [f64] {
+    let len = *(data as *const u64).sub(1);
+    std::slice::from_raw_parts(data as *const f64, len as usize)
+}
+
+/// An exact rational as compiled code passes it around, as `{ i64, i64 }`.
+/// Always in lowest terms with a positive denominator. Compiled code never
+/// sees a zero denominator: dividing by zero raises an error instead.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ratio {
+    pub num: i64,
+    pub den: i64,
+}
+
+fn gcd(mut a: i128, mut b: i128) -> i128 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a.abs()
+}
+
+impl Ratio {
+    /// Normalises `num/den`. Intermediate products are computed in i128, so
+    /// only results whose reduced terms do not fit an i64 overflow, which
+    /// raises an error.
+    pub fn new(num: i128, den: i128) -> Ratio {
+        Ratio::checked(num, den).unwrap_or_else(|| {
+            raise("rational overflow");
+            RATIO_ZERO
+        })
+    }
+
+    /// Normalises `num/den`, or `None` if its reduced terms do not fit an
+    /// i64.
+    pub fn checked(num: i128, den: i128) -> Option<Ratio> {
+        if den == 0 {
+            return Some(Ratio {
+                num: num.signum() as i64,
+                den: 0,
+            });
+        }
+        let divisor = gcd(num, den) * den.signum();
+        Some(Ratio {
+            num: i64::try_from(num / divisor).ok()?,
+            den: i64::try_from(den / divisor).ok()?,
+        })
+    }
+
+    /// The exact value of a finite float. Fractions finer than 2^-62 are
+    /// truncated, and infinities and NaN have no exact value, so they come
+    /// back as a division by zero.
+    pub fn from_f64(x: f64) -> Ratio {
+        if !x.is_finite() {
+            return Ratio { num: 0, den: 0 };
+        }
+        let mut num = x;
+        let mut den: i128 = 1;
+        while num.fract() != 0.0 && den < 1 << 62 {
+            num *= 2.0;
+            den *= 2;
+        }
+        Ratio::new(num as i128, den)
+    }
+
+    pub fn to_f64(self) -> f64 {
+        self.num as f64 / self.den as f64
+    }
+
+    /// The rational as a host value; integral ones become `Int`.
+    pub fn value(self) -> Value {
+        match self {
+            Ratio { num, den: 1 } => Value::Int(num),
+            Ratio { num, den } => Value::Rational(num, den),
+        }
+    }
+}
+
+/// The placeholder rational returned after raising an error.
+const RATIO_ZERO: Ratio = Ratio { num: 0, den: 1 };
+
+#[no_mangle]
+pub extern "C" fn lisp_rational_add(an: i64, ad: i64, bn: i64, bd: i64) -> Ratio {
+    let (an, ad, bn, bd) = (an as i128, ad as i128, bn as i128, bd as i128);
+    Ratio::new(an * bd + bn * ad, ad * bd)
+}
+
+#[no_mangle]
+pub extern "C" fn lisp_rational_sub(an: i64, ad: i64, bn: i64, bd: i64) -> Ratio {
+    let (an, ad, bn, bd) = (an as i128, ad as i128, bn as i128, bd as i128);
+    Ratio::new(an * bd - bn * ad, ad * bd)
+}
+
+#[no_mangle]
+pub extern "C" fn lisp_rational_mul(an: i64, ad: i64, bn: i64, bd: i64) -> Ratio {
+    Ratio::new(an as i128 * bn as i128, ad as i128 * bd as i128)
+}
+
+#[no_mangle]
+pub extern "C" fn lisp_rational_div(an: i64, ad: i64, bn: i64, bd: i64) -> Ratio {
+    if bn == 0 {
+        raise("division by zero");
+        return RATIO_ZERO;
+    }
+    Ratio::new(an as i128 * bd as i128, ad as i128 * bn as i128)
+}
+
+/// -1, 0 or 1 as `a` is less than, equal to or greater than `b`.
+#[no_mangle]
+pub extern "C" fn lisp_rational_compare(an: i64, ad: i64, bn: i64, bd: i64) -> i64 {
+    match (an as i128 * bd as i128).cmp(&(bn as i128 * ad as i128)) {
+        Ordering::Less => -1,
+        Ordering::Equal => 0,
+        Ordering::Greater => 1,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn lisp_rational_from_f64(x: f64) -> Ratio {
+    if !x.is_finite() {
+        raise(format!("{} has no exact value", Value::Float(x)));
+        return RATIO_ZERO;
+    }
+    Ratio::from_f64(x)
+}
+
+/// Returns `divisor` for `quotient`, `remainder` or `modulo` (`op` 0 to 2)
+/// of `dividend`, or 1 where the division would trap: after raising for a
+/// zero divisor, and for `i64::MIN` over -1, whose quotient overflows but
+/// whose remainder is 0 just as it is over 1.
+#[no_mangle]
+pub extern "C" fn lisp_check_divisor(op: u64, dividend: i64, divisor: i64) -> i64 {
+    if divisor == 0 {
+        raise("division by zero");
+        return 1;
+    }
+    if dividend == i64::MIN && divisor == -1 {
+        if op == 0 {
+            raise("integer overflow");
+        }
+        return 1;
+    }
+    divisor
+}
+
+#[no_mangle]
+pub extern "C" fn lisp_box_rational(num: i64, den: i64) -> Dynamic {
+    Dynamic::rational(Ratio { num, den })
+}
+
+#[no_mangle]
+pub extern "C" fn lisp_make_procedure(code: u64, arity: u64) -> Dynamic {
+    Dynamic {
+        tag: Tag::Procedure as u64,
+        payload: allocate(Procedure { code, arity }),
+    }
+}
+
+/// The payload of a value that must have tag `expected`, or 0 after raising
+/// a type error.
+#[no_mangle]
+pub extern "C" fn lisp_expect(tag: u64, payload: u64, expected: u64) -> u64 {
+    if tag == expected {
+        return payload;
+    }
+    let expected = Tag::from_u64(expected).map_or("?", Tag::name);
+    let actual = Dynamic { tag, payload }.type_name();
+    raise(format!("expected {}, got {}", expected, actual));
+    0
+}
+
+/// The code of a procedure that can be called with `nargs` arguments, or 0
+/// after raising an error.
+#[no_mangle]
+pub extern "C" fn lisp_check_procedure(tag: u64, payload: u64, nargs: u64) -> u64 {
+    let value = Dynamic { tag, payload };
+    if value.tag() != Some(Tag::Procedure) {
+        raise(format!("cannot call {}", value.type_name()));
+        return 0;
+    }
+    let procedure = unsafe { *(payload as *const Procedure) };
+    if procedure.arity != nargs {
+        raise(format!(
+            "procedure takes {} arguments, got {}",
+            procedure.arity, nargs
+        ));
+        return 0;
+    }
+    procedure.code
+}
+
+#[no_mangle]
+pub extern "C" fn lisp_cons(a_tag: u64, a_payload: u64, b_tag: u64, b_payload: u64) -> Dynamic {
+    Dynamic::cons(
+        Dynamic {
+            tag: a_tag,
+            payload: a_payload,
+        },
+        Dynamic {
+            tag: b_tag,
+            payload: b_payload,
+        },
+    )
+}
+
+/// The pair a value must be, or `None` after raising a type error.
+fn expect_pair(tag: u64, payload: u64) -> Option<Pair> {
+    let value = Dynamic { tag, payload };
+    let pair = value.pair();
+    if pair.is_none() {
+        raise(format!("expected a pair, got {}", value.type_name()));
+    }
+    pair
+}
+
+/// The car of a pair, or the empty list after raising an error.
+#[no_mangle]
+pub extern "C" fn lisp_car(tag: u64, payload: u64) -> Dynamic {
+    expect_pair(tag, payload).map_or(Dynamic::NIL, |pair| pair.car)
+}
+
+#[no_mangle]
+pub extern "C" fn lisp_cdr(tag: u64, payload: u64) -> Dynamic {
+    expect_pair(tag, payload).map_or(Dynamic::NIL, |pair| pair.cdr)
+}
+
+/// The number of elements in a proper list, or 0 after raising an error.
+#[no_mangle]
+pub extern "C" fn lisp_length(tag: u64, payload: u64) -> i64 {
+    let list = Dynamic { tag, payload };
+    let mut length = 0;
+    let mut rest = list;
+    while let Some(pair) = rest.pair() {
+        length += 1;
+        rest = pair.cdr;
+    }
+    if rest.tag() != Some(Tag::Nil) {
+        raise(format!("expected a list, got {}", list.type_name()));
+        return 0;
+    }
+    length
+}
+
+/// A copy of the first list followed by the second, which is shared and
+/// need not be a list at all, as in Scheme.
+#[no_mangle]
+pub extern "C" fn lisp_append(a_tag: u64, a_payload: u64, b_tag: u64, b_payload: u64) -> Dynamic {
+    let tail = Dynamic {
+        tag: b_tag,
+        payload: b_payload,
+    };
+    let list = Dynamic {
+        tag: a_tag,
+        payload: a_payload,
+    };
+    list.reverse_onto(Dynamic::NIL)
+        .and_then(|reversed| reversed.reverse_onto(tail))
+        .unwrap_or(Dynamic::NIL)
+}
+
+#[no_mangle]
+pub extern "C" fn lisp_reverse(tag: u64, payload: u64) -> Dynamic {
+    let list = Dynamic { tag, payload };
+    list.reverse_onto(Dynamic::NIL).unwrap_or(Dynamic::NIL)
+}
+
+/// A vector of `len` copies of `fill`, as the address of its first element.
+#[no_mangle]
+pub extern "C" fn lisp_make_vector(len: i64, fill: f64) -> u64 {
+    if len < 0 {
+        raise(format!("vector length {} is negative", len));
+        return empty_vector();
+    }
+    gc::allocate_vector(len as usize, fill)
+}
+
+/// The data of a value that must be a vector, or the empty vector after
+/// raising a type error.
+#[no_mangle]
+pub extern "C" fn lisp_expect_vector(tag: u64, payload: u64) -> u64 {
+    if tag == Tag::Vector as u64 {
+        return payload;
+    }
+    let value = Dynamic { tag, payload };
+    raise(format!("expected a vector, got {}", value.type_name()));
+    empty_vector()
+}
+
+/// Raises the error for integer arithmetic whose result does not fit in 64
+/// bits, returning a placeholder for it.
+#[no_mangle]
+pub extern "C" fn lisp_overflow_error() -> i64 {
+    raise("integer overflow");
+    0
+}
+
+/// Raises the error for an index outside a vector. The compiled code returns
+/// straight after, so the element it returns is never used.
+#[no_mangle]
+pub extern "C" fn lisp_index_error(index: i64, len: i64) -> f64 {
+    raise(format!(
+        "index {} is out of range for a vector of length {}",
+        index, len
+    ));
+    0.0
+}
+
+/// Copies the `len` bytes of UTF-8 at `text` into a new string.
+#[no_mangle]
+pub extern "C" fn lisp_make_string(text: u64, len: u64) -> Dynamic {
+    let bytes = unsafe { std::slice::from_raw_parts(text as *const u8, len as usize) };
+    Dynamic::string(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// The text of a value that must be a string, or `None` after raising a
+/// type error.
+fn expect_string<'s>(value: &'s Dynamic) -> Option<&'s str> {
+    let text = value.text();
+    if text.is_none() {
+        raise(format!("expected a string, got {}", value.type_name()));
+    }
+    text
+}
+
+/// Prints a value as `display` does: strings and characters as they are,
+/// anything else as the REPL would.
+#[no_mangle]
+pub extern "C" fn lisp_display(tag: u64, payload: u64) -> Dynamic {
+    print(&Dynamic { tag, payload }.value().display().to_string());
+    Dynamic::UNSPECIFIED
+}
+
+/// Prints a value as the REPL would, so that it reads back.
+#[no_mangle]
+pub extern "C" fn lisp_write(tag: u64, payload: u64) -> Dynamic {
+    print(&Dynamic { tag, payload }.value().to_string());
+    Dynamic::UNSPECIFIED
+}
+
+#[no_mangle]
+pub extern "C" fn lisp_newline() -> Dynamic {
+    print("\n");
+    Dynamic::UNSPECIFIED
+}
+
+#[no_mangle]
+pub extern "C" fn lisp_string_append(
+    a_tag: u64,
+    a_payload: u64,
+    b_tag: u64,
+    b_payload: u64,
+) -> Dynamic {
+    let a = Dynamic {
+        tag: a_tag,
+        payload: a_payload,
+    };
+    let b = Dynamic {
+        tag: b_tag,
+        payload: b_payload,
+    };
+    match (expect_string(&a), expect_string(&b)) {
+        (Some(a), Some(b)) => Dynamic::string(format!("{}{}", a, b)),
+        _ => Dynamic::string(String::new()),
+    }
+}
+
+/// The number of characters in a string, or 0 after raising an error.
+#[no_mangle]
+pub extern "C" fn lisp_string_length(tag: u64, payload: u64) -> i64 {
+    let value = Dynamic { tag, payload };
+    expect_string(&value).map_or(0, |text| text.chars().count() as i64)
+}
+
+#[no_mangle]
+pub extern "C" fn lisp_number_to_string(tag: u64, payload: u64) -> Dynamic {
+    let value = Dynamic { tag, payload };
+    if Number::expect(value).is_none() {
+        return Dynamic::string(String::new());
+    }
+    Dynamic::string(value.value().to_string())
+}
+
+/// The number a string reads as, or `#f` if it is not a number literal.
+#[no_mangle]
+pub extern "C" fn lisp_string_to_number(tag: u64, payload: u64) -> Dynamic {
+    let value = Dynamic { tag, payload };
+    let number = expect_string(&value).and_then(|text| crate::read(text.trim()).ok());
+    match number {
+        Some(Expr::Integer(n)) => Dynamic::int(n),
+        Some(Expr::Float(x)) => Dynamic::float(x),
+        Some(Expr::Rational(num, den)) => Dynamic::rational(Ratio { num, den }),
+        _ => Dynamic::bool(false),
+    }
+}
+
+/// Collects garbage, returning how many objects were freed.
+#[no_mangle]
+pub extern "C" fn lisp_gc() -> i64 {
+    gc::collect() as i64
+}
+
+/// A dynamic value that must be a number.
+#[derive(Clone, Copy)]
+enum Number {
+    Int(i64),
+    Float(f64),
+    Rational(Ratio),
+}
+
+impl Number {
+    fn of(value: Dynamic) -> Option<Number> {
+        match value.tag()? {
+            Tag::Int => Some(Number::Int(value.payload as i64)),
+            Tag::Float => Some(Number::Float(f64::from_bits(value.payload))),
+            Tag::Rational => Some(Number::Rational(unsafe {
+                *(value.payload as *const Ratio)
+            })),
+            _ => None,
+        }
+    }
+
+    /// Like `of`, raising a type error for anything else.
+    fn expect(value: Dynamic) -> Option<Number> {
+        let number = Number::of(value);
+        if number.is_none() {
+            raise(format!("expected a number, got {}", value.type_name()));
+        }
+        number
+    }
+
+    fn to_f64(self) -> f64 {
+        match self {
+            Number::Int(n) => n as f64,
+            Number::Float(x) => x,
+            Number::Rational(ratio) => ratio.to_f64(),
+        }
+    }
+
+    fn to_ratio(self) -> Ratio {
+        match self {
+            Number::Int(n) => Ratio { num: n, den: 1 },
+            Number::Float(x) => lisp_rational_from_f64(x),
+            Number::Rational(ratio) => ratio,
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn lisp_to_f64(tag: u64, payload: u64) -> f64 {
+    Number::expect(Dynamic { tag, payload }).map_or(0.0, Number::to_f64)
+}
+
+#[no_mangle]
+pub extern "C" fn lisp_to_rational(tag: u64, payload: u64) -> Ratio {
+    Number::expect(Dynamic { tag, payload }).map_or(RATIO_ZERO, Number::to_ratio)
+}
+
+/// `+ - * /` (`op` 0 to 3) on dynamic numbers, following the same rules as
+/// the compiled versions: integers stay integers except under `/`, and any
+/// float makes the result a float.
+#[no_mangle]
+pub extern "C" fn lisp_dynamic_arithmetic(
+    op: u64,
+    a_tag: u64,
+    a_payload: u64,
+    b_tag: u64,
+    b_payload: u64,
+) -> Dynamic {
+    let a = Number::expect(Dynamic {
+        tag: a_tag,
+        payload: a_payload,
+    });
+    let b = Number::expect(Dynamic {
+        tag: b_tag,
+        payload: b_payload,
+    });
+    let (a, b) = match (a, b) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return Dynamic::int(0),
+    };
+    match (a, b) {
+        (Number::Int(a), Number::Int(b)) if op != 3 => {
+            let result = match op {
+                0 => a.checked_add(b),
+                1 => a.checked_sub(b),
+                _ => a.checked_mul(b),
+            };
+            result.map_or_else(|| Dynamic::int(lisp_overflow_error()), Dynamic::int)
+        }
+        (Number::Float(_), _) | (_, Number::Float(_)) => {
+            let (a, b) = (a.to_f64(), b.to_f64());
+            Dynamic::float(match op {
+                0 => a + b,
+                1 => a - b,
+                2 => a * b,
+                _ => a / b,
+            })
+        }
+        _ => {
+            let (a, b) = (a.to_ratio(), b.to_ratio());
+            let f = [
+                lisp_rational_add,
+                lisp_rational_sub,
+                lisp_rational_mul,
+                lisp_rational_div,
+            ][op as usize];
+            Dynamic::rational(f(a.num, a.den, b.num, b.den))
+        }
+    }
+}
+
+/// `quotient`, `remainder` or `modulo` (`op` 0 to 2) on dynamic numbers,
+/// exact on integers and on floats otherwise, as the compiled versions are.
+#[no_mangle]
+pub extern "C" fn lisp_dynamic_division(
+    op: u64,
+    a_tag: u64,
+    a_payload: u64,
+    b_tag: u64,
+    b_payload: u64,
+) -> Dynamic {
+    let a = Number::expect(Dynamic {
+        tag: a_tag,
+        payload: a_payload,
+    });
+    let b = Number::expect(Dynamic {
+        tag: b_tag,
+        payload: b_payload,
+    });
+    match (a, b) {
+        (Some(Number::Int(a)), Some(Number::Int(b))) => {
+            let b = lisp_check_divisor(op, a, b);
+            let rem = a % b;
+            Dynamic::int(match op {
+                0 => a / b,
+                1 => rem,
+                _ if rem != 0 && (rem ^ b) < 0 => rem + b,
+                _ => rem,
+            })
+        }
+        (Some(a), Some(b)) => {
+            let (a, b) = (a.to_f64(), b.to_f64());
+            let rem = a % b;
+            Dynamic::float(match op {
+                0 => (a / b).trunc(),
+                1 => rem,
+                _ if rem != 0.0 && (rem < 0.0) != (b < 0.0) => rem + b,
+                _ => rem,
+            })
+        }
+        _ => Dynamic::int(0),
+    }
+}
+
+/// `= < > <= >=` (`op` 0 to 4) on dynamic numbers, as 0 or 1.
+#[no_mangle]
+pub extern "C" fn lisp_dynamic_compare(
+    op: u64,
+    a_tag: u64,
+    a_payload: u64,
+    b_tag: u64,
+    b_payload: u64,
+) -> u64 {
+    let a = Number::expect(Dynamic {
+        tag: a_tag,
+        payload: a_payload,
+    });
+    let b = Number::expect(Dynamic {
+        tag: b_tag,
+        payload: b_payload,
+    });
+    let ordering = match (a, b) {
+        (Some(Number::Float(x)), Some(b)) => x.partial_cmp(&b.to_f64()),
+        (Some(a), Some(Number::Float(y))) => a.to_f64().partial_cmp(&y),
+        (Some(a), Some(b)) => {
+            let (a, b) = (a.to_ratio(), b.to_ratio());
+            Some(lisp_rational_compare(a.num, a.den, b.num, b.den).cmp(&0))
+        }
+        _ => None,
+    };
+    let holds = match (op, ordering) {
+        (_, None) => false,
+        (0, Some(ordering)) => ordering == Ordering::Equal,
+        (1, Some(ordering)) => ordering == Ordering::Less,
+        (2, Some(ordering)) => ordering == Ordering::Greater,
+        (3, Some(ordering)) => ordering != Ordering::Greater,
+        (_, Some(ordering)) => ordering != Ordering::Less,
+    };
+    holds as u64
+}
+
+/// Every runtime function by the symbol compiled code declares it under.
+fn symbols() -> Vec<(&'static str, usize)> {
+    vec![
+        ("lisp_error_pending", lisp_error_pending as usize),
+        ("lisp_report_error", lisp_report_error as usize),
+        ("lisp_rational_add", lisp_rational_add as usize),
+        ("lisp_rational_sub", lisp_rational_sub as usize),
+        ("lisp_rational_mul", lisp_rational_mul as usize),
+        ("lisp_rational_div", lisp_rational_div as usize),
+        ("lisp_rational_compare", lisp_rational_compare as usize),
+        ("lisp_rational_from_f64", lisp_rational_from_f64 as usize),
+        ("lisp_check_divisor", lisp_check_divisor as usize),
+        ("lisp_box_rational", lisp_box_rational as usize),
+        ("lisp_make_procedure", lisp_make_procedure as usize),
+        ("lisp_expect", lisp_expect as usize),
+        ("lisp_check_procedure", lisp_check_procedure as usize),
+        ("lisp_to_f64", lisp_to_f64 as usize),
+        ("lisp_to_rational", lisp_to_rational as usize),
+        ("lisp_dynamic_arithmetic", lisp_dynamic_arithmetic as usize),
+        ("lisp_dynamic_division", lisp_dynamic_division as usize),
+        ("lisp_dynamic_compare", lisp_dynamic_compare as usize),
+        ("lisp_cons", lisp_cons as usize),
+        ("lisp_car", lisp_car as usize),
+        ("lisp_cdr", lisp_cdr as usize),
+        ("lisp_length", lisp_length as usize),
+        ("lisp_append", lisp_append as usize),
+        ("lisp_reverse", lisp_reverse as usize),
+        ("lisp_gc", lisp_gc as usize),
+        ("lisp_make_vector", lisp_make_vector as usize),
+        ("lisp_expect_vector", lisp_expect_vector as usize),
+        ("lisp_index_error", lisp_index_error as usize),
+        ("lisp_overflow_error", lisp_overflow_error as usize),
+        ("lisp_make_string", lisp_make_string as usize),
+        ("lisp_display", lisp_display as usize),
+        ("lisp_write", lisp_write as usize),
+        ("lisp_newline", lisp_newline as usize),
+        ("lisp_string_append", lisp_string_append as usize),
+        ("lisp_string_length", lisp_string_length as usize),
+        ("lisp_number_to_string", lisp_number_to_string as usize),
+        ("lisp_string_to_number", lisp_string_to_number as usize),
+    ]
+}
+
+/// Points the runtime functions `module` declares at their host addresses.
+/// Must be called before looking up anything in `ee`.
+pub fn link(module: &Module, ee: &ExecutionEngine) {
+    for (name, address) in symbols() {
+        if let Some(function) = module.get_function(name) {
+            ee.add_global_mapping(&function, address);
+        }
+    }
+}
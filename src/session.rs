@@ -0,0 +1,970 @@
+use crate::expand::Expander;
+use crate::gc;
+use crate::infer::Inference;
+use crate::lift::{at, at_mut, extend, lift_definitions, scoped_parts};
+use crate::modules::{defined_name, import_target, module_header, qualify, rename, Import};
+use crate::printer::{c_header, c_name, Verbosity};
+use crate::runtime::{self, Dynamic, Ratio, Tag, Vector};
+use crate::{
+    declaration_parts, function_parts, global_symbol, read_all, return_ty, Compiler, Expr, Ty,
+    Value, WIDENED,
+};
+use inkwell::{
+    builder::Builder,
+    context::Context,
+    execution_engine::ExecutionEngine,
+    module::{Linkage, Module},
+    passes::PassManager,
+    targets::{CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine},
+    types::FunctionType,
+    values::FunctionValue,
+    OptimizationLevel,
+};
+use std::{
+    cell::Cell,
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// The standard library, see `Session::load_prelude`.
+pub const PRELUDE: &str = include_str!("prelude.lisp");
+
+/// The functions of two arguments in `PRELUDE` that take any number of
+/// them: `(max a b c)` is `(max (max a b) c)`, and `(max a)` is `a`.
+const FOLDED: [&str; 2] = ["min", "max"];
+
+/// Host storage for one global variable, big enough for any `Ty`. Compiled
+/// code reads and writes it through the address `link_globals` maps.
+type Slot = [Cell<u64>; 2];
+
+/// A module that has been loaded, see `Session::load_module`.
+struct LoadedModule {
+    /// When its file was last modified, as of loading it.
+    modified: SystemTime,
+    /// exported name -> qualified name
+    exports: HashMap<String, String>,
+}
+
+/// A function compiled into an LLVM module of its own, see
+/// `Session::compile_unit`, which later modules declare and link to rather
+/// than compile again.
+#[derive(Clone, Copy)]
+struct Linked<'ctx> {
+    ty: FunctionType<'ctx>,
+    call_conventions: u32,
+    address: usize,
+    /// The index of the module it was compiled into in `Session::units`.
+    unit: usize,
+}
+
+/// An LLVM module `Session::compile_unit` compiled: the prelude's or a
+/// loaded module's.
+struct Unit<'ctx> {
+    /// The prelude or the module's name, which its object file is named
+    /// after, see `Session::write_objects`.
+    name: String,
+    /// The definitions it was compiled for.
+    functions: Vec<String>,
+    /// The definitions it has a version of, and so depends on.
+    depends: HashSet<String>,
+    /// A copy of it as compiled, before the JIT took it.
+    module: Module<'ctx>,
+}
+
+/// What evaluating one top-level form produced.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    /// An expression (or variable definition) was run and returned this.
+    Value(Value),
+    /// A function was defined; it is recompiled into every later module.
+    Defined,
+}
+
+/// The state of one REPL session: every definition made so far, and the
+/// transcript of forms that evaluated successfully.
+pub struct Session<'ctx> {
+    context: &'ctx Context,
+    builder: Builder<'ctx>,
+    fpm: PassManager<FunctionValue<'ctx>>,
+    // the function pass manager has to be created for some module, which must
+    // outlive it (fields drop in declaration order)
+    _fpm_module: Module<'ctx>,
+    global_scope: HashMap<String, Ty>,
+    // boxed so the addresses handed to compiled code stay put
+    slots: HashMap<String, Box<Slot>>,
+    previous_exprs: Vec<Expr>,
+    transcript: Vec<Expr>,
+    expander: Expander,
+    /// imported name -> qualified name, see `modules`
+    imports: HashMap<String, String>,
+    /// Every module loaded, by the canonical path of its file.
+    modules: HashMap<PathBuf, LoadedModule>,
+    /// The module files being loaded, innermost last, to catch a module
+    /// that imports itself.
+    loading: Vec<PathBuf>,
+    /// Where `(import (lib name))` looks for `name.lisp`.
+    pub library_path: Vec<PathBuf>,
+    /// The functions defined by the prelude and not redefined since.
+    prelude: HashSet<String>,
+    /// Functions already compiled, by their compiled names, see
+    /// `compile_unit`.
+    linked: HashMap<String, Linked<'ctx>>,
+    /// Every module `compile_unit` compiled.
+    units: Vec<Unit<'ctx>>,
+    /// Every engine that has run code. A procedure value points into the
+    /// machine code of the module it was made in, and can outlive the eval
+    /// that made it in a global variable, so none is ever freed.
+    engines: Vec<ExecutionEngine<'ctx>>,
+    loop_counter: usize, // used for module name
+    pub verbosity: Verbosity,
+}
+
+impl<'ctx> Session<'ctx> {
+    pub fn new(context: &'ctx Context) -> Self {
+        let fpm_module = context.create_module("repl");
+        let fpm = PassManager::create(&fpm_module);
+        // fpm.add_instruction_combining_pass();
+        // fpm.add_reassociate_pass();
+        // fpm.add_gvn_pass();
+        // fpm.add_cfg_simplification_pass();
+        // fpm.add_basic_alias_analysis_pass();
+        // fpm.add_promote_memory_to_register_pass();
+        // fpm.add_instruction_combining_pass();
+        // fpm.add_reassociate_pass();
+        // fpm.initialize();
+
+        Session {
+            context,
+            builder: context.create_builder(),
+            fpm,
+            _fpm_module: fpm_module,
+            global_scope: HashMap::new(),
+            slots: HashMap::new(),
+            previous_exprs: vec![],
+            transcript: vec![],
+            expander: Expander::new(),
+            imports: HashMap::new(),
+            modules: HashMap::new(),
+            loading: vec![],
+            library_path: vec![PathBuf::from("lib")],
+            prelude: HashSet::new(),
+            linked: HashMap::new(),
+            units: vec![],
+            engines: vec![],
+            loop_counter: 0,
+            verbosity: Verbosity::default(),
+        }
+    }
+
+    /// Number of forms evaluated so far, including failed ones.
+    pub fn loop_counter(&self) -> usize {
+        self.loop_counter
+    }
+
+    /// The forms that evaluated successfully, in order.
+    pub fn transcript(&self) -> &[Expr] {
+        &self.transcript
+    }
+
+    /// Imports the module `form` names, or expands the macros in `form` and
+    /// renames what it imported, then compiles it into a fresh module
+    /// alongside every earlier definition, and runs it if it is an
+    /// expression.
+    pub fn eval(&mut self, form: &Expr) -> Result<Outcome, String> {
+        let outcome = match import_target(form)? {
+            Some(import) => {
+                let path = self.resolve(&import, Path::new(""))?;
+                let exports = self.load_module(&path)?;
+                self.imports.extend(exports);
+                Outcome::Defined
+            }
+            // definitions are kept expanded, so a macro only applies to later
+            // forms
+            None => match self.expander.expand_top(form)? {
+                Some(expr) => {
+                    // a definition of an imported name shadows the import,
+                    // and replaces the prelude's
+                    if let Some(name) = defined_name(&expr)? {
+                        self.imports.remove(name);
+                        self.prelude.remove(name);
+                    }
+                    let expr = self.fold_prelude_calls(&rename(&expr, &self.imports));
+                    self.compile_and_run(&expr)?
+                }
+                None => Outcome::Defined,
+            },
+        };
+        self.transcript.push(form.clone());
+        Ok(outcome)
+    }
+
+    /// `expr` with its calls to the prelude's functions in `FOLDED` folded
+    /// into calls with two arguments, unless they have been redefined.
+    fn fold_prelude_calls(&self, expr: &Expr) -> Expr {
+        let names: Vec<&str> = FOLDED
+            .iter()
+            .copied()
+            .filter(|name| self.prelude.contains(*name))
+            .collect();
+        fold_calls(expr, &names, &[])
+    }
+
+    /// Compiles `expr` into a fresh module alongside every earlier
+    /// definition, and runs it if it is an expression.
+    fn compile_and_run(&mut self, expr: &Expr) -> Result<Outcome, String> {
+        let mod_name = format!("repl_{}", self.loop_counter);
+        self.loop_counter += 1;
+
+        // a redefinition replaces the old one, so the module never sees two
+        // functions of the same name; likewise for declarations
+        let defined = definition_key(expr)?;
+        let mut definitions: Vec<Expr> = self
+            .previous_exprs
+            .iter()
+            .filter(|prev| match (defined, definition_key(prev)) {
+                (Some(key), Ok(Some(prev_key))) => prev_key != key,
+                _ => true,
+            })
+            .cloned()
+            .collect();
+        let kept = definitions.len();
+        if let Some((key, _)) = defined {
+            definitions.push(expr.clone());
+            self.unlink(key);
+        }
+        // functions defined in other functions' bodies are compiled as
+        // top-level ones, see `lift`
+        let lifted = definitions
+            .iter()
+            .map(lift_definitions)
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut forms: Vec<Expr> = lifted.iter().flatten().cloned().collect();
+        // so is a named let that is not a loop, even outside a definition
+        let expr_lifted = match defined {
+            Some(_) => vec![],
+            None => lift_definitions(expr)?,
+        };
+        if let Some((_, functions)) = expr_lifted.split_last() {
+            forms.extend(functions.iter().cloned());
+        }
+
+        if self.verbosity >= Verbosity::Parse {
+            println!("{:?}", expr);
+        }
+
+        // a declaration has nothing to compile itself; recompiling the
+        // definitions has already checked them against it
+        let target = match defined {
+            Some((_, true)) => None,
+            Some(_) => lifted.last().and_then(|lifted| lifted.last()),
+            None => expr_lifted.last(),
+        };
+        // a form that fails to compile must not change any variable's type
+        let saved_scope = self.global_scope.clone();
+        let (module, function) = loop {
+            let module = self.context.create_module(&mod_name);
+            match self.compile_module(&module, &definitions[..kept], &lifted, &forms, target) {
+                // code compiled once already reads global variables as the
+                // types they were, so if one changed it is compiled again
+                Ok(_)
+                    if !self.linked.is_empty()
+                        && saved_scope
+                            .iter()
+                            .any(|(name, ty)| self.global_scope.get(name) != Some(ty)) =>
+                {
+                    self.linked.clear();
+                    continue;
+                }
+                Ok(function) => break (module, function),
+                // a set! boxed a global variable, see `Compiler::compile_set`
+                Err(err) if err == WIDENED => continue,
+                Err(err) => {
+                    self.global_scope = saved_scope;
+                    return Err(err);
+                }
+            }
+        };
+        // and one that fails at run time must not change any variable at all
+        let saved_values: HashMap<String, [u64; 2]> = self
+            .slots
+            .iter()
+            .map(|(name, slot)| (name.clone(), [slot[0].get(), slot[1].get()]))
+            .collect();
+        self.box_globals(&saved_scope);
+
+        let function = match function {
+            Some(function) => function,
+            None => {
+                self.previous_exprs = definitions;
+                return Ok(Outcome::Defined);
+            }
+        };
+        let function_name = function.get_name().to_str().unwrap();
+
+        if !function_name.contains("anon") {
+            if self.verbosity >= Verbosity::Trace {
+                println!("NON_ANON");
+                println!("{:?}\n\n", function);
+            }
+
+            self.previous_exprs = definitions;
+            return Ok(Outcome::Defined);
+        }
+
+        let ee = module
+            .create_jit_execution_engine(OptimizationLevel::None)
+            .map_err(|err| err.to_string())?;
+        runtime::link(&module, &ee);
+        self.link_globals(&module, &ee);
+        self.link_functions(&module, &ee);
+
+        if self.verbosity >= Verbosity::Trace {
+            println!("about to call ");
+        }
+        // clear anything an earlier run that failed to start left behind
+        runtime::take_error();
+        let value = match return_ty(function) {
+            Ty::I64 => Value::Int(run(&ee, function_name)?),
+            Ty::F64 => Value::Float(run(&ee, function_name)?),
+            Ty::Rational => run::<Ratio>(&ee, function_name)?.value(),
+            // an i1 comes back in the low bit of a byte
+            Ty::Bool => Value::Bool(run::<u8>(&ee, function_name)? & 1 != 0),
+            Ty::Dynamic => run::<Dynamic>(&ee, function_name)?.value(),
+            Ty::Vector => run::<Vector>(&ee, function_name)?.value(),
+        };
+        self.engines.push(ee);
+        if let Some(err) = runtime::take_error() {
+            self.restore_globals(saved_scope, &saved_values);
+            return Err(err);
+        }
+        if self.verbosity >= Verbosity::Trace {
+            println!("CALL=> {}", value);
+        }
+
+        Ok(Outcome::Value(value))
+    }
+
+    /// Compiles into `module` every definition in `previous` but the prelude
+    /// (each lifted as in `lifted`), and then `target`, if there is one,
+    /// returning what that compiled to. `forms` are all the definitions.
+    fn compile_module(
+        &mut self,
+        module: &Module<'ctx>,
+        previous: &[Expr],
+        lifted: &[Vec<Expr>],
+        forms: &[Expr],
+        target: Option<&Expr>,
+    ) -> Result<Option<FunctionValue<'ctx>>, String> {
+        // functions compiled once already are declared, and linked to when
+        // the module runs
+        for (name, linked) in &self.linked {
+            let function = module.add_function(name, linked.ty, None);
+            function.set_call_conventions(linked.call_conventions);
+        }
+
+        // recompile every previously parsed function into the new module
+        // this clears the anon so we dont get anon.1 anon.2 etc
+        for (prev, lifted) in previous.iter().zip(lifted) {
+            // declarations only inform the definitions they declare
+            if let Ok(Some(_)) = declaration_parts(prev) {
+                continue;
+            }
+            // and a function compiled once already is only compiled again
+            // for calls with other types
+            if let Ok(Some((name, _))) = definition_key(prev) {
+                if self
+                    .linked
+                    .keys()
+                    .any(|compiled| is_version_of(compiled, name))
+                {
+                    continue;
+                }
+            }
+            // the prelude is known to compile, so it is only compiled for the
+            // calls that need it
+            if let Ok(Some((name, _))) = definition_key(prev) {
+                if self.prelude.contains(name) {
+                    continue;
+                }
+            }
+            Compiler::compile(
+                self.context,
+                &self.builder,
+                &self.fpm,
+                module,
+                lifted.last().unwrap(),
+                forms,
+                &mut self.global_scope,
+            )
+            .map_err(|err| match err {
+                WIDENED => err.to_string(),
+                err => format!(
+                    "Cannot re-add previously compiled function {}: {}",
+                    prev, err
+                ),
+            })?;
+        }
+
+        let target = match target {
+            Some(target) => target,
+            None => return Ok(None),
+        };
+        let result = Compiler::compile(
+            self.context,
+            &self.builder,
+            &self.fpm,
+            module,
+            target,
+            forms,
+            &mut self.global_scope,
+        );
+
+        if self.verbosity >= Verbosity::Trace {
+            println!(
+                "GLOBAL_SCOPE:\n\n {:?}\n\nMODULE CONTENTS: \n\n{}",
+                self.global_scope,
+                module.to_string()
+            );
+        }
+
+        result.map(Some).map_err(str::to_string)
+    }
+
+    /// Boxes the value of every global variable that has been widened to
+    /// `Ty::Dynamic` since it had the type in `before`, see
+    /// `Compiler::compile_set`.
+    fn box_globals(&self, before: &HashMap<String, Ty>) {
+        for (name, slot) in &self.slots {
+            let ty = match (before.get(name), self.global_scope.get(name)) {
+                (Some(&ty), Some(Ty::Dynamic)) if ty != Ty::Dynamic => ty,
+                _ => continue,
+            };
+            let boxed = boxed(ty, [slot[0].get(), slot[1].get()]);
+            slot[0].set(boxed.tag);
+            slot[1].set(boxed.payload);
+        }
+    }
+
+    /// Puts the global variables back as they were before a form that
+    /// failed at run time, with the types in `scope` and the values in
+    /// `values`, and drops the slots of any it defined.
+    fn restore_globals(&mut self, scope: HashMap<String, Ty>, values: &HashMap<String, [u64; 2]>) {
+        self.global_scope = scope;
+        self.slots.retain(|name, slot| match values.get(name) {
+            Some([a, b]) => {
+                slot[0].set(*a);
+                slot[1].set(*b);
+                true
+            }
+            None => {
+                gc::remove_root(slot.as_ptr() as *const u64);
+                false
+            }
+        });
+    }
+
+    /// What `expr` expands to with the macros defined so far.
+    pub fn macroexpand(&mut self, expr: &Expr) -> Result<Expr, String> {
+        Ok(self.expander.expand(expr)?)
+    }
+
+    /// Defines the functions of `PRELUDE`, `abs`, `map`, `fold` and the like,
+    /// and compiles them once, into a module of their own that later modules
+    /// link to, see `compile_unit`.
+    pub fn load_prelude(&mut self) -> Result<(), String> {
+        let mut functions = vec![];
+        for expr in read_all(PRELUDE)? {
+            // compiling the unit checks them, so they are only recorded here
+            let name = match definition_key(&expr)? {
+                Some((name, false)) => name.to_string(),
+                _ => return Err(format!("the prelude defines only functions (in {})", expr)),
+            };
+            self.previous_exprs
+                .retain(|prev| definition_key(prev) != Ok(Some((name.as_str(), false))));
+            self.previous_exprs.push(expr);
+            self.prelude.insert(name.clone());
+            functions.push(name);
+        }
+        let functions: Vec<&str> = functions.iter().map(String::as_str).collect();
+        self.compile_unit("prelude", &functions)
+            .map_err(|err| format!("{} (in the prelude)", err))
+    }
+
+    /// The file `import` names; a relative path is taken relative to `dir`.
+    fn resolve(&self, import: &Import, dir: &Path) -> Result<PathBuf, String> {
+        match import {
+            Import::File(file) => Ok(dir.join(file)),
+            Import::Library(name) => self
+                .library_path
+                .iter()
+                .map(|lib| lib.join(format!("{}.lisp", name)))
+                .find(|path| path.exists())
+                .ok_or_else(|| format!("cannot find library {}", name)),
+        }
+    }
+
+    /// Loads the module in file `path`, unless it was loaded before and has
+    /// not been modified since, and returns what its exports stand for.
+    fn load_module(&mut self, path: &Path) -> Result<HashMap<String, String>, String> {
+        let cannot_read = |err: io::Error| format!("cannot read {}: {}", path.display(), err);
+        let path = fs::canonicalize(path).map_err(cannot_read)?;
+        let modified = fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .map_err(cannot_read)?;
+        if let Some(module) = self.modules.get(&path) {
+            if module.modified == modified {
+                return Ok(module.exports.clone());
+            }
+        }
+        if self.loading.contains(&path) {
+            return Err(format!("{} imports itself", path.display()));
+        }
+        let source = fs::read_to_string(&path).map_err(cannot_read)?;
+
+        self.loading.push(path.clone());
+        let exports = self.eval_module(&path, &source);
+        self.loading.pop();
+        let exports = exports.map_err(|err| format!("{} (in {})", err, path.display()))?;
+        self.modules.insert(
+            path,
+            LoadedModule {
+                modified,
+                exports: exports.clone(),
+            },
+        );
+        Ok(exports)
+    }
+
+    /// Evaluates the forms of the module in file `path`, which holds
+    /// `source`, and returns what its exports stand for.
+    fn eval_module(
+        &mut self,
+        path: &Path,
+        source: &str,
+    ) -> Result<HashMap<String, String>, String> {
+        let forms = read_all(source)?;
+        let (name, export) = match forms.first().map(module_header).transpose()?.flatten() {
+            Some(header) => header,
+            None => {
+                return Err(
+                    "a module file must start with (module name (export names...))".to_string(),
+                )
+            }
+        };
+        let dir = path.parent().unwrap_or(Path::new(""));
+
+        // a module sees its own macros and imports, not the session's, and
+        // all of its definitions, wherever they are
+        let mut expander = Expander::new();
+        let mut scope = HashMap::new();
+        let mut exprs = vec![];
+        for form in &forms[1..] {
+            if let Some(import) = import_target(form)? {
+                let path = self.resolve(&import, dir)?;
+                scope.extend(self.load_module(&path)?);
+            } else if let Some(expr) = expander.expand_top(form)? {
+                if let Some(defined) = defined_name(&expr)? {
+                    scope.insert(defined.to_string(), qualify(name, defined));
+                }
+                exprs.push(expr);
+            }
+        }
+        let mut functions = vec![];
+        for expr in &exprs {
+            let renamed = self.fold_prelude_calls(&rename(expr, &scope));
+            self.compile_and_run(&renamed)
+                .map_err(|err| format!("{} (in {})", err, expr))?;
+            if let Ok(Some((name, _, _))) = function_parts(&renamed) {
+                functions.push(name.to_string());
+            }
+        }
+        let functions: Vec<&str> = functions.iter().map(String::as_str).collect();
+        self.compile_unit(name, &functions)?;
+
+        export
+            .iter()
+            .map(|exported| match scope.get(*exported) {
+                Some(qualified) => Ok((exported.to_string(), qualified.clone())),
+                None => Err(format!("{} is exported but not defined", exported)),
+            })
+            .collect()
+    }
+
+    /// Compiles the functions `names` into an LLVM module of their own, with
+    /// whatever they call, and links them into every later module instead
+    /// of compiling them there again. The versions compiled here are the one
+    /// `Compiler::compile` compiles for a definition, and those for integer
+    /// and for dynamic arguments where they compile; calls with other types
+    /// still specialise them where they are made. `unit` names the module,
+    /// see `write_objects`.
+    fn compile_unit(&mut self, unit: &str, names: &[&str]) -> Result<(), String> {
+        let lifted = self
+            .previous_exprs
+            .iter()
+            .map(lift_definitions)
+            .collect::<Result<Vec<_>, _>>()?;
+        let forms: Vec<Expr> = lifted.iter().flatten().cloned().collect();
+        let module = self
+            .context
+            .create_module(&format!("unit_{}", self.units.len()));
+        for lifted in &lifted {
+            let def = lifted.last().unwrap();
+            let (name, params) = match function_parts(def) {
+                Ok(Some((name, params, _))) if names.contains(&name) => (name, params),
+                _ => continue,
+            };
+            Compiler::compile(
+                self.context,
+                &self.builder,
+                &self.fpm,
+                &module,
+                def,
+                &forms,
+                &mut self.global_scope,
+            )?;
+            for ty in [Ty::I64, Ty::Dynamic] {
+                // a version that does not compile is specialised where it
+                // is called, and fails there
+                let saved_scope = self.global_scope.clone();
+                let version = Compiler::compile_version(
+                    self.context,
+                    &self.builder,
+                    &self.fpm,
+                    &module,
+                    name,
+                    &vec![ty; params.len()],
+                    &forms,
+                    &mut self.global_scope,
+                );
+                if version.is_err() {
+                    self.global_scope = saved_scope;
+                }
+            }
+        }
+
+        let copy = module.clone();
+        let ee = module
+            .create_jit_execution_engine(OptimizationLevel::None)
+            .map_err(|err| err.to_string())?;
+        runtime::link(&module, &ee);
+        self.link_globals(&module, &ee);
+        let index = self.units.len();
+        let mut compiled = HashSet::new();
+        let mut next = module.get_first_function();
+        while let Some(function) = next {
+            next = function.get_next_function();
+            let name = function.get_name().to_str().unwrap();
+            if function.count_basic_blocks() == 0 {
+                continue;
+            }
+            for form in &forms {
+                if let Ok(Some((defined, _, _))) = function_parts(form) {
+                    if is_version_of(name, defined) {
+                        compiled.insert(defined.to_string());
+                    }
+                }
+            }
+            if names.iter().any(|exported| is_version_of(name, exported)) {
+                let address = ee
+                    .get_function_address(name)
+                    .map_err(|err| format!("cannot link {}: {:?}", name, err))?;
+                let linked = Linked {
+                    ty: function.get_type(),
+                    call_conventions: function.get_call_conventions(),
+                    address,
+                    unit: index,
+                };
+                self.linked.insert(name.to_string(), linked);
+            }
+        }
+        self.units.push(Unit {
+            name: unit.to_string(),
+            functions: names.iter().map(|name| name.to_string()).collect(),
+            depends: compiled,
+            module: copy,
+        });
+        self.engines.push(ee);
+        Ok(())
+    }
+
+    /// Writes each module `compile_unit` compiled that is still linked to
+    /// into `dir` as an object file named after it, such as `prelude.o` or
+    /// `geometry.o`, and returns their paths. Each defines the versions of
+    /// its own functions under their compiled names, such as
+    /// `geometry::area.f64`, with the entries `c_header` declares; the
+    /// copies it compiled of functions from elsewhere are local to it. The
+    /// objects call the runtime's `lisp_` functions and use global
+    /// variables by their symbols, which whatever links them must define.
+    pub fn write_objects(&self, dir: &Path) -> Result<Vec<PathBuf>, String> {
+        Target::initialize_native(&InitializationConfig::default())?;
+        let triple = TargetMachine::get_default_triple();
+        let target = Target::from_triple(&triple).map_err(|err| err.to_string())?;
+        let machine = target
+            .create_target_machine(
+                &triple,
+                &TargetMachine::get_host_cpu_name().to_string(),
+                &TargetMachine::get_host_cpu_features().to_string(),
+                OptimizationLevel::Default,
+                RelocMode::PIC,
+                CodeModel::Default,
+            )
+            .ok_or("cannot create a target machine for this host")?;
+
+        let mut paths = vec![];
+        for (index, unit) in self.units.iter().enumerate() {
+            // a module loaded again after its file changed replaces this one
+            if !self.linked.values().any(|linked| linked.unit == index) {
+                continue;
+            }
+            let module = unit.module.clone();
+            let mut next = module.get_first_function();
+            while let Some(function) = next {
+                next = function.get_next_function();
+                let name = function.get_name().to_str().unwrap();
+                let own = unit
+                    .functions
+                    .iter()
+                    .any(|own| is_version_of(name, own) || name == c_name(own));
+                if function.count_basic_blocks() > 0 && !own {
+                    function.set_linkage(Linkage::Internal);
+                }
+            }
+            let path = dir.join(format!("{}.o", unit.name));
+            machine
+                .write_to_file(&module, FileType::Object, &path)
+                .map_err(|err| format!("cannot write {}: {}", path.display(), err))?;
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+
+    /// Forgets the functions compiled by `compile_unit` along with a version
+    /// of definition `name`, which is being replaced.
+    fn unlink(&mut self, name: &str) {
+        let units = &self.units;
+        self.linked
+            .retain(|_, linked| !units[linked.unit].depends.contains(name));
+    }
+
+    /// The functions compiled once and linked to since, see `compile_unit`.
+    pub fn linked_functions(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.linked.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// Maps the functions `module` declares that `compile_unit` compiled to
+    /// their code.
+    fn link_functions(&self, module: &Module<'ctx>, ee: &ExecutionEngine<'ctx>) {
+        for (name, linked) in &self.linked {
+            if let Some(function) = module.get_function(name) {
+                if function.count_basic_blocks() == 0 {
+                    ee.add_global_mapping(&function.as_global_value(), linked.address);
+                }
+            }
+        }
+    }
+
+    /// Maps the global variables `module` declares to their slots, allocating
+    /// slots for variables it is about to define.
+    fn link_globals(&mut self, module: &Module<'ctx>, ee: &ExecutionEngine<'ctx>) {
+        for name in self.global_scope.keys() {
+            if let Some(global) = module.get_global(&global_symbol(name)) {
+                let slot = self.slots.entry(name.clone()).or_insert_with(|| {
+                    let slot = Box::<Slot>::default();
+                    gc::add_root(slot.as_ptr() as *const u64);
+                    slot
+                });
+                ee.add_global_mapping(&global, slot.as_ptr() as usize);
+            }
+        }
+    }
+
+    /// The current value of every global variable, sorted by name.
+    pub fn globals(&self) -> Vec<(&str, Value)> {
+        let mut globals: Vec<(&str, Value)> = self
+            .slots
+            .iter()
+            .filter_map(|(name, slot)| {
+                let [a, b] = [slot[0].get(), slot[1].get()];
+                let value = match self.global_scope.get(name)? {
+                    Ty::I64 => Value::Int(a as i64),
+                    Ty::F64 => Value::Float(f64::from_bits(a)),
+                    Ty::Bool => Value::Bool(a & 1 != 0),
+                    Ty::Rational => Ratio {
+                        num: a as i64,
+                        den: b as i64,
+                    }
+                    .value(),
+                    Ty::Dynamic => Dynamic { tag: a, payload: b }.value(),
+                    Ty::Vector => Vector {
+                        data: a,
+                        len: b as i64,
+                    }
+                    .value(),
+                };
+                Some((name.as_str(), value))
+            })
+            .collect();
+        globals.sort_by_key(|(name, _)| *name);
+        globals
+    }
+
+    /// The function definitions in effect, in the order they were made.
+    pub fn definitions(&self) -> &[Expr] {
+        &self.previous_exprs
+    }
+
+    /// A C header declaring the functions whose types are all declared, as C
+    /// code calls them, see `printer::c_header`.
+    pub fn c_header(&self) -> String {
+        let mut inference = Inference::new(&self.previous_exprs, self.global_scope.clone());
+        let mut functions = vec![];
+        for def in &self.previous_exprs {
+            let name = match function_parts(def) {
+                Ok(Some((name, _, _))) => name,
+                _ => continue,
+            };
+            let tys: Vec<Ty> = match inference.signature(name) {
+                Some(signature) if signature.is_complete() => {
+                    signature.params.iter().flatten().copied().collect()
+                }
+                _ => continue,
+            };
+            let ret = inference.return_type(name, &tys).unwrap_or(Ty::Dynamic);
+            functions.push((name.to_string(), tys, ret));
+        }
+        c_header(&functions)
+    }
+
+    /// The names of the macros in effect.
+    pub fn macro_names(&self) -> impl Iterator<Item = &str> {
+        self.expander.macro_names()
+    }
+
+    /// Imported name -> the qualified name it stands for, see `modules`.
+    pub fn imports(&self) -> &HashMap<String, String> {
+        &self.imports
+    }
+
+    /// Evaluates every form in `source` in order, stopping at the first error.
+    /// Returns the outcome of the last form.
+    pub fn eval_source(&mut self, source: &str) -> Result<Option<Outcome>, String> {
+        let mut outcome = None;
+        for expr in read_all(source)? {
+            outcome = Some(
+                self.eval(&expr)
+                    .map_err(|err| format!("{} (in {})", err, expr))?,
+            );
+        }
+        Ok(outcome)
+    }
+
+    /// Writes the transcript to `path` as a `.lisp` file that `replay` can
+    /// load again: one form per line, failed lines left out.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut contents = String::new();
+        for expr in &self.transcript {
+            contents.push_str(&expr.to_string());
+            contents.push('\n');
+        }
+        fs::write(path, contents)
+    }
+
+    /// Rebuilds state by evaluating a file written by `save` (or by hand).
+    pub fn replay(&mut self, path: impl AsRef<Path>) -> Result<Option<Outcome>, String> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path)
+            .map_err(|err| format!("cannot read {}: {}", path.display(), err))?;
+        self.eval_source(&source)
+    }
+}
+
+impl Drop for Session<'_> {
+    fn drop(&mut self) {
+        for slot in self.slots.values() {
+            gc::remove_root(slot.as_ptr() as *const u64);
+        }
+    }
+}
+
+/// What a top-level form defines, so that a later form defining the same
+/// thing replaces it: a function, or (`true`) the declared type of one.
+fn definition_key(expr: &Expr) -> Result<Option<(&str, bool)>, &'static str> {
+    if let Some((name, _, _)) = function_parts(expr)? {
+        return Ok(Some((name, false)));
+    }
+    Ok(declaration_parts(expr)?.map(|(name, _)| (name, true)))
+}
+
+/// Rewrites the calls in `expr` to the functions `names` with one argument,
+/// or more than two, see `FOLDED`, unless a local variable in `bound` or
+/// bound in `expr` hides them.
+fn fold_calls(expr: &Expr, names: &[&str], bound: &[&str]) -> Expr {
+    let exprs = match expr {
+        Expr::List(exprs) => exprs,
+        _ => return expr.clone(),
+    };
+    let mut folded = exprs.clone();
+    for (path, inner) in scoped_parts(exprs) {
+        *at_mut(&mut folded, &path) = fold_calls(at(exprs, &path), names, &extend(bound, inner));
+    }
+    match folded.as_slice() {
+        [Expr::Symbol(op), first, rest @ ..]
+            if names.contains(&op.as_str()) && !bound.contains(&op.as_str()) && rest.len() != 1 =>
+        {
+            rest.iter().fold(first.clone(), |folded, arg| {
+                Expr::List(vec![Expr::Symbol(op.clone()), folded, arg.clone()])
+            })
+        }
+        _ => Expr::List(folded),
+    }
+}
+
+/// Whether `compiled` is the name of a version of definition `name`: the
+/// name itself, or the name of a specialisation, internal function or entry
+/// point, which all carry on after a `.`.
+fn is_version_of(compiled: &str, name: &str) -> bool {
+    compiled
+        .strip_prefix(name)
+        .map_or(false, |rest| rest.is_empty() || rest.starts_with('.'))
+}
+
+/// The value of type `ty` held in the words of a slot, boxed.
+fn boxed(ty: Ty, [a, b]: [u64; 2]) -> Dynamic {
+    match ty {
+        Ty::I64 => Dynamic::int(a as i64),
+        Ty::F64 => Dynamic::float(f64::from_bits(a)),
+        Ty::Bool => Dynamic::bool(a & 1 != 0),
+        Ty::Rational => Dynamic::rational(Ratio {
+            num: a as i64,
+            den: b as i64,
+        }),
+        Ty::Dynamic => Dynamic { tag: a, payload: b },
+        Ty::Vector => Dynamic {
+            tag: Tag::Vector as u64,
+            payload: a,
+        },
+    }
+}
+
+/// Calls the zero-argument function `name`, which returns a `T`, letting the
+/// garbage collector scan its stack.
+fn run<T>(ee: &ExecutionEngine, name: &str) -> Result<T, String> {
+    let compiled_fn = unsafe { ee.get_function::<unsafe extern "C" fn() -> T>(name) }
+        .map_err(|err| format!("Error during execution: {:?}", err))?;
+    Ok(gc::with_stack_roots(|| unsafe { compiled_fn.call() }))
+}
+
+/// Evaluates a single expression in a fresh session and returns its value as
+/// a float.
+pub fn eval(expr: &Expr) -> Result<f64, String> {
+    let context = Context::create();
+    let mut session = Session::new(&context);
+    match session.eval(expr)? {
+        Outcome::Value(value) => Ok(value.as_f64()),
+        Outcome::Defined => Err("expected an expression, got a definition".to_string()),
+    }
+}
@@ -0,0 +1,161 @@
+use crate::printer::format_float;
+use std::fmt;
+
+/// A value handed back to the host by JIT-compiled code.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    /// An exact fraction in lowest terms, with a denominator above 1.
+    Rational(i64, i64),
+    Bool(bool),
+    Char(char),
+    /// A function; compiled code only hands back its address.
+    Procedure,
+    /// The empty list, `()`.
+    Nil,
+    /// A cons cell; a list is a chain of them ending in `Nil`.
+    Pair(Box<Value>, Box<Value>),
+    Vector(Vec<f64>),
+    Str(String),
+    /// A quoted symbol, as in `'pi`.
+    Symbol(String),
+    /// What `display` and the like return; the REPL prints nothing for it.
+    Unspecified,
+}
+
+/// Characters written by name, as in `#\space`.
+pub const CHAR_NAMES: &[(&str, char)] = &[
+    ("space", ' '),
+    ("newline", '\n'),
+    ("tab", '\t'),
+    ("return", '\r'),
+    ("null", '\0'),
+];
+
+/// Writes `text` as a string literal, escaping what the reader would not
+/// read back.
+pub fn format_string(text: &str) -> String {
+    let mut literal = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => literal.push_str("\\\""),
+            '\\' => literal.push_str("\\\\"),
+            '\n' => literal.push_str("\\n"),
+            '\t' => literal.push_str("\\t"),
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+/// Writes `c` as a character literal.
+pub fn format_char(c: char) -> String {
+    match CHAR_NAMES.iter().find(|(_, named)| *named == c) {
+        Some((name, _)) => format!("#\\{}", name),
+        None => format!("#\\{}", c),
+    }
+}
+
+impl Value {
+    /// The name of the machine type the value came from, as used in signatures.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "i64",
+            Value::Float(_) => "f64",
+            Value::Rational(..) => "rational",
+            Value::Bool(_) => "bool",
+            Value::Char(_) => "char",
+            Value::Procedure => "procedure",
+            Value::Nil => "null",
+            Value::Pair(..) => "pair",
+            Value::Vector(_) => "vector",
+            Value::Str(_) => "string",
+            Value::Symbol(_) => "symbol",
+            Value::Unspecified => "unspecified",
+        }
+    }
+
+    /// The value as a float, for callers that only care about magnitude.
+    /// Values that are not numbers are NaN.
+    pub fn as_f64(&self) -> f64 {
+        match self {
+            Value::Int(n) => *n as f64,
+            Value::Float(x) => *x,
+            Value::Rational(num, den) => *num as f64 / *den as f64,
+            Value::Bool(b) => *b as u8 as f64,
+            _ => f64::NAN,
+        }
+    }
+}
+
+/// A value as `display` prints it, see `Value::display`.
+pub struct Displayed<'v>(&'v Value);
+
+impl Value {
+    /// The value as `display` prints it: like `Display`, except that strings
+    /// and characters are written as they are rather than as literals.
+    pub fn display(&self) -> Displayed<'_> {
+        Displayed(self)
+    }
+
+    fn write(&self, f: &mut fmt::Formatter, literal: bool) -> fmt::Result {
+        match self {
+            Value::Str(text) if literal => write!(f, "{}", format_string(text)),
+            Value::Str(text) => write!(f, "{}", text),
+            Value::Symbol(name) => write!(f, "{}", name),
+            Value::Char(c) if !literal => write!(f, "{}", c),
+            Value::Unspecified => write!(f, "#<unspecified>"),
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Float(x) => write!(f, "{}", format_float(*x)),
+            Value::Rational(num, den) => write!(f, "{}/{}", num, den),
+            Value::Bool(true) => write!(f, "#t"),
+            Value::Bool(false) => write!(f, "#f"),
+            Value::Char(c) => write!(f, "{}", format_char(*c)),
+            Value::Procedure => write!(f, "#<procedure>"),
+            Value::Nil => write!(f, "()"),
+            Value::Vector(elements) => {
+                write!(f, "#(")?;
+                for (i, x) in elements.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", format_float(*x))?;
+                }
+                write!(f, ")")
+            }
+            // a list prints as `(1 2 3)`, and only an improper tail as `. x`
+            Value::Pair(car, cdr) => {
+                write!(f, "(")?;
+                car.write(f, literal)?;
+                let mut rest = cdr.as_ref();
+                while let Value::Pair(car, cdr) = rest {
+                    write!(f, " ")?;
+                    car.write(f, literal)?;
+                    rest = cdr;
+                }
+                match rest {
+                    Value::Nil => write!(f, ")"),
+                    tail => {
+                        write!(f, " . ")?;
+                        tail.write(f, literal)?;
+                        write!(f, ")")
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, true)
+    }
+}
+
+impl fmt::Display for Displayed<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.write(f, false)
+    }
+}
@@ -1,5 +1,18 @@
 extern crate lisp_repl;
+use inkwell::context::Context;
+use lisp_repl::completion::*;
+use lisp_repl::expand::Expander;
+use lisp_repl::highlight::highlight;
+use lisp_repl::infer::Inference;
+use lisp_repl::lexer::*;
+use lisp_repl::lift::lift_definitions;
+use lisp_repl::modules::*;
+use lisp_repl::printer::*;
 use lisp_repl::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::rc::Rc;
 
 #[cfg(test)]
 mod tests {
@@ -13,6 +26,37 @@ mod tests {
         assert_eq!(read("-3.14"), Ok(Expr::Float(-3.14)));
     }
 
+    #[test]
+    fn test_parse_number_syntax() {
+        assert_eq!(read("1e-9"), Ok(Expr::Float(1e-9)));
+        assert_eq!(read("6.02E23"), Ok(Expr::Float(6.02e23)));
+        assert_eq!(read(".5"), Ok(Expr::Float(0.5)));
+        assert_eq!(read("-.5"), Ok(Expr::Float(-0.5)));
+        assert_eq!(read("+7"), Ok(Expr::Integer(7)));
+        assert_eq!(read("#x1F"), Ok(Expr::Integer(31)));
+        assert_eq!(read("#b1010"), Ok(Expr::Integer(10)));
+        assert_eq!(read("#o-17"), Ok(Expr::Integer(-15)));
+        assert_eq!(read("+inf.0"), Ok(Expr::Float(f64::INFINITY)));
+        assert_eq!(read("-inf.0"), Ok(Expr::Float(f64::NEG_INFINITY)));
+        assert!(matches!(read("+nan.0"), Ok(Expr::Float(x)) if x.is_nan()));
+        assert_eq!(read("-"), Ok(Expr::Symbol("-".to_string())));
+
+        // overflow is a parse error rather than a panic
+        assert!(read("99999999999999999999").is_err());
+        assert!(read("#x10000000000000000").is_err());
+        assert!(read("1e400").is_err());
+        assert!(read("-99999999999999999999").is_err());
+        assert!(read("+99999999999999999999").is_err());
+        assert!(read("-1e400").is_err());
+        assert!(read("(+ -1e400 1)").is_err());
+        assert_eq!(read("-x"), Ok(Expr::Symbol("-x".to_string())));
+        assert_eq!(read("->"), Ok(Expr::Symbol("->".to_string())));
+        assert!(read("#b102").is_err());
+
+        let exprs = read_all("(+ 1e-9 +inf.0)").unwrap();
+        assert_eq!(exprs[0].to_string(), "(+ 1e-9 +inf.0)");
+    }
+
     #[test]
     fn test_parse_symbol() {
         assert_eq!(read("foo"), Ok(Expr::Symbol("foo".to_string())));
@@ -115,6 +159,16 @@ mod tests {
             ("(+ 1 (* 2 3))", 7.0),
             ("(* (- 5 2) (+ 2 3))", 15.0),
             ("(- (/ 24 2) (* 2 3 1))", 6.0),
+            ("(- 5)", -5.0),
+            ("(- 2.5)", -2.5),
+            ("(- (- 3))", 3.0),
+            ("(/ 4)", 0.25),
+            ("(/ 0.5)", 2.0),
+            ("(+)", 0.0),
+            ("(*)", 1.0),
+            ("(+ 7)", 7.0),
+            ("(* 7)", 7.0),
+            ("(* 2 (- 3))", -6.0),
         ];
 
         for (i, (input, expected)) in test_cases.into_iter().enumerate() {
@@ -131,5 +185,1640 @@ mod tests {
                 result
             );
         }
+
+        // negation flips the sign of zero too
+        assert!(eval(&read("(- 0.0)").unwrap()).unwrap().is_sign_negative());
+        assert!(eval(&read("(-)").unwrap()).is_err());
+        assert!(eval(&read("(/)").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_format_float() {
+        assert_eq!(format_float(4.0), "4.0");
+        assert_eq!(format_float(-3.0), "-3.0");
+        assert_eq!(format_float(2.5), "2.5");
+        assert_eq!(format_float(0.1 + 0.2), "0.30000000000000004");
+        assert_eq!(format_float(6.02e23), "6.02e23");
+        assert_eq!(format_float(1e-9), "1e-9");
+        assert_eq!(format_float(f64::INFINITY), "+inf.0");
+        assert_eq!(format_float(f64::NEG_INFINITY), "-inf.0");
+        assert_eq!(format_float(f64::NAN), "+nan.0");
+    }
+
+    #[test]
+    fn test_describe_definition() {
+        // the return type of an unannotated function depends on its arguments
+        let def = read("(define (square x) (* x x))").unwrap();
+        assert_eq!(
+            describe_definition(&def, None, &[]),
+            Some("square: (x) -> ?".to_string())
+        );
+        let def = read("(define x 5)").unwrap();
+        assert_eq!(
+            describe_definition(&def, Some(&Value::Int(5)), &[]),
+            Some("x: i64".to_string())
+        );
+        let def = read("(define (dot (x : f64) (n : i64)) : f64 (* x n))").unwrap();
+        assert_eq!(
+            describe_definition(&def, None, &[]),
+            Some("dot: ((x : f64) (n : i64)) -> f64".to_string())
+        );
+        let decl = read("(: dot (-> f64 i64 f64))").unwrap();
+        assert_eq!(
+            describe_definition(&decl, None, &[]),
+            Some("dot: (-> f64 i64 f64)".to_string())
+        );
+        let definitions = read_all("(: inc (-> i64 i64)) (define (inc n) (+ n 1))").unwrap();
+        assert_eq!(
+            describe_definition(&definitions[1], None, &definitions),
+            Some("inc: ((n : i64)) -> i64".to_string())
+        );
+        assert_eq!(
+            describe_definition(&read("(+ 1 2)").unwrap(), None, &[]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_completion_and_hints() {
+        let mut symbols = Symbols::new();
+        symbols.record(&read("(define (dot x y n) (* x y n))").unwrap());
+        symbols.record(&read("(define dt 0.01)").unwrap());
+
+        assert_eq!(
+            symbols.complete("(d", 2),
+            (
+                1,
+                vec![
+                    "define".to_string(),
+                    "define-syntax".to_string(),
+                    "defmacro".to_string(),
+                    "display".to_string(),
+                    "do".to_string(),
+                    "dot".to_string(),
+                    "dt".to_string()
+                ]
+            )
+        );
+        assert_eq!(
+            symbols.complete("(+ 1 (llvm.sq", 13),
+            (6, vec!["llvm.sqrt".to_string()])
+        );
+        assert_eq!(symbols.hint("(dot ", 5), Some("x y n)".to_string()));
+        assert_eq!(
+            symbols.hint("(dot (dot 1 2 3) ", 17),
+            Some("y n)".to_string())
+        );
+        assert_eq!(symbols.hint("(dot", 4), None);
+        assert_eq!(symbols.hint("(dt ", 4), None);
+        // only forms the compiler knows
+        assert_eq!(symbols.complete("(la", 3), (1, vec![]));
+
+        // a session's definitions, imports and globals replace what was there
+        let definitions = vec![read("(define (geometry::area r) (* r r))").unwrap()];
+        let imports: HashMap<String, String> =
+            [("area".to_string(), "geometry::area".to_string())].into();
+        symbols.refresh(&definitions, &imports, ["total"], ["swap!"]);
+        assert_eq!(symbols.hint("(area ", 6), Some("r)".to_string()));
+        assert_eq!(symbols.complete("(to", 3), (1, vec!["total".to_string()]));
+        assert_eq!(symbols.complete("(dt", 3), (1, vec![]));
+        // and so do its macros, alongside the forms that define them
+        assert_eq!(symbols.complete("(sw", 3), (1, vec!["swap!".to_string()]));
+        assert_eq!(
+            symbols.complete("(defm", 5),
+            (1, vec!["defmacro".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_tokenize() {
+        let kinds: Vec<TokenKind> = tokenize("(define x 4.5) ; four \"s")
+            .into_iter()
+            .map(|t| t.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                TokenKind::LParen,
+                TokenKind::SpecialForm,
+                TokenKind::Whitespace,
+                TokenKind::Symbol,
+                TokenKind::Whitespace,
+                TokenKind::Number,
+                TokenKind::RParen,
+                TokenKind::Whitespace,
+                TokenKind::Comment,
+            ]
+        );
+
+        let tokens = tokenize("(f \"a (b\")");
+        assert_eq!(tokens[3].kind, TokenKind::String);
+        assert_eq!((tokens[3].start, tokens[3].end), (3, 9));
+        assert_eq!(tokens[4].kind, TokenKind::RParen);
+
+        // forms the expander and the module loader handle are special too
+        for form in ["(define-syntax", "(defmacro", "(import", "(module"] {
+            assert_eq!(
+                tokenize(form)[1].kind,
+                TokenKind::SpecialForm,
+                "on {}",
+                form
+            );
+        }
+    }
+
+    #[test]
+    fn test_highlight() {
+        let plain = "(+ 1 (f x))";
+        let colored = highlight(plain, None);
+        assert!(colored.contains("\x1b[36m1\x1b[0m"));
+        // depth 0 and depth 1 parens get different colours
+        assert!(colored.starts_with("\x1b[33m(\x1b[0m"));
+        assert!(colored.contains("\x1b[35m(\x1b[0mf"));
+        // the pair under the cursor is emphasised, an unmatched paren flagged
+        assert!(highlight(plain, Some(5)).contains("\x1b[35m\x1b[1;4m("));
+        assert!(highlight("x)", None).contains("\x1b[1;41m)"));
+    }
+
+    #[test]
+    fn test_read_all_and_display() {
+        let exprs =
+            read_all("; squares\n(define (square x) (* x x))\n(square 2.0) ; four").unwrap();
+        assert_eq!(exprs.len(), 2);
+        assert_eq!(exprs[0].to_string(), "(define (square x) (* x x))");
+        assert_eq!(exprs[1].to_string(), "(square 2.0)");
+        assert_eq!(read(&exprs[1].to_string()), Ok(exprs[1].clone()));
+    }
+
+    #[test]
+    fn test_save_and_replay() {
+        let path = std::env::temp_dir().join("lisp_repl_test_session.lisp");
+
+        let context = Context::create();
+        let mut session = Session::new(&context);
+        session.eval_source("(define (square x) (* x x))").unwrap();
+        assert!(session.eval_source("(cube 2)").is_err());
+        assert_eq!(
+            session.eval_source("(square 3)"),
+            Ok(Some(Outcome::Value(Value::Int(9))))
+        );
+        assert_eq!(session.transcript().len(), 2);
+        session.save(&path).unwrap();
+
+        let mut replayed = Session::new(&context);
+        assert_eq!(
+            replayed.replay(&path),
+            Ok(Some(Outcome::Value(Value::Int(9))))
+        );
+        assert_eq!(
+            replayed.eval_source("(square 4)"),
+            Ok(Some(Outcome::Value(Value::Int(16))))
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    /// Output from `display` and the like, which the test reads back.
+    #[derive(Clone, Default)]
+    struct Capture(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn eval_in_session(source: &str) -> Value {
+        let context = Context::create();
+        let mut session = Session::new(&context);
+        match session.eval_source(source) {
+            Ok(Some(Outcome::Value(value))) => value,
+            other => panic!("{} evaluated to {:?}", source, other),
+        }
+    }
+
+    #[test]
+    fn test_integer_arithmetic() {
+        let test_cases = vec![
+            ("(+ 1 2 3)", Value::Int(6)),
+            ("(* 2 3 4)", Value::Int(24)),
+            ("(- 10 2 3)", Value::Int(5)),
+            ("(+ 1 2.5)", Value::Float(3.5)),
+            ("(/ 12 2 2)", Value::Int(3)),
+            ("(- 5)", Value::Int(-5)),
+            ("(/ 2)", Value::Rational(1, 2)),
+            ("(/ -4)", Value::Rational(-1, 4)),
+            ("(- 1/2)", Value::Rational(-1, 2)),
+            ("(/ 1/3)", Value::Int(3)),
+            ("(+)", Value::Int(0)),
+            ("(*)", Value::Int(1)),
+            ("(* 9007199254740993 1)", Value::Int(9007199254740993)),
+            ("(quotient 17 5)", Value::Int(3)),
+            ("(quotient -17 5)", Value::Int(-3)),
+            ("(remainder -17 5)", Value::Int(-2)),
+            ("(modulo -17 5)", Value::Int(3)),
+            ("(modulo 17 -5)", Value::Int(-3)),
+            ("(modulo 17 5)", Value::Int(2)),
+            ("(modulo -7.5 2)", Value::Float(0.5)),
+            ("(quotient (car '(17)) 5)", Value::Int(3)),
+            ("(remainder (car '(-17)) 5)", Value::Int(-2)),
+            ("(modulo 17 (car '(-5)))", Value::Int(-3)),
+            (
+                "(quotient (car '(9007199254740993)) 1)",
+                Value::Int(9007199254740993),
+            ),
+            ("(modulo (car '(-7.5)) 2)", Value::Float(0.5)),
+            ("(if (< 1 2.5) 1 2)", Value::Int(1)),
+            ("(if (= 1 2) 1 2.0)", Value::Float(2.0)),
+        ];
+
+        for (input, expected) in test_cases {
+            assert_eq!(eval_in_session(input), expected, "on input '{}'", input);
+        }
+
+        // an integer result that does not fit is an error, not a wrap round
+        let context = Context::create();
+        let mut session = Session::new(&context);
+        for input in [
+            "(* 4611686018427387904 4)",
+            "(+ 9223372036854775807 1)",
+            "(- (- 9223372036854775807) 2)",
+            "(* (if #t 4611686018427387904 #f) 2)",
+        ] {
+            assert!(
+                session
+                    .eval_source(input)
+                    .unwrap_err()
+                    .starts_with("integer overflow"),
+                "on input '{}'",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_factorial_stays_exact() {
+        let factorial = "(define (factorial n) (if (= n 0) 1 (* n (factorial (- n 1)))))";
+        assert_eq!(
+            eval_in_session(&format!("{} (factorial 20)", factorial)),
+            Value::Int(2432902008176640000)
+        );
+        // the f64 version is still there for float arguments
+        assert_eq!(
+            eval_in_session(&format!("{} (factorial 5.0)", factorial)),
+            Value::Float(120.0)
+        );
+    }
+
+    #[test]
+    fn test_read_rationals() {
+        assert_eq!(read("8/3"), Ok(Expr::Rational(8, 3)));
+        assert_eq!(read("-6/4"), Ok(Expr::Rational(-3, 2)));
+        assert_eq!(read("6/3"), Ok(Expr::Integer(2)));
+        assert!(read("1/0").is_err());
+        assert!(read("(1/0)").is_err());
+        assert!(read("(+ 1/2/3 1)").is_err());
+        assert_eq!(read("(+ 1/2 x)").unwrap().to_string(), "(+ 1/2 x)");
+        assert_eq!(read("+1/2"), Ok(Expr::Rational(1, 2)));
+        assert_eq!(
+            read("(+1/2 -2/4)"),
+            Ok(Expr::List(vec![
+                Expr::Rational(1, 2),
+                Expr::Rational(-1, 2)
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_rational_arithmetic() {
+        let test_cases = vec![
+            ("(/ 1 3)", Value::Rational(1, 3)),
+            ("(+ 1/3 1/6)", Value::Rational(1, 2)),
+            ("(* 2/3 3/2)", Value::Int(1)),
+            ("(- 1 1/4)", Value::Rational(3, 4)),
+            ("(/ 1/2 2)", Value::Rational(1, 4)),
+            ("(+ 1/2 0.25)", Value::Float(0.75)),
+            ("(if (< 1/3 0.34) 1/3 1)", Value::Rational(1, 3)),
+            ("(if (= 2/4 1/2) 1 0)", Value::Int(1)),
+            ("(exact->inexact 1/4)", Value::Float(0.25)),
+            ("(inexact->exact 0.5)", Value::Rational(1, 2)),
+            ("(exact 3)", Value::Int(3)),
+            ("(inexact 3)", Value::Float(3.0)),
+            (
+                "(define (half n) (/ n 2)) (+ (half 3) (half 4))",
+                Value::Rational(7, 2),
+            ),
+        ];
+
+        for (input, expected) in test_cases {
+            assert_eq!(eval_in_session(input), expected, "on input '{}'", input);
+        }
+        assert_eq!(Value::Rational(-7, 2).to_string(), "-7/2");
+
+        // reduced terms that do not fit an i64 are an error
+        let context = Context::create();
+        let mut session = Session::new(&context);
+        for input in ["(* 4611686018427387904/3 4)", "(+ 1/3 9223372036854775807)"] {
+            assert!(
+                session
+                    .eval_source(input)
+                    .unwrap_err()
+                    .starts_with("rational overflow"),
+                "on input '{}'",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_exact_division_by_zero() {
+        let context = Context::create();
+        let mut session = Session::new(&context);
+        assert!(session.eval_source("(/ 1 0)").is_err());
+    }
+
+    #[test]
+    fn test_global_variables() {
+        assert_eq!(eval_in_session("(define x 5) (* x 2)"), Value::Int(10));
+        assert_eq!(
+            eval_in_session("(define x 1/2) (define (f y) (+ x y)) (f 1)"),
+            Value::Rational(3, 2)
+        );
+        assert_eq!(
+            eval_in_session("(define x 1) (define x (+ x 0.5)) x"),
+            Value::Float(1.5)
+        );
+
+        let context = Context::create();
+        let mut session = Session::new(&context);
+        session.eval_source("(define b 2) (define a 1.5)").unwrap();
+        assert_eq!(
+            session.globals(),
+            vec![("a", Value::Float(1.5)), ("b", Value::Int(2))]
+        );
+    }
+
+    #[test]
+    fn test_constants() {
+        assert_eq!(eval_in_session("pi"), Value::Float(std::f64::consts::PI));
+        assert_eq!(
+            eval_in_session("(* 2 sqrt2 inv_sqrt2)"),
+            Value::Float(2.0 * std::f64::consts::SQRT_2 * std::f64::consts::FRAC_1_SQRT_2)
+        );
+        // user definitions shadow constants
+        assert_eq!(eval_in_session("(define e 3) e"), Value::Int(3));
+        assert_eq!(
+            eval_in_session("(define (f e) (* e 2)) (f 4)"),
+            Value::Int(8)
+        );
+        assert_eq!(constants::lookup("phi"), Some(1.618033988749895));
+        assert_eq!(constants::lookup("tau"), None);
+    }
+
+    #[test]
+    fn test_read_booleans() {
+        assert_eq!(read("#t"), Ok(Expr::Bool(true)));
+        assert_eq!(read("#false"), Ok(Expr::Bool(false)));
+        assert_eq!(read("(not #f)").unwrap().to_string(), "(not #f)");
+        assert_eq!(tokenize("#t")[0].kind, TokenKind::Boolean);
+    }
+
+    #[test]
+    fn test_booleans_and_predicates() {
+        let test_cases = vec![
+            ("#t", Value::Bool(true)),
+            ("(< 1 2 3)", Value::Bool(true)),
+            ("(= 1/2 0.5)", Value::Bool(true)),
+            ("(not (> 1 2))", Value::Bool(true)),
+            ("(not 0)", Value::Bool(false)),
+            // only #f is false
+            ("(if 0 1 2)", Value::Int(1)),
+            ("(if #f 1 2)", Value::Int(2)),
+            ("(zero? 0.0)", Value::Bool(true)),
+            ("(positive? -1/2)", Value::Bool(false)),
+            ("(negative? -3)", Value::Bool(true)),
+            ("(even? 10)", Value::Bool(true)),
+            ("(odd? -3.0)", Value::Bool(true)),
+            ("(even? 3/2)", Value::Bool(false)),
+            ("(null? 1)", Value::Bool(false)),
+            ("(boolean? (= 1 1))", Value::Bool(true)),
+            (
+                "(define (choose b) (if b 1 2)) (choose (zero? 1))",
+                Value::Int(2),
+            ),
+            ("(define big? (> 5 3)) big?", Value::Bool(true)),
+        ];
+
+        for (input, expected) in test_cases {
+            assert_eq!(eval_in_session(input), expected, "on input '{}'", input);
+        }
+        assert_eq!(Value::Bool(false).to_string(), "#f");
+
+        let context = Context::create();
+        let mut session = Session::new(&context);
+        assert!(session.eval_source("(+ #t 1)").is_err());
+    }
+
+    #[test]
+    fn test_read_chars() {
+        assert_eq!(read("#\\a"), Ok(Expr::Char('a')));
+        assert_eq!(read("#\\space"), Ok(Expr::Char(' ')));
+        assert_eq!(read("#\\("), Ok(Expr::Char('(')));
+        assert_eq!(read("#\\newline").unwrap().to_string(), "#\\newline");
+        assert_eq!(tokenize("#\\)")[0].kind, TokenKind::Char);
+    }
+
+    #[test]
+    fn test_read_quote() {
+        assert_eq!(
+            read("'(1 2)"),
+            Ok(Expr::List(vec![
+                Expr::Symbol("quote".into()),
+                Expr::List(vec![Expr::Integer(1), Expr::Integer(2)]),
+            ]))
+        );
+        let kinds: Vec<TokenKind> = tokenize("'(a)").iter().map(|t| t.kind).collect();
+        assert_eq!(kinds[..2], [TokenKind::Quote, TokenKind::LParen]);
+    }
+
+    #[test]
+    fn test_read_vectors() {
+        assert_eq!(
+            read("#(1 2.5)"),
+            Ok(Expr::Vector(vec![Expr::Integer(1), Expr::Float(2.5)]))
+        );
+        assert_eq!(read("#()").unwrap().to_string(), "#()");
+        assert_eq!(read("(f #(1 2))").unwrap().to_string(), "(f #(1 2))");
+    }
+
+    #[test]
+    fn test_read_strings() {
+        assert_eq!(read("\"a b\""), Ok(Expr::Str("a b".to_string())));
+        assert_eq!(
+            read(r#""say \"hi\"\n""#),
+            Ok(Expr::Str("say \"hi\"\n".to_string()))
+        );
+        assert_eq!(read(r#"(f "x\\y")"#).unwrap().to_string(), r#"(f "x\\y")"#);
+        assert_eq!(Value::Str("a\"b".into()).to_string(), r#""a\"b""#);
+        assert_eq!(Value::Str("a\"b".into()).display().to_string(), "a\"b");
+    }
+
+    #[test]
+    fn test_dynamic_values() {
+        let test_cases = vec![
+            ("(if (> 2 1) 1 #f)", Value::Int(1)),
+            (
+                "(define (pick b) (if b 1 #\\x)) (pick #f)",
+                Value::Char('x'),
+            ),
+            ("(+ (if #t 1 #f) 2)", Value::Int(3)),
+            ("(+ (if #t 1/2 #f) 0.5)", Value::Float(1.0)),
+            ("(< (if #t 1/2 #f) 1)", Value::Bool(true)),
+            (
+                "(define (square x) (* x x)) (define (twice f x) (f (f x))) (twice square 3)",
+                Value::Int(81),
+            ),
+            ("(define (square x) (* x x)) square", Value::Procedure),
+            ("(number? (if #t 1 #f))", Value::Bool(true)),
+            ("(char? #\\a)", Value::Bool(true)),
+        ];
+
+        for (input, expected) in test_cases {
+            assert_eq!(eval_in_session(input), expected, "on input '{}'", input);
+        }
+        assert_eq!(Value::Char(' ').to_string(), "#\\space");
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        let context = Context::create();
+        let mut session = Session::new(&context);
+        session.eval_source("(define (f x) (+ x 1))").unwrap();
+        assert_eq!(
+            session.eval_source("(f (if #t #f 1))"),
+            Err("expected a number, got a boolean (in (f (if #t #f 1)))".to_string())
+        );
+        assert!(session
+            .eval_source("(define (call g) (g 1)) (call 5)")
+            .unwrap_err()
+            .starts_with("cannot call an integer"));
+        assert!(session.eval_source("(quotient 1 0)").is_err());
+        assert!(session.eval_source("(modulo (car '(1)) 0)").is_err());
+
+        // C code that called a function gets the error handed over
+        runtime::raise("division by zero");
+        let mut error = std::ptr::null_mut();
+        unsafe {
+            assert_eq!(runtime::lisp_report_error(&mut error), 1);
+            assert_eq!(
+                std::ffi::CStr::from_ptr(error).to_str(),
+                Ok("division by zero")
+            );
+            runtime::lisp_free_error(error);
+            assert_eq!(runtime::lisp_report_error(&mut error), 0);
+        }
+        assert!(error.is_null());
+
+        // the one quotient that overflows raises rather than trapping, and
+        // the remainders by -1 are 0
+        for input in [
+            "(quotient -9223372036854775808 -1)",
+            "(define (quot a b) (quotient a b)) (quot -9223372036854775808 -1)",
+            "(quotient (car '(-9223372036854775808)) -1)",
+        ] {
+            assert!(
+                session
+                    .eval_source(input)
+                    .unwrap_err()
+                    .starts_with("integer overflow"),
+                "on input '{}'",
+                input
+            );
+        }
+        for input in [
+            "(remainder -9223372036854775808 -1)",
+            "(modulo -9223372036854775808 -1)",
+            "(define (rem a b) (remainder a b)) (rem -9223372036854775808 -1)",
+            "(modulo (car '(-9223372036854775808)) -1)",
+        ] {
+            assert_eq!(
+                session.eval_source(input),
+                Ok(Some(Outcome::Value(Value::Int(0)))),
+                "on input '{}'",
+                input
+            );
+        }
+        assert_eq!(
+            session.eval_source("(+ 1 2)"),
+            Ok(Some(Outcome::Value(Value::Int(3))))
+        );
+    }
+
+    #[test]
+    fn test_type_inference() {
+        let forms = read_all(
+            "(define (fact n) (if (= n 0) 1 (* n (fact (- n 1)))))
+             (define (halve n) (if (= n 0) 1 (/ (halve (- n 1)) 2)))
+             (define (pick n) (if (> n 0) n #f))
+             (define (spin x) (spin x))
+             (define (scale x) (set! x (* x 1.5)) x)
+             (define (count n) (let ((i 0)) (set! i (+ i n)) i))",
+        )
+        .unwrap();
+        let mut inference = Inference::new(&forms, HashMap::new());
+        assert_eq!(inference.return_type("fact", &[Ty::I64]), Some(Ty::I64));
+        assert_eq!(inference.return_type("fact", &[Ty::F64]), Some(Ty::F64));
+        // the base case says i64, the recursive case widens it
+        assert_eq!(
+            inference.return_type("halve", &[Ty::I64]),
+            Some(Ty::Rational)
+        );
+        assert_eq!(inference.return_type("pick", &[Ty::I64]), Some(Ty::Dynamic));
+        assert_eq!(inference.return_type("spin", &[Ty::I64]), None);
+        // a variable set to a value its type cannot hold is boxed
+        assert_eq!(
+            inference.return_type("scale", &[Ty::I64]),
+            Some(Ty::Dynamic)
+        );
+        assert_eq!(inference.return_type("scale", &[Ty::F64]), Some(Ty::F64));
+        assert_eq!(inference.return_type("count", &[Ty::I64]), Some(Ty::I64));
+        assert_eq!(
+            inference.return_type("count", &[Ty::F64]),
+            Some(Ty::Dynamic)
+        );
+
+        assert_eq!(
+            eval_in_session("(define (halve n) (if (= n 0) 1 (/ (halve (- n 1)) 2))) (halve 3)"),
+            Value::Rational(1, 8)
+        );
+    }
+
+    #[test]
+    fn test_type_annotations() {
+        let test_cases = vec![
+            (
+                "(define (dot (x : f64) (y : f64) (n : i64)) : f64 (* x y n)) (dot 2 3 4)",
+                Value::Float(24.0),
+            ),
+            (
+                "(: half (-> i64 rational)) (define (half n) (/ n 2)) (half 3)",
+                Value::Rational(3, 2),
+            ),
+            (
+                "(define (sq (x : i64)) (* x x)) (define (app f v) (f v)) (app sq 5)",
+                Value::Int(25),
+            ),
+        ];
+        for (input, expected) in test_cases {
+            assert_eq!(eval_in_session(input), expected, "on input '{}'", input);
+        }
+
+        let context = Context::create();
+        let mut session = Session::new(&context);
+        session
+            .eval_source("(: twice (-> i64 i64)) (define (twice n) (* n 2))")
+            .unwrap();
+        // numbers only widen at a call
+        assert!(session.eval_source("(twice 2.5)").is_err());
+        assert!(session
+            .eval_source("(define (g (b : bool)) (if b 1 2)) (g 1)")
+            .is_err());
+        // the declared return type is checked against the body
+        assert!(session.eval_source("(define (h x) : i64 (/ x 2))").is_err());
+        assert!(session.eval_source("(define (k (x : f64*)) x)").is_ok());
+        assert!(session.eval_source("(define (k (x : f64**)) x)").is_err());
+        session
+            .eval_source("(define (head (v : f64*)) : f64 (vector-ref v 0))")
+            .unwrap();
+        assert_eq!(
+            session.eval_source("(head (vector 1.5 2))"),
+            Ok(Some(Outcome::Value(Value::Float(1.5))))
+        );
+        assert!(session.eval_source("(head 1.5)").is_err());
+        session
+            .eval_source("(define (positive (n : i64)) : bool (> n 0))")
+            .unwrap();
+        let header = session.c_header();
+        assert!(header.contains("int64_t c_twice(int64_t, char **error);"));
+        assert!(header.contains("double c_head(struct lisp_vector, char **error);"));
+        assert!(header.contains("struct lisp_vector c_k(struct lisp_vector, char **error);"));
+        assert!(header.contains("bool c_positive(int64_t, char **error);"));
+        assert_eq!(
+            session.eval_source("(twice 21)"),
+            Ok(Some(Outcome::Value(Value::Int(42))))
+        );
+    }
+    #[test]
+    fn test_lists() {
+        let list = |values: Vec<Value>| {
+            values.into_iter().rev().fold(Value::Nil, |cdr, car| {
+                Value::Pair(Box::new(car), Box::new(cdr))
+            })
+        };
+        let test_cases = vec![
+            ("(car '(1 2 3))", Value::Int(1)),
+            ("(cdr '(1 2 3))", list(vec![Value::Int(2), Value::Int(3)])),
+            (
+                "(cons 0 (list 1 2.5))",
+                list(vec![Value::Int(0), Value::Int(1), Value::Float(2.5)]),
+            ),
+            ("(null? '())", Value::Bool(true)),
+            ("(null? '(1 2 3))", Value::Bool(false)),
+            ("(length (list 1 2 3))", Value::Int(3)),
+            (
+                "(append '(1) '() (list 2 3))",
+                list(vec![Value::Int(1), Value::Int(2), Value::Int(3)]),
+            ),
+            ("(car (reverse '(1 2 3)))", Value::Int(3)),
+            ("(car '(a b))", Value::Symbol("a".into())),
+            ("(symbol? (car (cdr '(1 pi))))", Value::Bool(true)),
+            ("(symbol? \"a\")", Value::Bool(false)),
+            (
+                "(define (square x) (* x x))
+                 (define (sum-of-squares lst)
+                   (if (null? lst)
+                       0
+                       (+ (square (car lst)) (sum-of-squares (cdr lst)))))
+                 (sum-of-squares '(1 2 3))",
+                Value::Int(14),
+            ),
+        ];
+        for (input, expected) in test_cases {
+            assert_eq!(eval_in_session(input), expected, "on input '{}'", input);
+        }
+
+        assert_eq!(
+            list(vec![Value::Int(1), list(vec![Value::Bool(true)])]).to_string(),
+            "(1 (#t))"
+        );
+        assert_eq!(
+            Value::Pair(Box::new(Value::Int(1)), Box::new(Value::Int(2))).to_string(),
+            "(1 . 2)"
+        );
+        assert_eq!(
+            eval_in_session("'(define x (f 1))").to_string(),
+            "(define x (f 1))"
+        );
+        let context = Context::create();
+        let mut session = Session::new(&context);
+        assert!(session
+            .eval_source("(car '())")
+            .unwrap_err()
+            .starts_with("expected a pair, got the empty list"));
+        assert!(session.eval_source("(length (cons 1 2))").is_err());
+    }
+    #[test]
+    fn test_garbage_collection() {
+        let context = Context::create();
+        let mut session = Session::new(&context);
+        session
+            .eval_source(
+                "(define xs (list 1 2 3))
+                 (define (build n) (if (= n 0) '() (cons n (build (- n 1)))))
+                 (define (sum lst) (if (null? lst) 0 (+ (car lst) (sum (cdr lst)))))
+                 (define (churn n) (if (= n 0) 0 (+ (length (list n n n)) (churn (- n 1)))))",
+            )
+            .unwrap();
+        let before = gc::stats();
+        assert_eq!(
+            session.eval_source("(churn 5000)"),
+            Ok(Some(Outcome::Value(Value::Int(15000))))
+        );
+        let after = gc::stats();
+        assert!(after.collections > before.collections);
+        assert!(after.freed_objects > before.freed_objects);
+
+        // a list still being built survives the collections it triggers
+        assert_eq!(
+            session.eval_source("(sum (build 5000))"),
+            Ok(Some(Outcome::Value(Value::Int(12502500))))
+        );
+        assert!(matches!(
+            session.eval_source("(gc)"),
+            Ok(Some(Outcome::Value(Value::Int(_))))
+        ));
+        // global variables are roots
+        assert_eq!(
+            session.eval_source("(car (cdr xs))"),
+            Ok(Some(Outcome::Value(Value::Int(2))))
+        );
+
+        // empty vectors are objects of their own, and whatever a list holds
+        // lives as long as the list
+        session
+            .eval_source(
+                "(define (empties n)
+                   (if (= n 0) 0 (+ (vector-length (make-vector 0)) (empties (- n 1)))))
+                 (define vs (list (make-vector 0) (vector 1 2)))",
+            )
+            .unwrap();
+        assert_eq!(
+            session.eval_source("(empties 5000)"),
+            Ok(Some(Outcome::Value(Value::Int(0))))
+        );
+        session.eval_source("(gc)").unwrap();
+        assert_eq!(
+            session.eval_source("(+ (vector-length (car vs)) (vector-ref (car (cdr vs)) 1))"),
+            Ok(Some(Outcome::Value(Value::Float(2.0))))
+        );
+
+        // a vector only a loop still refers to, perhaps by no more than the
+        // address of an element, survives the collections the loop triggers
+        session
+            .eval_source(
+                "(define (walk n)
+                   (let ((v (make-vector n 1.0)))
+                     (do ((i 0 (+ i 1))
+                          (sum 0.0 (+ sum (vector-ref v i) (length (list i i)))))
+                         ((= i n) sum))))",
+            )
+            .unwrap();
+        let before = gc::stats();
+        assert_eq!(
+            session.eval_source("(walk 20000)"),
+            Ok(Some(Outcome::Value(Value::Float(60000.0))))
+        );
+        assert!(gc::stats().collections > before.collections);
+    }
+    #[test]
+    fn test_vectors() {
+        let test_cases = vec![
+            ("#(1 2.5 3)", Value::Vector(vec![1.0, 2.5, 3.0])),
+            ("(vector-ref (vector 1 2 3) 1)", Value::Float(2.0)),
+            ("(vector-length (make-vector 4 1.5))", Value::Int(4)),
+            ("(vector-set! (make-vector 2) 1 7)", Value::Float(7.0)),
+            (
+                "(define v (make-vector 3 0.5)) (vector-set! v 0 2) v",
+                Value::Vector(vec![2.0, 0.5, 0.5]),
+            ),
+            (
+                "(define (step x dt)
+                   (vector (+ (vector-ref x 0) (* dt (vector-ref x 1)))
+                           (vector-ref x 1)
+                           (vector-length x)))
+                 (step #(1 2 3) 0.5)",
+                Value::Vector(vec![2.0, 2.0, 3.0]),
+            ),
+            ("(vector? (if #t #(1) #f))", Value::Bool(true)),
+            ("(vector-ref (if #t #(1 2) #f) 1)", Value::Float(2.0)),
+        ];
+        for (input, expected) in test_cases {
+            assert_eq!(eval_in_session(input), expected, "on input '{}'", input);
+        }
+        assert_eq!(Value::Vector(vec![1.0, 2.5]).to_string(), "#(1.0 2.5)");
+
+        let context = Context::create();
+        let mut session = Session::new(&context);
+        assert!(session
+            .eval_source("(vector-ref #(1 2) 2)")
+            .unwrap_err()
+            .starts_with("index 2 is out of range for a vector of length 2"));
+        assert!(session.eval_source("(vector-ref #(1) -1)").is_err());
+        assert!(session
+            .eval_source("(vector-ref (if #t 1 #f) 0)")
+            .unwrap_err()
+            .starts_with("expected a vector, got an integer"));
+        assert!(session.eval_source("(+ #(1) 1)").is_err());
+    }
+    #[test]
+    fn test_strings_and_output() {
+        let test_cases = vec![
+            ("(string-append \"a\" \"bc\")", Value::Str("abc".into())),
+            ("(string-append)", Value::Str("".into())),
+            ("(string-length \"h\u{e9}llo\")", Value::Int(5)),
+            ("(number->string 1/2)", Value::Str("1/2".into())),
+            ("(string->number \"2.5\")", Value::Float(2.5)),
+            ("(string->number \"abc\")", Value::Bool(false)),
+            ("(string? \"x\")", Value::Bool(true)),
+            ("(display 1)", Value::Unspecified),
+        ];
+        for (input, expected) in test_cases {
+            assert_eq!(eval_in_session(input), expected, "on input '{}'", input);
+        }
+
+        let output = Capture::default();
+        let stdout = runtime::set_output(Box::new(output.clone()));
+        let context = Context::create();
+        let mut session = Session::new(&context);
+        session
+            .eval_source(
+                r#"(display "Adding five to ")
+                   (display 3)
+                   (newline)
+                   (write "a\"b")
+                   (display (list 1 "two" #\3))"#,
+            )
+            .unwrap();
+        assert!(session
+            .eval_source("(string-length 5)")
+            .unwrap_err()
+            .starts_with("expected a string, got an integer"));
+        runtime::set_output(stdout);
+        assert_eq!(
+            String::from_utf8(output.0.take()).unwrap(),
+            "Adding five to 3\n\"a\\\"b\"(1 two 3)"
+        );
+    }
+
+    #[test]
+    fn test_sequencing() {
+        assert_eq!(eval_in_session("(begin 1 2 3)"), Value::Int(3));
+
+        let output = Capture::default();
+        let stdout = runtime::set_output(Box::new(output.clone()));
+        let context = Context::create();
+        let mut session = Session::new(&context);
+        let outcome = session
+            .eval_source(
+                "(define total 0.0)
+                 (define (add! n) (set! total (+ total n)) total)
+                 (define (twice x) (set! x (* x 2)) x)
+                 (define (shout x) (display x) (newline) x)
+                 (add! 2)
+                 (add! 3)",
+            )
+            .unwrap();
+        assert_eq!(outcome, Some(Outcome::Value(Value::Float(5.0))));
+        assert_eq!(
+            session.eval(&read("(twice 4)").unwrap()),
+            Ok(Outcome::Value(Value::Int(8)))
+        );
+        assert_eq!(
+            session.eval(&read("(shout 7)").unwrap()),
+            Ok(Outcome::Value(Value::Int(7)))
+        );
+        assert_eq!(
+            session.eval(&read("(set! missing 1)").unwrap()),
+            Err("set! of an unbound variable.".to_string())
+        );
+        assert!(session.eval(&read("(begin)").unwrap()).is_err());
+
+        // a variable set to a value its type cannot hold is boxed, keeping
+        // the exact value it had until then
+        session
+            .eval_source(
+                "(define x 1)
+                 (define (bump!) (set! x (+ x 0.5)))
+                 (define (halve n) (set! n (/ n 2)) n)
+                 (define (third) (define z 1) (set! z (/ z 3)) z)",
+            )
+            .unwrap();
+        let test_cases = vec![
+            ("x", Value::Int(1)),
+            ("(begin (bump!) x)", Value::Float(1.5)),
+            (
+                "(begin (set! x \"done\") x)",
+                Value::Str("done".to_string()),
+            ),
+            ("(halve 3)", Value::Rational(3, 2)),
+            ("(halve 4)", Value::Int(2)),
+            ("(third)", Value::Rational(1, 3)),
+            (
+                "(let ((y 1)) (if (> y 0) (set! y 2.5) 0) y)",
+                Value::Float(2.5),
+            ),
+            ("(let ((y 1)) (if (< y 0) (set! y 2.5) 0) y)", Value::Int(1)),
+            ("(begin (define w 1) (set! w 0.5) w)", Value::Float(0.5)),
+        ];
+        for (source, expected) in test_cases {
+            assert_eq!(
+                session.eval(&read(source).unwrap()),
+                Ok(Outcome::Value(expected)),
+                "{}",
+                source
+            );
+        }
+        runtime::set_output(stdout);
+        assert_eq!(String::from_utf8(output.0.take()).unwrap(), "7\n");
+    }
+
+    #[test]
+    fn test_lift_definitions() {
+        let lift = |source: &str| -> Vec<String> {
+            lift_definitions(&read(source).unwrap())
+                .unwrap()
+                .iter()
+                .map(ToString::to_string)
+                .collect()
+        };
+        assert_eq!(lift("(+ 1 2)"), vec!["(+ 1 2)"]);
+        assert_eq!(
+            lift("(define (sum-to n) (define (go i acc) (if (> i n) acc (go (+ i 1) (+ acc i)))) (go 1 0))"),
+            vec![
+                "(define (sum-to.go i acc n) (if (> i n) acc (sum-to.go (+ i 1) (+ acc i) n)))",
+                "(define (sum-to n) (sum-to.go 1 0 n))",
+            ]
+        );
+        // a function passes on what the functions it calls capture
+        assert_eq!(
+            lift("(define (scale x k) (define (mul y) (* y k)) (define (twice y) (mul (mul y))) (twice x))"),
+            vec![
+                "(define (scale.mul y k) (* y k))",
+                "(define (scale.twice y k) (scale.mul (scale.mul y k) k))",
+                "(define (scale x k) (scale.twice x k))",
+            ]
+        );
+        assert_eq!(
+            lift("(define (f x) : i64 (define z 5) (define (g) z) (g))"),
+            vec![
+                "(define (f.g z) z)",
+                "(define (f x) : i64 (define z 5) (f.g z))",
+            ]
+        );
+        assert!(
+            lift_definitions(&read("(define (f x) (define (g) (set! x 1)) (g))").unwrap()).is_err()
+        );
+        assert!(lift_definitions(&read("(define (f x) (define (g) x))").unwrap()).is_err());
+
+        // local variables hide internal functions and enclosing variables
+        assert_eq!(
+            lift("(define (f n) (define (g) n) (let ((g 5)) (* g (do ((g 0 (+ g 1))) ((= g 3) g)))))"),
+            vec![
+                "(define (f.g n) n)",
+                "(define (f n) (let ((g 5)) (* g (do ((g 0 (+ g 1))) ((= g 3) g)))))",
+            ]
+        );
+        assert_eq!(
+            lift("(define (f x) (define (g) (let ((x 1)) (set! x 2) x)) (g))"),
+            vec![
+                "(define (f.g) (let ((x 1)) (set! x 2) x))",
+                "(define (f x) (f.g))",
+            ]
+        );
+        // but these functions are not closures
+        assert!(lift_definitions(
+            &read("(define (f n) (define (g) n) (let ((n 1)) (g)))").unwrap()
+        )
+        .is_err());
+        assert_eq!(
+            lift_definitions(&read("(define (f n) (define (g) n) (list g))").unwrap()),
+            Err("an internal function that uses enclosing variables can only be called, not used as a value.")
+        );
+
+        // a named let is lifted too when it is not a loop, taking the
+        // enclosing variables it uses
+        assert_eq!(
+            lift("(define (f k) (let loop ((i 0)) (if (= i k) i (loop (+ i 1)))))"),
+            vec!["(define (f k) (let loop ((i 0)) (if (= i k) i (loop (+ i 1)))))"]
+        );
+        assert_eq!(
+            lift("(define (f k) (let count ((n 3)) (if (= n 0) 0 (+ k (count (- n 1))))))"),
+            vec![
+                "(define (f.count n k) (if (= n 0) 0 (+ k (f.count (- n 1) k))))",
+                "(define (f k) (f.count 3 k))",
+            ]
+        );
+        assert_eq!(
+            lift("(let f ((n 3)) (if (= n 0) 0 (+ 1 (f (- n 1)))))"),
+            vec![
+                "(define (let.f n) (if (= n 0) 0 (+ 1 (let.f (- n 1)))))",
+                "(let.f 3)",
+            ]
+        );
+        // nor is one called from a named let that is not a loop
+        assert_eq!(
+            lift("(let f ((n 3)) (let g ((m n)) (if (= m 0) (f (- n 1)) (+ 1 (g (- m 1))))))"),
+            vec![
+                "(define (let.f.g m n) (if (= m 0) (let.f (- n 1)) (+ 1 (let.f.g (- m 1) n))))",
+                "(define (let.f n) (let.f.g n n))",
+                "(let.f 3)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_internal_defines() {
+        let context = Context::create();
+        let mut session = Session::new(&context);
+        let outcome = session
+            .eval_source(
+                "(define x 10)
+                 (define (example-function1 y)
+                   (define z 5)
+                   (+ x y z))
+                 (example-function1 7)",
+            )
+            .unwrap();
+        assert_eq!(outcome, Some(Outcome::Value(Value::Int(22))));
+        assert_eq!(
+            session.eval(&read("z").unwrap()),
+            Err("Could not find a matching variable.".to_string())
+        );
+
+        let test_cases = vec![
+            (
+                "(define (sum-to n)
+                   (define (go i acc) (if (> i n) acc (go (+ i 1) (+ acc i))))
+                   (go 1 0))",
+                "(sum-to 10)",
+                Value::Int(55),
+            ),
+            (
+                "(define (scale x k)
+                   (define (mul y) (* y k))
+                   (define (twice y) (mul (mul y)))
+                   (twice x))",
+                "(scale 3 2)",
+                Value::Int(12),
+            ),
+        ];
+        for (definition, call, expected) in test_cases {
+            session.eval(&read(definition).unwrap()).unwrap();
+            assert_eq!(
+                session.eval(&read(call).unwrap()),
+                Ok(Outcome::Value(expected)),
+                "on input '{}'",
+                call
+            );
+        }
+        // the lifted functions go when the one they were defined in changes
+        session
+            .eval(&read("(define (sum-to n) n)").unwrap())
+            .unwrap();
+        assert_eq!(
+            session.eval(&read("(sum-to 10)").unwrap()),
+            Ok(Outcome::Value(Value::Int(10)))
+        );
+    }
+
+    #[test]
+    fn test_tail_calls() {
+        let context = Context::create();
+        let mut session = Session::new(&context);
+        session
+            .eval_source(
+                "(define (count-down n) (if (= n 0) 0 (count-down (- n 1))))
+                 (define (sum-to n acc)
+                   (if (= n 0) acc (begin (set! acc (+ acc n)) (sum-to (- n 1) acc))))
+                 (define (parity n)
+                   (define (ev? k) (if (= k 0) #t (od? (- k 1))))
+                   (define (od? k) (if (= k 0) #f (ev? (- k 1))))
+                   (ev? n))
+                 (define (factorial n) (if (= n 0) 1 (* n (factorial (- n 1)))))
+                 (define (down n) : dynamic (if (= n 0) 0 (up (- n 1))))
+                 (define (up n) : i64 (if (= n 0) 1 (down (- n 1))))
+                 (define (bounce f n) (if (= n 0) 0 (f f (- n 1))))",
+            )
+            .unwrap();
+        let test_cases = vec![
+            ("(count-down 1000000)", Value::Int(0)),
+            ("(sum-to 1000000 0)", Value::Int(500000500000)),
+            ("(parity 1000000)", Value::Bool(true)),
+            ("(parity 1000001)", Value::Bool(false)),
+            // returning different types, so each calls a version of the
+            // other that returns its own type
+            ("(down 1000000)", Value::Int(0)),
+            ("(up 1000001)", Value::Int(0)),
+            // through a procedure value
+            ("(bounce bounce 1000000)", Value::Int(0)),
+            // not a tail call, so still an ordinary recursion
+            ("(factorial 20)", Value::Int(2432902008176640000)),
+        ];
+        for (input, expected) in test_cases {
+            assert_eq!(
+                session.eval(&read(input).unwrap()),
+                Ok(Outcome::Value(expected)),
+                "on input '{}'",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_loops() {
+        let context = Context::create();
+        let mut session = Session::new(&context);
+        session
+            .eval_source(
+                "(define total 0)
+                 (define (triangle n)
+                   (let loop ((i 0) (acc 0)) (if (= i n) acc (loop (+ i 1) (+ acc i)))))",
+            )
+            .unwrap();
+        let test_cases = vec![
+            (
+                "(do ((i 0 (+ i 1)) (s 0 (+ s i))) ((= i 100) s))",
+                Value::Int(4950),
+            ),
+            ("(triangle 10)", Value::Int(45)),
+            (
+                "(let loop ((i 0) (acc 0)) (if (= i 10) acc (loop (+ i 1) (+ acc i))))",
+                Value::Int(45),
+            ),
+            // the loop variable widens to a double when its update does
+            (
+                "(do ((x 1 (* x 0.5)) (n 0 (+ n 1))) ((< x 0.1) x))",
+                Value::Float(0.0625),
+            ),
+            (
+                "(begin (while (< total 10) (set! total (+ total 3))) total)",
+                Value::Int(12),
+            ),
+        ];
+        for (input, expected) in test_cases {
+            assert_eq!(
+                session.eval(&read(input).unwrap()),
+                Ok(Outcome::Value(expected)),
+                "on input '{}'",
+                input
+            );
+        }
+        // not from tail position, so a recursive function rather than a loop
+        assert_eq!(
+            session.eval_source("(let f ((n 3)) (if (= n 0) 0 (+ 1 (f (- n 1)))))"),
+            Ok(Some(Outcome::Value(Value::Int(3))))
+        );
+        session
+            .eval_source(
+                "(define (size tree)
+                   (let walk ((t tree))
+                     (if (pair? t) (+ (walk (car t)) (walk (cdr t))) (if (null? t) 0 1))))",
+            )
+            .unwrap();
+        assert_eq!(
+            session.eval_source("(size '((1 2) 3))"),
+            Ok(Some(Outcome::Value(Value::Int(3))))
+        );
+    }
+
+    #[test]
+    fn test_syntax_rules() {
+        let mut expander = Expander::new();
+        let definitions = [
+            "(define-syntax swap! (syntax-rules ()
+               ((_ a b) (let ((tmp a)) (set! a b) (set! b tmp)))))",
+            "(define-syntax my-or (syntax-rules ()
+               ((_) #f)
+               ((_ e) e)
+               ((_ e rest ...) (let ((t e)) (if t t (my-or rest ...))))))",
+            "(define-syntax my-if (syntax-rules (then else)
+               ((_ c then t else e) (if c t e))))",
+            "(define-syntax my-let* (syntax-rules ()
+               ((_ () body ...) (let () body ...))
+               ((_ ((x v) rest ...) body ...) (let ((x v)) (my-let* (rest ...) body ...)))))",
+        ];
+        for definition in definitions {
+            assert_eq!(expander.expand_top(&read(definition).unwrap()), Ok(None));
+        }
+
+        let test_cases = vec![
+            // the template's tmp is renamed apart from the user's
+            (
+                "(swap! tmp other)",
+                "(let ((tmp~1 tmp)) (set! tmp other) (set! other tmp~1))",
+            ),
+            ("(my-or a b)", "(let ((t~2 a)) (if t~2 t~2 b))"),
+            ("(my-or)", "#f"),
+            ("(my-if x then 1 else 2)", "(if x 1 2)"),
+            (
+                "(my-let* ((a 1) (b a)) b)",
+                "(let ((a 1)) (let ((b a)) (let () b)))",
+            ),
+            (
+                "(define (f x) (my-if x then '(swap! x) else 0))",
+                "(define (f x) (if x (quote (swap! x)) 0))",
+            ),
+        ];
+        for (input, expected) in test_cases {
+            assert_eq!(
+                expander
+                    .expand_top(&read(input).unwrap())
+                    .map(|expr| expr.unwrap().to_string()),
+                Ok(expected.to_string()),
+                "on input '{}'",
+                input
+            );
+        }
+
+        assert!(expander
+            .expand_top(&read("(my-if x 1 2)").unwrap())
+            .is_err());
+        assert!(expander
+            .expand_top(&read("(f (define-syntax g (syntax-rules ())))").unwrap())
+            .is_err());
+        // a function of the same name replaces the macro
+        expander
+            .expand_top(&read("(define (my-if c) c)").unwrap())
+            .unwrap();
+        assert_eq!(
+            expander.expand_top(&read("(my-if x 1 2)").unwrap()),
+            Ok(Some(read("(my-if x 1 2)").unwrap()))
+        );
+    }
+
+    #[test]
+    fn test_macros_in_session() {
+        let context = Context::create();
+        let mut session = Session::new(&context);
+        session
+            .eval_source(
+                "(define-syntax swap! (syntax-rules ()
+                   ((_ a b) (let ((tmp a)) (set! a b) (set! b tmp)))))
+                 (define-syntax unless (syntax-rules ()
+                   ((_ c body ...) (if c 0 (begin body ...)))))
+                 (define x 1)
+                 (define y 2)
+                 (define (sign n) (unless (< n 0) 1))",
+            )
+            .unwrap();
+        let test_cases = vec![
+            ("(begin (swap! x y) (- x y))", Value::Int(1)),
+            (
+                "(let ((tmp 5) (other 6)) (swap! tmp other) (- tmp other))",
+                Value::Int(1),
+            ),
+            ("(sign 3)", Value::Int(1)),
+            ("(sign -3)", Value::Int(0)),
+        ];
+        for (input, expected) in test_cases {
+            assert_eq!(
+                session.eval(&read(input).unwrap()),
+                Ok(Outcome::Value(expected)),
+                "on input '{}'",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_defmacro() {
+        assert_eq!(
+            read("`(a ,b ,@c)").unwrap().to_string(),
+            "(quasiquote (a (unquote b) (unquote-splicing c)))"
+        );
+        let kinds: Vec<TokenKind> = tokenize(",@x").iter().map(|token| token.kind).collect();
+        assert_eq!(kinds, [TokenKind::Quote, TokenKind::Symbol]);
+
+        let mut expander = Expander::new();
+        let definitions = [
+            "(defmacro unroll-sum (v n)
+               (let loop ((i (- n 1)) (terms '()))
+                 (if (< i 0)
+                     `(+ ,@terms)
+                     (loop (- i 1) (cons `(vector-ref ,v ,i) terms)))))",
+            "(defmacro my-when (c &rest body) `(if ,c (begin ,@body) 0))",
+            "(defmacro square-of (x)
+               (define t (gensym))
+               `(let ((,t ,x)) (* ,t ,t)))",
+        ];
+        for definition in definitions {
+            assert_eq!(expander.expand_top(&read(definition).unwrap()), Ok(None));
+        }
+
+        let test_cases = vec![
+            (
+                "(unroll-sum v 3)",
+                "(+ (vector-ref v 0) (vector-ref v 1) (vector-ref v 2))",
+            ),
+            ("(my-when x (f) (g))", "(if x (begin (f) (g)) 0)"),
+            // an expansion is expanded again
+            (
+                "(my-when x (unroll-sum w 1))",
+                "(if x (begin (+ (vector-ref w 0))) 0)",
+            ),
+            ("(square-of (f y))", "(let ((g~1 (f y))) (* g~1 g~1))"),
+        ];
+        for (input, expected) in test_cases {
+            assert_eq!(
+                expander
+                    .expand_top(&read(input).unwrap())
+                    .map(|expr| expr.unwrap().to_string()),
+                Ok(expected.to_string()),
+                "on input '{}'",
+                input
+            );
+        }
+        assert!(expander
+            .expand_top(&read("(unroll-sum v)").unwrap())
+            .is_err());
+        assert!(expander
+            .expand_top(&read("(defmacro bad (x &rest) x)").unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn test_defmacro_in_session() {
+        let context = Context::create();
+        let mut session = Session::new(&context);
+        session
+            .eval_source(
+                "(defmacro unroll-dot (a b n)
+                   (let loop ((i 0) (terms '()))
+                     (if (= i n)
+                         `(+ ,@terms)
+                         (loop (+ i 1) (cons `(* (vector-ref ,a ,i) (vector-ref ,b ,i)) terms)))))
+                 (define (dot3 a b) (unroll-dot a b 3))",
+            )
+            .unwrap();
+        assert_eq!(
+            session
+                .macroexpand(&read("(unroll-dot x y 2)").unwrap())
+                .map(|expr| expr.to_string()),
+            Ok(
+                "(+ (* (vector-ref x 1) (vector-ref y 1)) (* (vector-ref x 0) (vector-ref y 0)))"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            session.eval(&read("(dot3 #(1 2 3) #(4 5 6))").unwrap()),
+            Ok(Outcome::Value(Value::Float(32.0)))
+        );
+    }
+
+    #[test]
+    fn test_module_forms() {
+        let header = read("(module geometry (export area pi))").unwrap();
+        assert_eq!(
+            module_header(&header),
+            Ok(Some(("geometry", vec!["area", "pi"])))
+        );
+        assert_eq!(module_header(&read("(area 2)").unwrap()), Ok(None));
+        assert!(module_header(&read("(module geometry area)").unwrap()).is_err());
+
+        assert_eq!(
+            import_target(&read("(import \"lib/geometry.lisp\")").unwrap()),
+            Ok(Some(Import::File("lib/geometry.lisp".to_string())))
+        );
+        assert_eq!(
+            import_target(&read("(import (lib geometry))").unwrap()),
+            Ok(Some(Import::Library("geometry".to_string())))
+        );
+        assert!(import_target(&read("(import geometry)").unwrap()).is_err());
+
+        let defined = read("(define (area r) (* pi r r))").unwrap();
+        assert_eq!(defined_name(&defined), Ok(Some("area")));
+        let scope: HashMap<String, String> = ["area", "pi"]
+            .iter()
+            .map(|name| (name.to_string(), qualify("geometry", name)))
+            .collect();
+        assert_eq!(
+            rename(
+                &read("(define (area r) (list 'pi (* pi r r)))").unwrap(),
+                &scope
+            )
+            .to_string(),
+            "(define (geometry::area r) (list (quote pi) (* geometry::pi r r)))"
+        );
+        // local variables of those names are left alone
+        assert_eq!(
+            rename(&read("(define (f area) area)").unwrap(), &scope).to_string(),
+            "(define (f area) area)"
+        );
+        assert_eq!(
+            rename(
+                &read("(define (f r) (let ((pi 3)) (* pi (area r))))").unwrap(),
+                &scope
+            )
+            .to_string(),
+            "(define (f r) (let ((pi 3)) (* pi (geometry::area r))))"
+        );
+    }
+
+    #[test]
+    fn test_modules() {
+        let dir = std::env::temp_dir().join("lisp_repl_test_modules");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("constants.lisp"),
+            "(module constants (export tau))\n(define tau 6.25)\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("geometry.lisp"),
+            "(module geometry (export area circumference))
+             (import \"constants.lisp\")
+             (define (area r) (* tau (square r) 0.5))
+             (define (square x) (* x x))
+             (define (circumference r) (* tau r))",
+        )
+        .unwrap();
+
+        let context = Context::create();
+        let mut session = Session::new(&context);
+        session.library_path = vec![dir.clone()];
+        let import = format!("(import \"{}\")", dir.join("geometry.lisp").display());
+        session.eval_source(&import).unwrap();
+        let test_cases = vec![
+            ("(area 2)", Value::Float(12.5)),
+            ("(circumference 2)", Value::Float(12.5)),
+            // not exported, but reachable by its qualified name
+            ("(geometry::square 3)", Value::Int(9)),
+        ];
+        for (input, expected) in test_cases {
+            assert_eq!(
+                session.eval(&read(input).unwrap()),
+                Ok(Outcome::Value(expected)),
+                "on input '{}'",
+                input
+            );
+        }
+        assert!(session.eval_source("(square 3)").is_err());
+        // compiled once, into a module of their own
+        assert!(session
+            .linked_functions()
+            .contains(&"geometry::circumference.f64"));
+        assert_eq!(
+            session.eval_source("(circumference 2.0)"),
+            Ok(Some(Outcome::Value(Value::Float(12.5))))
+        );
+        // and written out as an object file; constants has no functions
+        let objects = session.write_objects(&dir).unwrap();
+        assert_eq!(objects, [dir.join("geometry.o")]);
+        let object = std::fs::read(&objects[0]).unwrap();
+        let symbol = b"geometry::circumference.f64";
+        assert!(object.windows(symbol.len()).any(|window| window == symbol));
+
+        // an unchanged module is not loaded again
+        let evaluated = session.loop_counter();
+        session.eval_source(&import).unwrap();
+        session.eval_source("(import (lib constants))").unwrap();
+        assert_eq!(session.loop_counter(), evaluated);
+        assert_eq!(
+            session.eval_source("tau"),
+            Ok(Some(Outcome::Value(Value::Float(6.25))))
+        );
+
+        // a definition of an imported name replaces the import
+        session.eval_source("(define (area r) r)").unwrap();
+        assert_eq!(
+            session.eval_source("(area 2)"),
+            Ok(Some(Outcome::Value(Value::Int(2))))
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_prelude() {
+        let context = Context::create();
+        let mut session = Session::new(&context);
+        session.load_prelude().unwrap();
+        assert!(session.transcript().is_empty());
+        // compiled once, into a module of its own, without evaluating a form
+        assert!(session.linked_functions().contains(&"abs.i64"));
+        assert_eq!(session.loop_counter(), 0);
+
+        let test_cases = vec![
+            ("(abs -3)", Value::Int(3)),
+            ("(max 2 5)", Value::Int(5)),
+            ("(min 4 1)", Value::Int(1)),
+            ("(max 2 7 5)", Value::Int(7)),
+            ("(min 4 1.5 2 3)", Value::Float(1.5)),
+            ("(min 3)", Value::Int(3)),
+            // min as a value takes two arguments
+            ("(fold min 9 '(4 7 2))", Value::Int(2)),
+            (
+                "(define (minus a b c) (- a b c)) (let ((max minus)) (max 5 2 1))",
+                Value::Int(2),
+            ),
+            ("(cube 3)", Value::Int(27)),
+            ("(sum '(1 2 3))", Value::Int(6)),
+            ("(mean '(1 2 3))", Value::Int(2)),
+            ("(car (map square '(3 4)))", Value::Int(9)),
+            (
+                "(define (big? x) (> x 1)) (length (filter big? '(1 2 3)))",
+                Value::Int(2),
+            ),
+            (
+                "(define (add a b) (+ a b)) (fold add 0 '(1 2 3 4))",
+                Value::Int(10),
+            ),
+            // a session definition replaces the prelude's
+            ("(define (square x) 0) (square 5)", Value::Int(0)),
+        ];
+        for (source, expected) in test_cases {
+            assert_eq!(
+                session.eval_source(source),
+                Ok(Some(Outcome::Value(expected))),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_procedures_outlive_their_eval() {
+        let context = Context::create();
+        let mut session = Session::new(&context);
+        session
+            .eval_source(
+                "(define (square x) (* x x))
+                 (define sq square)
+                 (define fs (list square square))
+                 (define (apply1 f x) (f x))",
+            )
+            .unwrap();
+        // the code they point to was compiled by the evals that defined them
+        assert_eq!(
+            session.eval_source("(sq 3)"),
+            Ok(Some(Outcome::Value(Value::Int(9))))
+        );
+        assert_eq!(
+            session.eval_source("(apply1 (car fs) 4)"),
+            Ok(Some(Outcome::Value(Value::Int(16))))
+        );
+    }
+
+    #[test]
+    fn test_errors_abort_evaluation() {
+        let output = Capture::default();
+        let stdout = runtime::set_output(Box::new(output.clone()));
+        let context = Context::create();
+        let mut session = Session::new(&context);
+        // would never reach 10 if the loop ran on after the error
+        assert!(session
+            .eval_source("(do ((i 0 (+ i \"a\"))) ((= i 10) i))")
+            .unwrap_err()
+            .starts_with("expected a number, got a string"));
+        session
+            .eval_source("(define x 1) (define v (make-vector 2 0))")
+            .unwrap();
+        assert!(session
+            .eval_source("(begin (+ 1 \"a\") (set! x 2) (display x))")
+            .is_err());
+        assert!(session
+            .eval_source("(begin (vector-ref v 5) (vector-set! v 0 3))")
+            .is_err());
+        // and so does a type error in a function it calls
+        assert!(session
+            .eval_source("(define (bad n) (+ n #\\a)) (begin (bad 1) (set! x 3))")
+            .is_err());
+        runtime::set_output(stdout);
+        assert_eq!(String::from_utf8(output.0.take()).unwrap(), "");
+        assert_eq!(
+            session.eval_source("(list x (vector-ref v 0))"),
+            Ok(Some(Outcome::Value(Value::Pair(
+                Box::new(Value::Int(1)),
+                Box::new(Value::Pair(
+                    Box::new(Value::Float(0.0)),
+                    Box::new(Value::Nil)
+                ))
+            ))))
+        );
+
+        // a definition that fails leaves no variable behind, and a
+        // redefinition that fails leaves the old value and type
+        for input in [
+            "(define y (vector-ref v 9))",
+            "(define r (begin (vector-ref v 9) 1/2))",
+            "(define x (vector-ref v 3))",
+            "(define x (begin (set! x 7) (vector-ref v 3)))",
+        ] {
+            assert!(session.eval_source(input).is_err(), "on input '{}'", input);
+        }
+        assert!(session.eval_source("y").is_err());
+        assert!(session.eval_source("r").is_err());
+        assert_eq!(
+            session.eval_source("x"),
+            Ok(Some(Outcome::Value(Value::Int(1))))
+        );
+        let names: Vec<&str> = session
+            .globals()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, ["v", "x"]);
     }
 }